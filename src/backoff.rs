@@ -0,0 +1,237 @@
+use std::future::Future;
+use std::time::Duration;
+
+use log::warn;
+use rand::Rng;
+use reqwest::{RequestBuilder, Response, StatusCode};
+
+pub const DEFAULT_JITTER: f64 = 0.2;
+pub const DEFAULT_RETRIES: u32 = 2;
+// the wait before retrying a mal or jellyfin api request, grown with each attempt
+pub const REQUEST_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The kinds of failure worth retrying a request for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    // the request never got an answer
+    Connection,
+    // 429
+    RateLimited,
+    // 5xx
+    ServerError,
+}
+
+impl Failure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Failure::Connection => "connection error",
+            Failure::RateLimited => "rate limited",
+            Failure::ServerError => "server error",
+        }
+    }
+}
+
+/// How many times each kind of failure is retried. They're counted separately, so that waiting
+/// out a rate limit doesn't also keep retrying a server that can't be reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryLimits {
+    pub connection: u32,
+    pub rate_limited: u32,
+    pub server_error: u32,
+}
+
+impl Default for RetryLimits {
+    fn default() -> RetryLimits {
+        RetryLimits {
+            connection: DEFAULT_RETRIES,
+            rate_limited: DEFAULT_RETRIES,
+            server_error: DEFAULT_RETRIES,
+        }
+    }
+}
+
+impl RetryLimits {
+    fn limit(&self, failure: Failure) -> u32 {
+        match failure {
+            Failure::Connection => self.connection,
+            Failure::RateLimited => self.rate_limited,
+            Failure::ServerError => self.server_error,
+        }
+    }
+}
+
+/// How long to wait before retrying a request, and how often to. The wait grows with each
+/// attempt, and is spread out randomly so that requests failing together don't all retry at the
+/// same moment.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    base: Duration,
+    // the share of the wait that's randomly added or taken away
+    jitter: f64,
+    limits: RetryLimits,
+}
+
+impl Backoff {
+    pub fn new(base: Duration) -> Backoff {
+        Backoff {
+            base,
+            jitter: DEFAULT_JITTER,
+            limits: RetryLimits::default(),
+        }
+    }
+
+    /// Sets how many times each kind of failure is retried.
+    pub fn with_limits(mut self, limits: RetryLimits) -> Backoff {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets the share of each wait that's randomized, clamped to 0 to 1. 0 waits exactly the
+    /// base times the attempt.
+    pub fn with_jitter(mut self, jitter: f64) -> Backoff {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The wait before retrying after the given attempt, starting at 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.base * attempt;
+        if self.jitter == 0.0 {
+            return delay;
+        }
+        let factor = rand::thread_rng().gen_range(1.0 - self.jitter..=1.0 + self.jitter);
+        delay.mul_f64(factor)
+    }
+
+    /// Runs `attempt` until `classify` finds nothing to retry in its result, or the kind of
+    /// failure it found has used up its retries. The last result is returned either way.
+    pub async fn retry<T, F, Fut>(
+        &self,
+        mut attempt: F,
+        classify: impl Fn(&T) -> Option<Failure>,
+    ) -> T
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let mut retried = RetryLimits {
+            connection: 0,
+            rate_limited: 0,
+            server_error: 0,
+        };
+        let mut attempts = 1;
+        loop {
+            let result = attempt().await;
+            let Some(failure) = classify(&result) else {
+                return result;
+            };
+            let count = match failure {
+                Failure::Connection => &mut retried.connection,
+                Failure::RateLimited => &mut retried.rate_limited,
+                Failure::ServerError => &mut retried.server_error,
+            };
+            if *count >= self.limits.limit(failure) {
+                return result;
+            }
+            *count += 1;
+            warn!(
+                "request failed with a {} (attempt {}), retrying",
+                failure.as_str(),
+                attempts
+            );
+            tokio::time::sleep(self.delay(attempts)).await;
+            attempts += 1;
+        }
+    }
+}
+
+/// What's worth retrying about a sent request: no answer at all, a 429 or a server error.
+pub fn classify_response(result: &reqwest::Result<Response>) -> Option<Failure> {
+    match result {
+        Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+            Some(Failure::RateLimited)
+        }
+        Ok(response) if response.status().is_server_error() => Some(Failure::ServerError),
+        Ok(_) => None,
+        Err(_) => Some(Failure::Connection),
+    }
+}
+
+impl Backoff {
+    /// Sends a request, retrying it as `classify_response` says. A request with a streamed body
+    /// can't be sent twice, so it's sent once.
+    pub async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        if request.try_clone().is_none() {
+            return request.send().await;
+        }
+        self.retry(
+            // cloning worked once, so it works every time
+            || request.try_clone().expect("request can be cloned").send(),
+            classify_response,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn test_delay_is_jittered() {
+        let backoff = Backoff::new(Duration::from_millis(500)).with_jitter(0.2);
+        let delays: Vec<Duration> = (0..20).map(|_| backoff.delay(2)).collect();
+        for delay in &delays {
+            assert!(*delay >= Duration::from_millis(800) && *delay <= Duration::from_millis(1200));
+        }
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    // runs through the failures in order, returning the last result and the attempts it took
+    async fn run(backoff: &Backoff, failures: &[Option<Failure>]) -> (Option<Failure>, usize) {
+        let attempts = AtomicUsize::new(0);
+        let result = backoff
+            .retry(
+                || async { failures[attempts.fetch_add(1, Ordering::SeqCst)] },
+                |failure| *failure,
+            )
+            .await;
+        (result, attempts.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn test_retry_limits_per_failure() {
+        let backoff = Backoff::new(Duration::ZERO).with_limits(RetryLimits {
+            connection: 2,
+            rate_limited: 5,
+            server_error: 0,
+        });
+
+        // the connection errors stop at their own cap, however many 429s could be retried
+        let failures = [Some(Failure::Connection); 10];
+        assert_eq!(
+            run(&backoff, &failures).await,
+            (Some(Failure::Connection), 3)
+        );
+
+        // while the 429s are retried up to theirs, next to the connection errors
+        let mut failures = vec![Some(Failure::RateLimited); 5];
+        failures.extend([Some(Failure::Connection), Some(Failure::Connection), None]);
+        assert_eq!(run(&backoff, &failures).await, (None, 8));
+
+        let failures = [Some(Failure::ServerError), None];
+        assert_eq!(
+            run(&backoff, &failures).await,
+            (Some(Failure::ServerError), 1)
+        );
+    }
+
+    #[test]
+    fn test_delay_without_jitter() {
+        let backoff = Backoff::new(Duration::from_millis(500)).with_jitter(0.0);
+        assert_eq!(backoff.delay(1), Duration::from_millis(500));
+        assert_eq!(backoff.delay(3), Duration::from_millis(1500));
+    }
+}
@@ -0,0 +1,84 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+const CACHE_DIR_NAME: &str = "jellymal-rs";
+
+/// Where anything that can be fetched or rebuilt again is kept between runs. Credentials and the
+/// state file aren't cache, so they never go here.
+pub struct Cache {
+    // unset when caching is disabled, or no cache directory could be found
+    dir: Option<PathBuf>,
+}
+
+impl Cache {
+    /// Uses the configured directory, falling back to the XDG cache directory.
+    pub fn new(cache_dir: Option<&str>) -> Cache {
+        Cache {
+            dir: resolve_cache_dir(
+                cache_dir,
+                std::env::var("XDG_CACHE_HOME").ok(),
+                std::env::var("HOME").ok(),
+            ),
+        }
+    }
+
+    /// A cache that never reads or writes anything, forcing everything to be fetched again.
+    pub fn disabled() -> Cache {
+        Cache { dir: None }
+    }
+
+    /// Gets the path of a cache file, creating the cache directory if needed. Returns none when
+    /// caching is disabled.
+    pub fn path(&self, name: &str) -> Result<Option<String>> {
+        let Some(dir) = &self.dir else {
+            return Ok(None);
+        };
+        std::fs::create_dir_all(dir)?;
+        Ok(Some(dir.join(name).to_string_lossy().to_string()))
+    }
+}
+
+fn resolve_cache_dir(
+    cache_dir: Option<&str>,
+    xdg_cache_home: Option<String>,
+    home: Option<String>,
+) -> Option<PathBuf> {
+    if let Some(cache_dir) = cache_dir {
+        return Some(PathBuf::from(cache_dir));
+    }
+    // an empty XDG_CACHE_HOME is treated as unset, as the spec says
+    if let Some(xdg_cache_home) = xdg_cache_home.filter(|dir| !dir.is_empty()) {
+        return Some(PathBuf::from(xdg_cache_home).join(CACHE_DIR_NAME));
+    }
+    home.map(|home| PathBuf::from(home).join(".cache").join(CACHE_DIR_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_cache_dir() {
+        let home = || Some("/home/alyosha".to_string());
+        assert_eq!(
+            resolve_cache_dir(Some("/cache"), Some("/xdg".to_string()), home()),
+            Some(PathBuf::from("/cache"))
+        );
+        assert_eq!(
+            resolve_cache_dir(None, Some("/xdg".to_string()), home()),
+            Some(PathBuf::from("/xdg/jellymal-rs"))
+        );
+        assert_eq!(
+            resolve_cache_dir(None, Some("".to_string()), home()),
+            Some(PathBuf::from("/home/alyosha/.cache/jellymal-rs"))
+        );
+        assert_eq!(resolve_cache_dir(None, None, None), None);
+    }
+
+    #[test]
+    fn test_disabled_cache() -> Result<(), anyhow::Error> {
+        assert_eq!(Cache::disabled().path("consolidated-mapping.json")?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,698 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use reqwest::header::{HeaderName, HeaderValue};
+
+use crate::backoff::{Backoff, RetryLimits, DEFAULT_JITTER, DEFAULT_RETRIES};
+use crate::jellyfin::{AuthScheme, DuplicateSeries, IdSource, WatchedSource};
+use crate::mal::ListStatus;
+use crate::oauth::SecretsFile;
+use crate::report::OutputFormat;
+use crate::search::DEFAULT_MIN_SCORE;
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Config {
+    /// log one json object per line instead of the human readable format
+    #[arg(long, env = "JSON_LOGS")]
+    pub json_logs: bool,
+
+    /// label added to every log line, to tell accounts apart when several are synced
+    #[arg(long, env = "ACCOUNT_LABEL")]
+    pub account_label: Option<String>,
+
+    // the jellyfin settings aren't needed by every mode, so they're checked with `require`
+    #[arg(long, env = "JELLYFIN_HOST")]
+    pub jellyfin_host: Option<String>,
+
+    #[arg(long, env = "JELLYFIN_TOKEN", hide_env_values = true)]
+    pub jellyfin_token: Option<String>,
+
+    /// file holding the jellyfin token, e.g. a docker secret. takes precedence over JELLYFIN_TOKEN
+    #[arg(long, env = "JELLYFIN_TOKEN_FILE")]
+    pub jellyfin_token_file: Option<String>,
+
+    /// the jellyfin user to sync, or a comma separated list of them. the progress of several users
+    /// is merged into the one MAL list, each series counting the furthest any of them got
+    #[arg(long, env = "JELLYFIN_USER", value_delimiter = ',')]
+    pub jellyfin_user: Vec<String>,
+
+    /// the jellyfin user's id, used instead of looking `JELLYFIN_USER` up. for api keys that
+    /// aren't allowed to list the users
+    #[arg(long, env = "JELLYFIN_USER_ID")]
+    pub jellyfin_user_id: Option<String>,
+
+    /// how the token is sent to jellyfin
+    #[arg(long, env = "JELLYFIN_AUTH_SCHEME", value_enum, default_value_t = AuthScheme::EmbyToken)]
+    pub jellyfin_auth_scheme: AuthScheme,
+
+    /// comma separated headers sent on every jellyfin request, e.g. "X-Forwarded-User: alyosha",
+    /// for auth proxies in front of jellyfin
+    #[arg(
+        long = "jellyfin-header",
+        env = "JELLYFIN_HEADERS",
+        value_delimiter = ',',
+        value_parser = parse_header
+    )]
+    pub jellyfin_headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// what to do with jellyfin series that share an id: merge them into one, or skip them
+    #[arg(long, env = "DUPLICATE_SERIES", value_enum, default_value_t = DuplicateSeries::Merge)]
+    pub duplicate_series: DuplicateSeries,
+
+    /// where series ids are read from. anidb and anilist ids are mapped to mal directly, without
+    /// the tvdb to anidb mapping
+    #[arg(long, env = "JELLYFIN_ID_SOURCE", value_enum, default_value_t = IdSource::UserDataKey)]
+    pub jellyfin_id_source: IdSource,
+
+    /// where it's read from whether an episode was watched. playback-reporting uses the plays
+    /// recorded by jellyfin's playback reporting plugin, and the played state when it's missing
+    #[arg(long, env = "JELLYFIN_WATCHED_SOURCE", value_enum, default_value_t = WatchedSource::UserData)]
+    pub jellyfin_watched_source: WatchedSource,
+
+    /// maximum number of jellyfin folders fetched concurrently during the library walk
+    #[arg(long, env = "JELLYFIN_CONCURRENCY", default_value_t = 4)]
+    pub jellyfin_concurrency: usize,
+
+    /// walk every jellyfin library, instead of only tv show, movie and mixed libraries (plus books
+    /// when syncing manga)
+    #[arg(long, env = "ALL_COLLECTIONS")]
+    pub all_collections: bool,
+
+    /// walk the jellyfin library one folder at a time, same as a concurrency of 1, so that runs
+    /// are reproducible when troubleshooting
+    #[arg(long, env = "SEQUENTIAL")]
+    pub sequential: bool,
+
+    /// playback percentage at which an episode counts as watched, even if jellyfin hasn't marked
+    /// it as played yet. unset by default, relying solely on jellyfin's played status
+    #[arg(long, env = "WATCHED_PERCENT_THRESHOLD")]
+    pub watched_percent_threshold: Option<f64>,
+
+    /// comma separated seasons that never count towards the latest watched episode. season 0
+    /// holds specials in jellyfin
+    #[arg(
+        long,
+        env = "IGNORED_SEASONS",
+        value_delimiter = ',',
+        default_value = "0"
+    )]
+    pub ignored_seasons: Vec<i32>,
+
+    /// count episode 0 of a season (often a prologue) like any other episode. it's left out like
+    /// the specials by default
+    #[arg(long, env = "INCLUDE_EPISODE_ZERO")]
+    pub include_episode_zero: bool,
+
+    /// series with fewer episodes watched on jellyfin aren't synced, to keep barely started
+    /// series off the MAL list. 0 syncs everything
+    #[arg(long, env = "MIN_EPISODES_WATCHED", default_value_t = 0)]
+    pub min_episodes_watched: i32,
+
+    /// the season that episodes without a season number (e.g. loose files outside of a season
+    /// folder) are counted in
+    #[arg(long, env = "MISSING_SEASON_NUMBER", default_value_t = 1)]
+    pub missing_season_number: i32,
+
+    /// skip episodes without a season number, instead of counting them in
+    /// `--missing-season-number`
+    #[arg(long, env = "SKIP_MISSING_SEASON")]
+    pub skip_missing_season: bool,
+
+    /// seconds a single series may take to sync before it's counted as failed. 0 disables the
+    /// limit
+    #[arg(long, env = "SERIES_TIMEOUT_SECS", default_value_t = 300)]
+    pub series_timeout_secs: u64,
+
+    /// only sync series marked as a favorite on jellyfin
+    #[arg(long, env = "FAVORITES_ONLY")]
+    pub favorites_only: bool,
+
+    /// let the sync lower mal's episode count and move completed series back to watching, so
+    /// mal mirrors jellyfin. by default mal only ever moves forward
+    #[arg(long, env = "ALLOW_DECREASE")]
+    pub allow_decrease: bool,
+
+    /// only sync series whose name contains this, ignoring case. for quick manual runs
+    #[arg(long)]
+    pub series_name_contains: Option<String>,
+
+    /// fail when jellyfin sends item fields that aren't known, to debug parse errors after a
+    /// jellyfin upgrade. unknown fields are ignored by default
+    #[arg(long, env = "STRICT_PARSE")]
+    pub strict_parse: bool,
+
+    /// count files spanning several episodes (`IndexNumberEnd`) as only their first episode
+    #[arg(long, env = "IGNORE_INDEX_NUMBER_END")]
+    pub ignore_index_number_end: bool,
+
+    // like the secret and redirect url, may also come from the secrets file
+    #[arg(long, env = "MAL_CLIENT_ID")]
+    pub mal_client_id: Option<String>,
+
+    // either the secret or a file holding it has to be set, checked with `require_secret`
+    #[arg(long, env = "MAL_CLIENT_SECRET", hide_env_values = true)]
+    pub mal_client_secret: Option<String>,
+
+    /// file holding the MAL client secret. takes precedence over MAL_CLIENT_SECRET
+    #[arg(long, env = "MAL_CLIENT_SECRET_FILE")]
+    pub mal_client_secret_file: Option<String>,
+
+    #[arg(long, env = "MAL_API_REDIRECT_URL")]
+    pub mal_api_redirect_url: Option<String>,
+
+    /// json file holding the MAL client_id, client_secret, redirect_url and token together. the
+    /// token is kept in it instead of its own file, and the other settings override its fields
+    #[arg(long, env = "MAL_SECRETS_FILE")]
+    pub mal_secrets_file: Option<String>,
+
+    /// base url of the MAL api
+    #[arg(
+        long,
+        env = "MAL_ENDPOINT",
+        default_value = crate::mal::MAL_ENDPOINT
+    )]
+    pub mal_endpoint: String,
+
+    /// base url the MAL list is read from when MAL can't be reached, e.g. a caching proxy. it's
+    /// sent the token too. writes always go to MAL_ENDPOINT
+    #[arg(long, env = "MAL_READ_FALLBACK_ENDPOINT")]
+    pub mal_read_fallback_endpoint: Option<String>,
+
+    /// maximum number of requests per second made to the MAL api. 0 disables the limit
+    #[arg(long, env = "MAL_REQUESTS_PER_SECOND", default_value_t = 2.0)]
+    pub mal_requests_per_second: f64,
+
+    /// milliseconds to wait between writes to the MAL list, on top of the request limit
+    #[arg(long, env = "MAL_WRITE_DELAY_MS", default_value_t = 0)]
+    pub write_delay_ms: u64,
+
+    /// seconds after which the cached MAL list is fetched again, picking up changes made on MAL
+    /// in the meantime
+    #[arg(long, env = "MAL_LIST_TTL_SECS", default_value_t = 3600)]
+    pub mal_list_ttl_secs: u64,
+
+    /// comments saved on every MAL entry that gets synced. left alone when unset
+    #[arg(long, env = "MAL_COMMENTS")]
+    pub mal_comments: Option<String>,
+
+    /// fetch each MAL entry before updating it, and only send the fields that changed
+    #[arg(long, env = "MAL_READ_BEFORE_WRITE")]
+    pub mal_read_before_write: bool,
+
+    /// comma separated tags saved on every MAL entry that gets synced, e.g. "jellyfin-sync". left
+    /// alone when unset
+    #[arg(long, env = "MAL_TAGS")]
+    pub mal_tags: Option<String>,
+
+    /// exit with code 3 instead of 0 when a sync didn't update anything
+    #[arg(long, env = "EXIT_CODE_ON_NOCHANGE")]
+    pub exit_code_on_nochange: bool,
+
+    /// check that jellyfin and MAL are reachable and the MAL token works, then exit
+    #[arg(long)]
+    pub check: bool,
+
+    /// print the latest watched episode of each jellyfin series as json, then exit
+    #[arg(long)]
+    pub dump_episodes: bool,
+
+    /// print how many of the jellyfin series the mappings cover, then exit
+    #[arg(long)]
+    pub mapping_stats: bool,
+
+    /// resolve a few well known series to check that the mapping files are intact, then exit
+    #[arg(long)]
+    pub test_mapping: bool,
+
+    /// check everything a sync depends on: the settings, jellyfin and the user, the MAL token,
+    /// and the mapping files, print a checklist, then exit
+    #[arg(long)]
+    pub config_check: bool,
+
+    /// stop starting on new series once the run has taken this long, e.g. "90s", "15m" or "2h".
+    /// the series being synced is finished and the rest are left for the next run
+    #[arg(long, env = "MAX_RUNTIME", value_parser = parse_duration)]
+    pub max_runtime: Option<Duration>,
+
+    /// for testing writes against a real account: set a single MAL series to this many episodes
+    /// watched, skipping jellyfin and the mappings, log MAL's response, then exit
+    #[arg(long, num_args = 2, value_names = ["MAL_ID", "EPISODES"])]
+    pub simulate_watch: Option<Vec<i32>>,
+
+    /// the status --simulate-watch gives the series
+    #[arg(long, value_enum, default_value_t = ListStatus::Watching)]
+    pub simulate_status: ListStatus,
+
+    /// look a tvdb id and season up in the anidb mapping file only, then exit
+    #[arg(long, num_args = 2, value_names = ["TVDB_ID", "SEASON"])]
+    pub anidb_only: Option<Vec<i32>>,
+
+    /// look an anidb id up in the mal mapping file only, then exit
+    #[arg(long, value_name = "ANIDB_ID")]
+    pub mal_only: Option<i32>,
+
+    /// write the tvdb to anidb and MAL mapping built from the mapping files to a json file, then
+    /// exit. it can be passed back as --consolidated-mapping-path
+    #[arg(long, value_name = "PATH")]
+    pub export_mapping: Option<String>,
+
+    /// print the MAL anime list as json, then exit
+    #[arg(long)]
+    pub dump_mal_list: bool,
+
+    /// run the MAL authorization flow (or refresh the stored token), save the token, then exit
+    #[arg(long)]
+    pub init_auth: bool,
+
+    /// finish an authorization that --init-auth started without a terminal, with the url the
+    /// browser was redirected to, then exit
+    #[arg(long, value_name = "REDIRECT_URL")]
+    pub complete_auth: Option<String>,
+
+    /// refresh the MAL token when it was last refreshed more than this many seconds ago, even if
+    /// it's far from expiring
+    #[arg(long, env = "ALWAYS_REFRESH_AFTER_SECS")]
+    pub always_refresh_after_secs: Option<u64>,
+
+    /// share of each retry's wait that's randomized, so that requests failing together don't
+    /// retry together. between 0 and 1
+    #[arg(long, env = "RETRY_JITTER", default_value_t = DEFAULT_JITTER)]
+    pub retry_jitter: f64,
+
+    /// times a request to MAL or jellyfin is retried after it couldn't connect
+    #[arg(long, env = "RETRY_CONNECTION_ERRORS", default_value_t = DEFAULT_RETRIES)]
+    pub retry_connection_errors: u32,
+
+    /// times a request to MAL or jellyfin is retried after being rate limited (429)
+    #[arg(long, env = "RETRY_RATE_LIMITED", default_value_t = DEFAULT_RETRIES)]
+    pub retry_rate_limited: u32,
+
+    /// times a request to MAL or jellyfin is retried after a server error (5xx)
+    #[arg(long, env = "RETRY_SERVER_ERRORS", default_value_t = DEFAULT_RETRIES)]
+    pub retry_server_errors: u32,
+
+    /// ignore the stored MAL token and run the authorization flow again, replacing it. for when
+    /// the refresh token was revoked
+    #[arg(long)]
+    pub reauth: bool,
+
+    /// json file resolving tvdb seasons directly to MAL ids. used instead of the anidb and MAL
+    /// mapping files when it exists, and written from them when it doesn't. kept in the cache
+    /// directory by default
+    #[arg(long, env = "CONSOLIDATED_MAPPING_PATH")]
+    pub consolidated_mapping_path: Option<String>,
+
+    /// map episodes with an absolute number by it first, for series the anidb mapping numbers
+    /// absolutely (`defaulttvdbseason="a"`). set to false to map by season first, falling back
+    /// to the absolute number when the season can't be mapped
+    #[arg(
+        long,
+        env = "PREFER_ABSOLUTE_WHEN_PRESENT",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    pub prefer_absolute_when_present: bool,
+
+    /// warn when a mapping file is older than this many days, 0 disables the check
+    #[arg(long, env = "MAPPING_MAX_AGE_DAYS", default_value_t = 30)]
+    pub mapping_max_age_days: u64,
+
+    /// directory for files that can be rebuilt, defaulting to the XDG cache directory
+    #[arg(long, env = "CACHE_DIR")]
+    pub cache_dir: Option<String>,
+
+    /// don't read or write any cached files, so everything is fetched and built fresh. the MAL
+    /// token is still saved
+    #[arg(long, env = "NO_CACHE")]
+    pub no_cache: bool,
+
+    /// json file with per series corrections, e.g. `episodes_per_file` for combined releases
+    #[arg(long, env = "OVERRIDES_PATH")]
+    pub overrides_path: Option<String>,
+
+    /// state file used to checkpoint progress, so that an interrupted run can skip the series it
+    /// already synced when restarted
+    #[arg(long, env = "STATE_PATH")]
+    pub state_path: Option<String>,
+
+    /// state file the checkpoint is read from, instead of STATE_PATH. it's never written to
+    #[arg(long, env = "INPUT_STATE_PATH")]
+    pub input_state: Option<String>,
+
+    /// state file the checkpoint is written to, instead of STATE_PATH
+    #[arg(long, env = "OUTPUT_STATE_PATH")]
+    pub output_state: Option<String>,
+
+    /// how many runs in a row must find a season finished before it's marked completed, to ride
+    /// out episodes that are briefly misnumbered. 1 completes it right away
+    #[arg(long, env = "COMPLETE_AFTER_RUNS", default_value_t = 1)]
+    pub complete_after_runs: u32,
+
+    /// file the seasons waiting to be completed are kept in between runs, needed for
+    /// --complete-after-runs
+    #[arg(long, env = "COMPLETION_STATE_PATH")]
+    pub completion_state_path: Option<String>,
+
+    /// file holding when the last fully successful sync started, written after every one
+    #[arg(long, env = "LAST_RUN_PATH")]
+    pub last_run_path: Option<String>,
+
+    /// only sync the series played since the last fully successful sync, which keeps repeated
+    /// runs cheap. the first run, and any after a failed one, still syncs everything
+    #[arg(long, env = "ONLY_WATCHED_SINCE_LAST_RUN", requires = "last_run_path")]
+    pub only_watched_since_last_run: bool,
+
+    /// sync every series, even with --only-watched-since-last-run
+    #[arg(long)]
+    pub full: bool,
+
+    /// url that a summary of each sync is posted to as json, e.g. a discord or slack webhook
+    #[arg(long, env = "NOTIFY_WEBHOOK")]
+    pub notify_webhook: Option<String>,
+
+    /// print a report of the sync in this format, before the summary line
+    #[arg(long, env = "OUTPUT_FORMAT", value_enum)]
+    pub output_format: Option<OutputFormat>,
+
+    /// write the report to this file instead of stdout
+    #[arg(long, env = "OUTPUT_PATH", requires = "output_format")]
+    pub output_path: Option<String>,
+
+    /// after syncing, remove MAL entries that none of the jellyfin series map to. only lists
+    /// them unless --confirm-prune is given as well
+    #[arg(long)]
+    pub prune_missing: bool,
+
+    /// actually remove the entries found by --prune-missing
+    #[arg(long, requires = "prune_missing")]
+    pub confirm_prune: bool,
+
+    /// compare jellyfin with the MAL list and print the differences as json, without writing
+    /// anything, then exit
+    #[arg(long)]
+    pub verify: bool,
+
+    /// with --verify, also print the exact MAL request each update would send
+    #[arg(long, requires = "verify")]
+    pub dry_run: bool,
+
+    /// share of a series' episodes that has to be watched for a sync or --cleanup-completed to
+    /// mark it completed, e.g. 0.95 when MAL counts an episode more than the files. above 0 and
+    /// at most 1
+    #[arg(long, env = "COMPLETED_RATIO", default_value_t = 1.0, value_parser = parse_ratio)]
+    pub completed_ratio: f64,
+
+    /// search MAL by title for first seasons the mapping files can't place. every match is
+    /// logged with its score and the runner-up
+    #[arg(long, env = "SEARCH_FALLBACK")]
+    pub search_fallback: bool,
+
+    /// how alike a title found by --search-fallback has to be to the jellyfin name to be used,
+    /// above 0 and at most 1. series below it are reported as unmapped
+    #[arg(long, env = "SEARCH_MIN_SCORE", default_value_t = DEFAULT_MIN_SCORE, value_parser = parse_ratio)]
+    pub search_min_score: f64,
+
+    /// mark series that are fully watched but still "watching" on MAL as completed, then exit
+    #[arg(long)]
+    pub cleanup_completed: bool,
+
+    /// also sync read volumes of jellyfin book libraries to the MAL manga list
+    #[arg(long, env = "SYNC_MANGA", requires = "manga_mapping_path")]
+    pub sync_manga: bool,
+
+    /// json file mapping jellyfin book series names to MAL manga ids
+    #[arg(long, env = "MANGA_MAPPING_PATH")]
+    pub manga_mapping_path: Option<String>,
+}
+
+impl Config {
+    /// The backoff for retried requests, waiting `base` times the attempt.
+    pub fn retry_backoff(&self, base: Duration) -> Backoff {
+        Backoff::new(base)
+            .with_jitter(self.retry_jitter)
+            .with_limits(RetryLimits {
+                connection: self.retry_connection_errors,
+                rate_limited: self.retry_rate_limited,
+                server_error: self.retry_server_errors,
+            })
+    }
+
+    /// The settings a sync needs that aren't set, by their environment variable.
+    pub fn missing_settings(&self) -> Vec<&'static str> {
+        [
+            ("JELLYFIN_HOST", self.jellyfin_host.is_some()),
+            (
+                "JELLYFIN_TOKEN",
+                self.jellyfin_token.is_some() || self.jellyfin_token_file.is_some(),
+            ),
+            (
+                "JELLYFIN_USER",
+                !self.jellyfin_user.is_empty() || self.jellyfin_user_id.is_some(),
+            ),
+            (
+                "MAL_CLIENT_ID",
+                self.mal_client_id.is_some() || self.mal_secrets_file.is_some(),
+            ),
+            (
+                "MAL_CLIENT_SECRET",
+                self.mal_client_secret.is_some()
+                    || self.mal_client_secret_file.is_some()
+                    || self.mal_secrets_file.is_some(),
+            ),
+            (
+                "MAL_API_REDIRECT_URL",
+                self.mal_api_redirect_url.is_some() || self.mal_secrets_file.is_some(),
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, set)| !set)
+        .map(|(env, _)| env)
+        .collect()
+    }
+}
+
+/// A setting that is optional for clap but that the current mode needs.
+#[derive(Debug)]
+pub struct MissingSetting(pub String);
+
+impl std::fmt::Display for MissingSetting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "missing required setting {}", self.0)
+    }
+}
+
+impl std::error::Error for MissingSetting {}
+
+/// Gets a setting that is optional for clap but required by the current mode.
+pub fn require<'a>(value: &'a Option<String>, env: &str) -> Result<&'a str> {
+    value
+        .as_deref()
+        .ok_or(MissingSetting(env.to_string()).into())
+}
+
+// parses a "Name: value" header
+fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue)> {
+    let (name, value) = header.split_once(':').ok_or(anyhow!(
+        "expected a header like \"Name: value\", got \"{}\"",
+        header
+    ))?;
+    Ok((name.trim().parse()?, value.trim().parse()?))
+}
+
+// a number of seconds, or of minutes or hours with an "m" or "h" suffix
+fn parse_duration(duration: &str) -> Result<Duration> {
+    let duration = duration.trim();
+    let (number, unit_secs) = match duration.char_indices().last() {
+        Some((i, 's')) => (&duration[..i], 1),
+        Some((i, 'm')) => (&duration[..i], 60),
+        Some((i, 'h')) => (&duration[..i], 60 * 60),
+        _ => (duration, 1),
+    };
+    let number: u64 = number.parse().with_context(|| {
+        format!(
+            "expected a duration like \"90s\", \"15m\" or \"2h\", got \"{}\"",
+            duration
+        )
+    })?;
+    Ok(Duration::from_secs(number * unit_secs))
+}
+
+// a share above 0 and at most 1
+fn parse_ratio(ratio: &str) -> Result<f64> {
+    let ratio: f64 = ratio
+        .trim()
+        .parse()
+        .with_context(|| format!("expected a number like 0.95, got \"{}\"", ratio))?;
+    if !(ratio > 0.0 && ratio <= 1.0) {
+        return Err(anyhow!(
+            "expected a ratio above 0 and at most 1, got {}",
+            ratio
+        ));
+    }
+    Ok(ratio)
+}
+
+/// The MAL client settings, and where its token is kept.
+pub struct MalCredentials {
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_url: String,
+    // the secrets file, when the token is kept in it
+    pub secrets_file: Option<String>,
+}
+
+impl Config {
+    /// Gets the MAL client settings, from the settings themselves or else from the secrets file.
+    pub fn mal_credentials(&self) -> Result<MalCredentials> {
+        let secrets = match &self.mal_secrets_file {
+            Some(path) => SecretsFile::load(path)?,
+            None => SecretsFile::default(),
+        };
+        let client_secret = match (&self.mal_client_secret_file, &self.mal_client_secret) {
+            (None, None) => secrets.client_secret,
+            _ => None,
+        };
+        Ok(MalCredentials {
+            client_id: require(
+                &self.mal_client_id.clone().or(secrets.client_id),
+                "MAL_CLIENT_ID",
+            )?
+            .to_string(),
+            client_secret: require_secret(
+                &self.mal_client_secret.clone().or(client_secret),
+                &self.mal_client_secret_file,
+                "MAL_CLIENT_SECRET",
+            )?,
+            redirect_url: require(
+                &self.mal_api_redirect_url.clone().or(secrets.redirect_url),
+                "MAL_API_REDIRECT_URL",
+            )?
+            .to_string(),
+            secrets_file: self.mal_secrets_file.clone(),
+        })
+    }
+}
+
+/// Gets a secret either from the file it's stored in, or from the setting itself.
+pub fn require_secret(value: &Option<String>, file: &Option<String>, env: &str) -> Result<String> {
+    match file {
+        Some(path) => Ok(std::fs::read_to_string(path)
+            .with_context(|| format!("unable to read {}_FILE {}", env, path))?
+            .trim()
+            .to_string()),
+        None => Ok(require(value, env)?.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_settings() {
+        let config = Config::parse_from([
+            "jellymal-rs",
+            "--mal-client-id",
+            "client",
+            "--mal-api-redirect-url",
+            "http://localhost/callback",
+            "--jellyfin-host",
+            "http://jellyfin",
+            "--mal-client-secret-file",
+            "/run/secrets/mal",
+        ]);
+        assert_eq!(
+            config.missing_settings(),
+            vec!["JELLYFIN_TOKEN", "JELLYFIN_USER"]
+        );
+    }
+
+    #[test]
+    fn test_mal_credentials_from_secrets_file() -> Result<(), anyhow::Error> {
+        let path = std::env::temp_dir().join("jellymal-test-credentials.json");
+        std::fs::write(
+            &path,
+            r#"{ "client_id": "file-client", "client_secret": "file-secret",
+                 "redirect_url": "http://localhost/callback" }"#,
+        )?;
+        // the settings win over the file's fields
+        let config = Config::parse_from([
+            "jellymal-rs",
+            "--mal-secrets-file",
+            path.to_str().unwrap(),
+            "--mal-client-id",
+            "env-client",
+        ]);
+        let credentials = config.mal_credentials()?;
+        assert_eq!(credentials.client_id, "env-client");
+        assert_eq!(credentials.client_secret, "file-secret");
+        assert_eq!(credentials.redirect_url, "http://localhost/callback");
+        assert!(config
+            .missing_settings()
+            .iter()
+            .all(|env| !env.starts_with("MAL")));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_require_missing_setting() {
+        let e = require(&None, "JELLYFIN_HOST").unwrap_err();
+        assert!(e.is::<MissingSetting>());
+        assert_eq!(e.to_string(), "missing required setting JELLYFIN_HOST");
+    }
+
+    #[test]
+    fn test_parse_header() -> Result<(), anyhow::Error> {
+        let (name, value) = parse_header("X-Forwarded-User:  alyosha ")?;
+        assert_eq!(name, "x-forwarded-user");
+        assert_eq!(value, "alyosha");
+        assert!(parse_header("X-Forwarded-User").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_require_secret_from_file() -> Result<(), anyhow::Error> {
+        let path = std::env::temp_dir().join("jellymal-test-secret");
+        std::fs::write(&path, "file-secret\n")?;
+        let secret_file = Some(path.to_str().unwrap().to_string());
+
+        let env_secret = Some("env-secret".to_string());
+        assert_eq!(
+            require_secret(&env_secret, &secret_file, "JELLYFIN_TOKEN")?,
+            "file-secret"
+        );
+        assert_eq!(
+            require_secret(&env_secret, &None, "JELLYFIN_TOKEN")?,
+            "env-secret"
+        );
+        assert!(require_secret(&None, &None, "JELLYFIN_TOKEN").is_err());
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_duration() -> Result<(), anyhow::Error> {
+        assert_eq!(parse_duration("90")?, Duration::from_secs(90));
+        assert_eq!(parse_duration("90s")?, Duration::from_secs(90));
+        assert_eq!(parse_duration("15m")?, Duration::from_secs(15 * 60));
+        assert_eq!(parse_duration("2h")?, Duration::from_secs(2 * 60 * 60));
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("m").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_ratio() -> Result<(), anyhow::Error> {
+        assert_eq!(parse_ratio("0.95")?, 0.95);
+        assert_eq!(parse_ratio("1")?, 1.0);
+        assert!(parse_ratio("0").is_err());
+        assert!(parse_ratio("-0.5").is_err());
+        assert!(parse_ratio("1.5").is_err());
+        assert!(parse_ratio("most").is_err());
+        assert!(parse_ratio("NaN").is_err());
+        Ok(())
+    }
+}
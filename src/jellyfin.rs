@@ -1,19 +1,120 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use anyhow::{anyhow, Context, Result};
-use reqwest::Response;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{debug, info, warn};
+use reqwest::header::HeaderMap;
+use reqwest::{RequestBuilder, Response};
 use serde::{Deserialize, Serialize};
 
+use crate::backoff::{Backoff, REQUEST_RETRY_BACKOFF};
+use crate::json::{parse_response, parse_response_strict};
+use crate::{logging, mapping};
+
+const DEFAULT_CONCURRENCY: usize = 4;
+// number of items requested per page of /Items
+const PAGE_SIZE: usize = 500;
+// jellyfin puts specials in season 0
+const DEFAULT_IGNORED_SEASONS: [i32; 1] = [0];
+const DEFAULT_MISSING_SEASON_NUMBER: i32 = 1;
+// libraries that can hold series and films. books are left out unless manga is synced
+pub const DEFAULT_COLLECTION_TYPES: [&str; 3] = ["tvshows", "movies", "mixed"];
+
+/// How the api token is sent to jellyfin.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum AuthScheme {
+    /// the legacy `X-Emby-Token` header
+    #[default]
+    EmbyToken,
+    /// `Authorization: MediaBrowser Token="..."`, preferred by newer jellyfin versions
+    MediaBrowser,
+}
+
+/// What happens to several jellyfin series that share an id, e.g. after merging libraries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DuplicateSeries {
+    /// treat them as one series, syncing the latest episode watched in any of them
+    #[default]
+    Merge,
+    /// keep them apart by skipping all of them, since they may be different shows
+    Skip,
+}
+
+/// Where the id used to look a series up in the mappings is read from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum IdSource {
+    /// `UserData.Key`, which is the tvdb id for series matched by the tvdb provider
+    #[default]
+    UserDataKey,
+    /// `ProviderIds.Tvdb`
+    Tvdb,
+    /// `ProviderIds.AniDb`, looked up directly in the mal mapping
+    AniDb,
+    /// `ProviderIds.AniList`, looked up directly in the mal mapping
+    AniList,
+}
+
+/// Where it's read from whether an episode has been watched.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum WatchedSource {
+    /// `UserData.Played`
+    #[default]
+    UserData,
+    /// the plays recorded by the playback reporting plugin, falling back to `UserData.Played`
+    /// when the plugin isn't installed
+    PlaybackReporting,
+}
+
+impl IdSource {
+    fn provider_name(&self) -> Option<&'static str> {
+        match self {
+            IdSource::UserDataKey => None,
+            IdSource::Tvdb => Some("Tvdb"),
+            IdSource::AniDb => Some("AniDb"),
+            IdSource::AniList => Some("AniList"),
+        }
+    }
+}
+
 pub struct JellyfinApi {
     host: String,
     token: String,
     client: reqwest::Client,
+    auth_scheme: AuthScheme,
+    concurrency: usize,
+    watched_percent_threshold: Option<f64>,
+    ignored_seasons: Vec<i32>,
+    // episode 0 is usually a prologue, and left out like the specials unless this is set
+    include_episode_zero: bool,
+    id_source: IdSource,
+    watched_source: WatchedSource,
+    use_index_number_end: bool,
+    // fail on fields of /Items that aren't known, to catch changes to jellyfin's api
+    strict_parse: bool,
+    // the library types walked by `get_items`, or all of them when unset
+    collection_types: Option<Vec<String>>,
+    // sent along with the token on every request, e.g. for an auth proxy in front of jellyfin
+    headers: HeaderMap,
+    // the season of episodes without one, or none to skip them
+    missing_season_number: Option<i32>,
+    duplicate_series: DuplicateSeries,
+    // how failed requests are retried
+    retry_backoff: Backoff,
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 struct ItemsResponse {
     items: Vec<Item>,
+    // missing when the server returned everything at once
+    total_record_count: Option<usize>,
+}
+
+// the rows of a playback reporting query, without the column names the plugin sends along
+#[derive(Deserialize)]
+struct CustomQueryResponse {
+    results: Vec<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -23,6 +124,13 @@ struct User {
     id: String,
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PublicSystemInfo {
+    pub server_name: String,
+    pub version: String,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct Item {
@@ -30,23 +138,89 @@ pub struct Item {
     #[serde(rename = "Type")]
     pub media_type: String,
     pub index_number: Option<i32>,
+    // set for files holding a range of episodes, e.g. "episodes 1-2"
+    pub index_number_end: Option<i32>,
+    pub absolute_episode_number: Option<i32>,
     pub parent_index_number: Option<i32>,
     pub name: String,
     pub season_name: Option<String>,
     pub series_name: Option<String>,
     pub series_id: Option<String>,
     pub is_folder: bool,
+    // only set on libraries, e.g. "tvshows" or "music"
+    pub collection_type: Option<String>,
     pub user_data: UserData,
+    pub provider_ids: Option<HashMap<String, String>>,
 }
 
+/// A series' id and season number, which is what gets mapped to a single mal entry.
+pub type SeasonKey = (i32, i32);
+
 pub struct Episode {
     pub id: String,
     pub number: i32,
+    pub number_end: Option<i32>,
+    // counted across every season, for series mapped by absolute numbers
+    pub absolute_number: Option<i32>,
     pub name: String,
     pub season_number: i32,
     pub series_name: String,
+    // jellyfin's id of the series
+    pub series_id: String,
+    // the series' id from the configured id source, which is only a tvdb id by default
     pub tvdb_id: i32,
     pub watched: bool,
+    pub played_percentage: Option<f64>,
+    // whether the episode's series is a favorite
+    pub series_favorite: bool,
+    // whether every episode of the season has been watched, set by `latest_episodes`
+    pub season_finished: bool,
+}
+
+impl Episode {
+    /// The last episode in the file, which is what's been watched once the file is.
+    pub fn last_number(&self) -> i32 {
+        self.number_end.unwrap_or(self.number)
+    }
+
+    /// Whether the series' name contains `needle`, ignoring case.
+    pub fn series_name_contains(&self, needle: &str) -> bool {
+        self.series_name
+            .to_lowercase()
+            .contains(&needle.to_lowercase())
+    }
+
+    /// The absolute number of the last episode in the file.
+    pub fn last_absolute_number(&self) -> Option<i32> {
+        self.absolute_number
+            .map(|absolute_number| absolute_number + self.last_number() - self.number)
+    }
+}
+
+/// The number of episodes in each season, going by the highest episode in it.
+pub fn season_lengths(episodes: &[Episode]) -> HashMap<SeasonKey, i32> {
+    let mut lengths: HashMap<SeasonKey, i32> = HashMap::new();
+    for episode in episodes {
+        let length = lengths
+            .entry((episode.tvdb_id, episode.season_number))
+            .or_default();
+        *length = (*length).max(episode.last_number());
+    }
+    lengths
+}
+
+// the plugin stores ids without dashes, while the api may send them with
+fn normalize_id(id: &str) -> String {
+    id.replace('-', "").to_lowercase()
+}
+
+// jellyfin plugins don't agree on the casing of provider names
+fn provider_id(item: &Item, provider: &str) -> Option<String> {
+    item.provider_ids
+        .as_ref()?
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(provider))
+        .map(|(_, id)| id.clone())
 }
 
 #[derive(Serialize, Deserialize)]
@@ -54,6 +228,8 @@ pub struct Episode {
 pub struct UserData {
     pub played: bool,
     pub key: String,
+    pub played_percentage: Option<f64>,
+    pub is_favorite: Option<bool>,
 }
 
 impl JellyfinApi {
@@ -63,23 +239,261 @@ impl JellyfinApi {
             host: host.to_string(),
             token: token.to_string(),
             client,
+            auth_scheme: AuthScheme::default(),
+            concurrency: DEFAULT_CONCURRENCY,
+            watched_percent_threshold: None,
+            ignored_seasons: DEFAULT_IGNORED_SEASONS.to_vec(),
+            include_episode_zero: false,
+            id_source: IdSource::default(),
+            watched_source: WatchedSource::default(),
+            use_index_number_end: true,
+            strict_parse: false,
+            collection_types: Some(
+                DEFAULT_COLLECTION_TYPES
+                    .iter()
+                    .map(|collection_type| collection_type.to_string())
+                    .collect(),
+            ),
+            headers: HeaderMap::new(),
+            missing_season_number: Some(DEFAULT_MISSING_SEASON_NUMBER),
+            duplicate_series: DuplicateSeries::default(),
+            retry_backoff: Backoff::new(REQUEST_RETRY_BACKOFF),
+        }
+    }
+
+    /// Uses an existing client, so that its connection pool is shared with other apis.
+    pub fn with_client(mut self, client: reqwest::Client) -> JellyfinApi {
+        self.client = client;
+        self
+    }
+
+    pub fn with_auth_scheme(mut self, auth_scheme: AuthScheme) -> JellyfinApi {
+        self.auth_scheme = auth_scheme;
+        self
+    }
+
+    /// Sets the season that episodes without a season number are counted in. `None` skips
+    /// them instead.
+    pub fn with_missing_season_number(mut self, season_number: Option<i32>) -> JellyfinApi {
+        self.missing_season_number = season_number;
+        self
+    }
+
+    /// Sets what happens to several series sharing an id.
+    pub fn with_duplicate_series(mut self, duplicate_series: DuplicateSeries) -> JellyfinApi {
+        self.duplicate_series = duplicate_series;
+        self
+    }
+
+    /// Sets extra headers sent on every request, e.g. `X-Forwarded-User` for an auth proxy.
+    pub fn with_headers(mut self, headers: HeaderMap) -> JellyfinApi {
+        self.headers = headers;
+        self
+    }
+
+    /// Sets the maximum number of folders fetched concurrently by `get_items`.
+    pub fn with_concurrency(mut self, concurrency: usize) -> JellyfinApi {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Treats episodes played at least this far through as watched, even if jellyfin hasn't
+    /// marked them as played. When unset, only jellyfin's own played flag is used.
+    pub fn with_watched_percent_threshold(mut self, threshold: Option<f64>) -> JellyfinApi {
+        self.watched_percent_threshold = threshold;
+        self
+    }
+
+    /// Sets the seasons whose episodes never count towards the latest watched episode.
+    pub fn with_ignored_seasons(mut self, ignored_seasons: Vec<i32>) -> JellyfinApi {
+        self.ignored_seasons = ignored_seasons;
+        self
+    }
+
+    /// Counts episode 0 of a season like any other episode, for mappings that expect it.
+    pub fn with_include_episode_zero(mut self, include_episode_zero: bool) -> JellyfinApi {
+        self.include_episode_zero = include_episode_zero;
+        self
+    }
+
+    /// Sets where series ids are read from.
+    pub fn with_id_source(mut self, id_source: IdSource) -> JellyfinApi {
+        self.id_source = id_source;
+        self
+    }
+
+    /// Sets how requests that failed with a connection error, a 429 or a 5xx are retried.
+    pub fn with_retry_backoff(mut self, retry_backoff: Backoff) -> JellyfinApi {
+        self.retry_backoff = retry_backoff;
+        self
+    }
+
+    /// Sets where it's read from whether an episode has been watched.
+    pub fn with_watched_source(mut self, watched_source: WatchedSource) -> JellyfinApi {
+        self.watched_source = watched_source;
+        self
+    }
+
+    /// Sets whether files spanning several episodes count as watched up to their last episode,
+    /// rather than their first.
+    pub fn with_index_number_end(mut self, use_index_number_end: bool) -> JellyfinApi {
+        self.use_index_number_end = use_index_number_end;
+        self
+    }
+
+    /// Sets whether items with fields that aren't known fail to parse, rather than the fields
+    /// being ignored.
+    pub fn with_strict_parse(mut self, strict_parse: bool) -> JellyfinApi {
+        self.strict_parse = strict_parse;
+        self
+    }
+
+    /// Sets the types of libraries walked, e.g. "tvshows". Libraries of other types, like music
+    /// or photos, are skipped entirely. `None` walks every library.
+    pub fn with_collection_types(mut self, collection_types: Option<Vec<String>>) -> JellyfinApi {
+        self.collection_types = collection_types;
+        self
+    }
+
+    fn series_id(&self, item: &Item) -> Option<String> {
+        match self.id_source.provider_name() {
+            None => Some(item.user_data.key.clone()),
+            Some(provider) => provider_id(item, provider),
+        }
+    }
+
+    // series without a usable tvdb id can still be found by their imdb id in tvdb keyed mappings
+    fn imdb_key(&self, item: &Item) -> Option<i32> {
+        if !matches!(self.id_source, IdSource::UserDataKey | IdSource::Tvdb) {
+            return None;
+        }
+        mapping::imdb_key(&provider_id(item, "Imdb")?)
+    }
+
+    // films are looked up like series, by the configured provider's id. tvdb doesn't list
+    // films, so they fall back to their imdb id there
+    fn movie_key(&self, item: &Item) -> Option<i32> {
+        match self.id_source {
+            IdSource::AniDb | IdSource::AniList => self.series_id(item)?.parse().ok(),
+            IdSource::UserDataKey | IdSource::Tvdb => self.imdb_key(item),
+        }
+    }
+
+    // a film is synced as a series with a single episode, watched once the film is
+    fn movie_episode(&self, item: Item) -> Option<Episode> {
+        let Some(tvdb_id) = self.movie_key(&item) else {
+            debug!("skipping film {}, it has no usable id", item.name);
+            return None;
+        };
+        Some(Episode {
+            number: 1,
+            number_end: None,
+            absolute_number: None,
+            season_number: 1,
+            series_name: item.name.clone(),
+            series_id: item.id.clone(),
+            tvdb_id,
+            watched: item.user_data.played,
+            played_percentage: item.user_data.played_percentage,
+            series_favorite: item.user_data.is_favorite == Some(true),
+            season_finished: false,
+            id: item.id,
+            name: item.name,
+        })
+    }
+
+    fn is_watched(&self, episode: &Episode) -> bool {
+        if episode.watched {
+            return true;
+        }
+        match (self.watched_percent_threshold, episode.played_percentage) {
+            (Some(threshold), Some(percentage)) => percentage >= threshold,
+            _ => false,
         }
     }
 
+    fn authorize(&self, request_builder: RequestBuilder) -> RequestBuilder {
+        let (name, value) = match self.auth_scheme {
+            AuthScheme::EmbyToken => ("X-Emby-Token", self.token.clone()),
+            AuthScheme::MediaBrowser => (
+                "Authorization",
+                format!("MediaBrowser Token=\"{}\"", self.token),
+            ),
+        };
+        // the extra headers can't replace the token
+        let mut headers = self.headers.clone();
+        headers.remove(name);
+        request_builder.headers(headers).header(name, value)
+    }
+
     async fn get(&self, route: &str, params: Option<HashMap<&str, String>>) -> Result<Response> {
         let url = format!("{}{}", self.host, route);
-        let mut request_builder = self.client.get(url).header("X-Emby-Token", &self.token);
+        let param_keys: Vec<&str> = params.iter().flat_map(|p| p.keys().copied()).collect();
+        let auth = match self.auth_scheme {
+            AuthScheme::EmbyToken => "X-Emby-Token",
+            AuthScheme::MediaBrowser => "Authorization",
+        };
+        logging::trace_request("GET", &url, &param_keys, auth);
+        let mut request_builder = self.authorize(self.client.get(url));
         if let Some(p) = params {
             request_builder = request_builder.query(&p);
         }
-        let response = request_builder.send().await?;
+        let response = self.retry_backoff.send(request_builder).await?;
+        logging::trace_response(&response);
+        Ok(response)
+    }
+
+    async fn post(&self, route: &str, body: &serde_json::Value) -> Result<Response> {
+        let url = format!("{}{}", self.host, route);
+        let auth = match self.auth_scheme {
+            AuthScheme::EmbyToken => "X-Emby-Token",
+            AuthScheme::MediaBrowser => "Authorization",
+        };
+        logging::trace_request("POST", &url, &[], auth);
+        let response = self
+            .retry_backoff
+            .send(self.authorize(self.client.post(url)).json(body))
+            .await?;
+        logging::trace_response(&response);
         Ok(response)
     }
 
+    /// Gets the ids of the episodes and films the user has played, according to the playback
+    /// reporting plugin. Fails when the plugin isn't installed.
+    pub async fn get_played_item_ids(&self, user_id: &str) -> Result<HashSet<String>> {
+        // the id ends up in sql, so only ids jellyfin could have made are let through
+        if !user_id.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+            return Err(anyhow!("invalid user id {}", user_id));
+        }
+        let query = format!(
+            "SELECT DISTINCT ItemId FROM PlaybackActivity WHERE UserId = '{}' AND ItemType IN ('Episode','Movie')",
+            normalize_id(user_id)
+        );
+        let response = self
+            .post(
+                "/user_usage_stats/submit_custom_query",
+                &serde_json::json!({ "CustomQueryString": query, "ReplaceUserId": false }),
+            )
+            .await?;
+        let response: CustomQueryResponse = parse_response(response.error_for_status()?).await?;
+        Ok(response
+            .results
+            .into_iter()
+            .filter_map(|row| row.into_iter().next())
+            .map(|item_id| normalize_id(&item_id))
+            .collect())
+    }
+
+    /// Gets the server's public info, which doesn't require a valid token. Useful to check that
+    /// the server can be reached at all.
+    pub async fn get_public_system_info(&self) -> Result<PublicSystemInfo> {
+        let response = self.get("/System/Info/Public", None).await?;
+        parse_response(response.error_for_status()?).await
+    }
+
     pub async fn get_user_id(&self, username: &str) -> Result<Option<String>> {
         let response = self.get("/Users", None).await?;
-        let text = response.text().await?;
-        let users_response: Vec<User> = serde_json::from_str(&text)?;
+        let users_response: Vec<User> = parse_response(response).await?;
         for user in users_response {
             if user.name == username {
                 return Ok(Some(user.id));
@@ -88,18 +502,116 @@ impl JellyfinApi {
         Ok(None)
     }
 
+    /// Gets the ids of the users to sync. An explicit id is used as is, without going through
+    /// `/Users`, which server api keys may not be allowed to list.
+    pub async fn resolve_user_ids(
+        &self,
+        usernames: &[String],
+        user_id: Option<&str>,
+    ) -> Result<Vec<String>> {
+        if let Some(user_id) = user_id {
+            return Ok(vec![user_id.to_string()]);
+        }
+        if usernames.is_empty() {
+            return Err(anyhow!("JELLYFIN_USER or JELLYFIN_USER_ID is not set"));
+        }
+        let mut user_ids = vec![];
+        for username in usernames {
+            let user_id = self
+                .get_user_id(username)
+                .await?
+                .ok_or(anyhow!("user {} does not exist", username))?;
+            user_ids.push(user_id);
+        }
+        Ok(user_ids)
+    }
+
+    /// Gets the episodes of every one of the users. The same episode is listed once per user,
+    /// and `latest_episodes` counts it as watched when any of them watched it, so their progress
+    /// is merged.
+    pub async fn get_episodes_of(&self, user_ids: &[String]) -> Result<Vec<Episode>> {
+        let mut episodes = vec![];
+        for user_id in user_ids {
+            episodes.extend(self.get_episodes(user_id).await?);
+        }
+        Ok(episodes)
+    }
+
     pub async fn get_episodes(&self, user_id: &str) -> Result<Vec<Episode>> {
         let items = self.get_items(user_id, None).await?;
-        let mut series_tvdb: HashMap<String, String> = HashMap::new();
+        let mut series_tvdb: HashMap<String, i32> = HashMap::new();
+        let mut favorite_series: HashSet<String> = HashSet::new();
+        let mut unsupported_series: HashSet<String> = HashSet::new();
         let mut episodes: Vec<Episode> = vec![];
 
         for item in items.iter() {
             if item.media_type == "Series" {
-                series_tvdb.insert(item.id.clone(), item.user_data.key.clone());
+                if item.user_data.is_favorite == Some(true) {
+                    favorite_series.insert(item.id.clone());
+                }
+                // the key is only a tvdb id for series matched by the tvdb provider
+                let series_id = self.series_id(item);
+                match (series_id.as_deref().map(str::parse), self.imdb_key(item)) {
+                    (Some(Ok(tvdb_id)), _) => {
+                        series_tvdb.insert(item.id.clone(), tvdb_id);
+                    }
+                    (_, Some(imdb_key)) => {
+                        debug!("looking series {} up by its imdb id", item.name);
+                        series_tvdb.insert(item.id.clone(), imdb_key);
+                    }
+                    _ => {
+                        warn!(
+                            "skipping series {}: {:?} id {:?} is not numeric",
+                            item.name, self.id_source, series_id
+                        );
+                        unsupported_series.insert(item.id.clone());
+                    }
+                }
+            }
+        }
+
+        // series sharing an id would be synced to the same mal entry
+        let mut series_by_id: HashMap<i32, Vec<&Item>> = HashMap::new();
+        for item in items
+            .iter()
+            .filter(|item| series_tvdb.contains_key(&item.id))
+        {
+            series_by_id
+                .entry(series_tvdb[&item.id])
+                .or_default()
+                .push(item);
+        }
+        for (tvdb_id, series) in series_by_id {
+            if series.len() < 2 {
+                continue;
+            }
+            let names: Vec<&str> = series.iter().map(|item| item.name.as_str()).collect();
+            match self.duplicate_series {
+                DuplicateSeries::Merge => warn!(
+                    "merging series {} since they share {:?} id {}",
+                    names.join(", "),
+                    self.id_source,
+                    tvdb_id
+                ),
+                DuplicateSeries::Skip => {
+                    warn!(
+                        "skipping series {} since they share {:?} id {}",
+                        names.join(", "),
+                        self.id_source,
+                        tvdb_id
+                    );
+                    for item in series {
+                        unsupported_series.insert(item.id.clone());
+                    }
+                }
             }
         }
 
         for item in items {
+            if item.media_type == "Movie" {
+                episodes.extend(self.movie_episode(item));
+                continue;
+            }
             if item.media_type == "Episode" {
                 if item.index_number.is_none() {
                     continue;
@@ -107,86 +619,271 @@ impl JellyfinApi {
                 let series_name = item.series_name.ok_or(anyhow!("episode missing series"))?;
                 let index_number: i32 =
                     item.index_number.ok_or(anyhow!("episode missing number"))?;
-                let season_number = item
-                    .parent_index_number
-                    .ok_or(anyhow!("episode missing season number"))?;
                 let series_id = item.series_id.ok_or(anyhow!("episode missing series id"))?;
+                if unsupported_series.contains(&series_id) {
+                    continue;
+                }
+                // loose files outside of a season folder have no season number
+                let season_number = match (item.parent_index_number, self.missing_season_number) {
+                    (Some(season_number), _) => season_number,
+                    (None, Some(season_number)) => {
+                        info!(
+                            "episode {} of {} has no season number, counting it as season {}",
+                            index_number, series_name, season_number
+                        );
+                        season_number
+                    }
+                    (None, None) => {
+                        info!(
+                            "skipping episode {} of {}, it has no season number",
+                            index_number, series_name
+                        );
+                        continue;
+                    }
+                };
                 let tvdb_id = series_tvdb
                     .get(&series_id)
                     .ok_or(anyhow!("unable to get tvdb id for episode"))?;
                 episodes.push(Episode {
                     id: item.id,
                     number: index_number,
+                    number_end: item.index_number_end.filter(|_| self.use_index_number_end),
+                    absolute_number: item.absolute_episode_number,
                     name: item.name,
                     season_number,
                     series_name,
                     watched: item.user_data.played,
-                    tvdb_id: tvdb_id.clone().parse()?,
+                    played_percentage: item.user_data.played_percentage,
+                    tvdb_id: *tvdb_id,
+                    series_favorite: favorite_series.contains(&series_id),
+                    season_finished: false,
+                    series_id,
                 });
             }
         }
+        if self.watched_source == WatchedSource::PlaybackReporting {
+            match self.get_played_item_ids(user_id).await {
+                Ok(played) => {
+                    for episode in episodes.iter_mut() {
+                        episode.watched = played.contains(&normalize_id(&episode.id));
+                    }
+                }
+                Err(e) => warn!(
+                    "unable to read plays from the playback reporting plugin, using the played state instead: {:#}",
+                    e
+                ),
+            }
+        }
         Ok(episodes)
     }
 
     pub async fn get_latest_episodes(
         &self,
         user_id: &str,
-    ) -> anyhow::Result<HashMap<i32, Episode>> {
+    ) -> anyhow::Result<HashMap<SeasonKey, Episode>> {
         // get all episodes
         let episodes = self.get_episodes(user_id).await?;
+        Ok(self.latest_episodes(episodes))
+    }
 
-        // get the latest season and episode watched for each series
-        let mut status: HashMap<i32, Episode> = HashMap::new();
-        episodes.into_iter().for_each(|episode| {
-            if !episode.watched {
-                return;
-            }
-            let tvdb_id = episode.tvdb_id;
-            if let Some(other) = status.get(&tvdb_id) {
-                if episode.season_number > other.season_number
-                    || episode.season_number == other.season_number && episode.number > other.number
-                {
-                    status.insert(tvdb_id, episode);
+    /// Picks the latest watched episode of each season out of the episodes.
+    pub fn latest_episodes(&self, episodes: Vec<Episode>) -> HashMap<SeasonKey, Episode> {
+        // get the latest episode watched for each season of each series, since seasons can map
+        // to different mal entries
+        let mut status: HashMap<SeasonKey, Episode> = HashMap::new();
+        let mut unfinished: HashSet<SeasonKey> = HashSet::new();
+        self.collapse_versions(episodes)
+            .into_iter()
+            .for_each(|episode| {
+                if self.ignored_seasons.contains(&episode.season_number) {
+                    return;
+                }
+                // an unwatched prologue doesn't leave the season unfinished either
+                if episode.number == 0 && !self.include_episode_zero {
+                    return;
+                }
+                let key = (episode.tvdb_id, episode.season_number);
+                if !self.is_watched(&episode) {
+                    unfinished.insert(key);
+                    return;
+                }
+                if let Some(other) = status.get(&key) {
+                    if episode.last_number() > other.last_number() {
+                        status.insert(key, episode);
+                    }
+                } else {
+                    status.insert(key, episode);
+                }
+            });
+        for (key, episode) in status.iter_mut() {
+            episode.season_finished = !unfinished.contains(key);
+        }
+        status
+    }
+
+    // jellyfin lists each version of a merged episode separately. keep one per episode, a watched
+    // one if there is one, so an unplayed alternate doesn't leave the season unfinished
+    fn collapse_versions(&self, episodes: Vec<Episode>) -> Vec<Episode> {
+        let mut versions: HashMap<(SeasonKey, i32), Episode> = HashMap::new();
+        let mut order: Vec<(SeasonKey, i32)> = vec![];
+        for episode in episodes {
+            let key = ((episode.tvdb_id, episode.season_number), episode.number);
+            match versions.get(&key) {
+                Some(other) => {
+                    debug!(
+                        "collapsing versions {} and {} of episode {} of series {}",
+                        other.id, episode.id, episode.number, episode.series_name
+                    );
+                    if !self.is_watched(other) && self.is_watched(&episode) {
+                        versions.insert(key, episode);
+                    }
+                }
+                None => {
+                    order.push(key);
+                    versions.insert(key, episode);
                 }
-            } else {
-                status.insert(tvdb_id, episode);
             }
-        });
+        }
+        order
+            .into_iter()
+            .filter_map(|key| versions.remove(&key))
+            .collect()
+    }
 
+    /// Gets the highest read volume for each book series, keyed by series name.
+    pub async fn get_latest_volumes(&self, user_id: &str) -> Result<HashMap<String, i32>> {
+        let items = self.get_items(user_id, None).await?;
+        let mut status: HashMap<String, i32> = HashMap::new();
+        for item in items {
+            if item.media_type != "Book" || !item.user_data.played {
+                continue;
+            }
+            let (Some(series_name), Some(volume_number)) = (item.series_name, item.index_number)
+            else {
+                continue;
+            };
+            let latest = status.entry(series_name).or_insert(volume_number);
+            *latest = (*latest).max(volume_number);
+        }
         Ok(status)
     }
 
+    fn scans_collection(&self, item: &Item) -> bool {
+        match (&self.collection_types, &item.collection_type) {
+            (Some(collection_types), Some(collection_type)) => collection_types
+                .iter()
+                .any(|scanned| scanned.eq_ignore_ascii_case(collection_type)),
+            _ => true,
+        }
+    }
+
     pub async fn get_items(&self, user_id: &str, parent_id: Option<&str>) -> Result<Vec<Item>> {
         let mut media: Vec<Item> = vec![];
+        // series in a boxset are also listed in their library, and are only walked once
+        let mut seen: HashSet<String> = HashSet::new();
         let mut frontier: Vec<Option<String>> = vec![parent_id.map(|s| s.to_string())];
-        while !frontier.is_empty() {
-            // build the params
-            let mut params: HashMap<&str, String> = HashMap::new();
-            params.insert("userId", user_id.to_string());
-            params.insert("enableUserData", "true".to_string());
-            if let Some(Some(id)) = frontier.pop() {
-                params.insert("parentId", id);
+        let mut in_flight = FuturesUnordered::new();
+        while !frontier.is_empty() || !in_flight.is_empty() {
+            // keep up to `concurrency` folders being fetched at once
+            while in_flight.len() < self.concurrency {
+                match frontier.pop() {
+                    Some(parent) => {
+                        let is_root = parent.is_none();
+                        in_flight.push(async move {
+                            (is_root, self.get_child_items(user_id, parent).await)
+                        })
+                    }
+                    None => break,
+                }
             }
-            // get all items under this root
-            let response: Response = self.get("/Items", Some(params)).await?;
-            let text: String = response.text().await?;
-            let items_response: ItemsResponse =
-                serde_json::from_str(&text).context("unable to parse items")?;
-            for item in items_response.items {
-                if item.is_folder {
-                    frontier.push(Some(item.id.clone()));
+            // collect whichever folder finishes first, queueing its subfolders
+            if let Some((is_root, items)) = in_flight.next().await {
+                for item in items? {
+                    // the libraries are at the root, and only some of them can hold anime
+                    if is_root && !self.scans_collection(&item) {
+                        debug!(
+                            "skipping the {} library {}",
+                            item.collection_type.as_deref().unwrap_or_default(),
+                            item.name
+                        );
+                        continue;
+                    }
+                    if !seen.insert(item.id.clone()) {
+                        continue;
+                    }
+                    if item.is_folder {
+                        frontier.push(Some(item.id.clone()));
+                    }
+                    media.push(item);
                 }
-                media.push(item);
             }
         }
         Ok(media)
     }
+
+    async fn get_child_items(&self, user_id: &str, parent_id: Option<String>) -> Result<Vec<Item>> {
+        // build the params
+        let mut params: HashMap<&str, String> = HashMap::new();
+        params.insert("userId", user_id.to_string());
+        params.insert("enableUserData", "true".to_string());
+        params.insert("fields", "ProviderIds".to_string());
+        params.insert("limit", PAGE_SIZE.to_string());
+        if let Some(id) = parent_id {
+            params.insert("parentId", id);
+        }
+        // get all items under this root
+        self.get_paged_items(params).await
+    }
+
+    /// Gets the episodes and films whose user data (e.g. played state or progress) was saved
+    /// since the given time, across the whole library. Enough to sync incrementally, without
+    /// walking every folder again.
+    pub async fn get_recently_played(
+        &self,
+        user_id: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<Item>> {
+        let mut params: HashMap<&str, String> = HashMap::new();
+        params.insert("userId", user_id.to_string());
+        params.insert("enableUserData", "true".to_string());
+        params.insert("fields", "ProviderIds".to_string());
+        params.insert("limit", PAGE_SIZE.to_string());
+        params.insert("recursive", "true".to_string());
+        params.insert("includeItemTypes", "Episode,Movie".to_string());
+        params.insert(
+            "minDateLastSavedForUser",
+            since.to_rfc3339_opts(SecondsFormat::Secs, true),
+        );
+        self.get_paged_items(params).await
+    }
+
+    // gets every item matching the params, a page at a time
+    async fn get_paged_items(&self, mut params: HashMap<&str, String>) -> Result<Vec<Item>> {
+        let mut items: Vec<Item> = vec![];
+        loop {
+            params.insert("startIndex", items.len().to_string());
+            let response: Response = self.get("/Items", Some(params.clone())).await?;
+            let items_response: ItemsResponse = if self.strict_parse {
+                parse_response_strict(response).await
+            } else {
+                parse_response(response).await
+            }
+            .context("unable to parse items")?;
+            let page_size = items_response.items.len();
+            items.extend(items_response.items);
+            match items_response.total_record_count {
+                Some(total) if page_size > 0 && items.len() < total => continue,
+                _ => break,
+            }
+        }
+        Ok(items)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use serde_json::json;
-    use wiremock::matchers::{method, path};
+    use wiremock::matchers::{body_partial_json, header, method, path, query_param};
     use wiremock::{Mock, MockServer, ResponseTemplate};
 
     use super::*;
@@ -211,6 +908,195 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_resolve_user_id() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let jellyfin_client = JellyfinApi::new(&server.uri(), "token");
+        // the api key can't list the users
+        Mock::given(method("GET"))
+            .and(path("/Users"))
+            .respond_with(ResponseTemplate::new(403))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let user_ids = jellyfin_client
+            .resolve_user_ids(&["alyosha".to_string()], Some("123"))
+            .await?;
+        assert_eq!(user_ids, vec!["123"]);
+        assert!(jellyfin_client.resolve_user_ids(&[], None).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_episodes_of_merges_users() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let episode = |number: i32, played: bool| {
+            json!({
+                "Id": format!("e{}", number),
+                "Type": "Episode",
+                "Name": format!("episode {}", number),
+                "IsFolder": false,
+                "IndexNumber": number,
+                "ParentIndexNumber": 1,
+                "SeriesName": "test_series",
+                "SeriesId": "14",
+                "UserData": { "Key": "e", "Played": played }
+            })
+        };
+        let series = json!({
+            "Id": "14",
+            "Type": "Series",
+            "Name": "test_series",
+            "IsFolder": false,
+            "UserData": { "Key": "42", "Played": false }
+        });
+        // one user is further into the series than the other
+        for (user_id, watched) in [("alyosha", 3), ("ivan", 5)] {
+            let items: Vec<serde_json::Value> = std::iter::once(series.clone())
+                .chain((1..=6).map(|number| episode(number, number <= watched)))
+                .collect();
+            Mock::given(method("GET"))
+                .and(path("/Items"))
+                .and(query_param("userId", user_id))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "Items": items })))
+                .mount(&server)
+                .await;
+        }
+
+        let jellyfin_client = JellyfinApi::new(&server.uri(), "token");
+        let episodes = jellyfin_client
+            .get_episodes_of(&["alyosha".to_string(), "ivan".to_string()])
+            .await?;
+        let latest_episodes = jellyfin_client.latest_episodes(episodes);
+        assert_eq!(latest_episodes.len(), 1);
+        assert_eq!(latest_episodes[&(42, 1)].number, 5);
+        assert!(!latest_episodes[&(42, 1)].season_finished);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_public_system_info() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let jellyfin_client = JellyfinApi::new(&server.uri(), "token");
+        Mock::given(method("GET"))
+            .and(path("/System/Info/Public"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "ServerName": "jellyfin",
+                "Version": "10.8.13",
+                "Id": "abc"
+            })))
+            .mount(&server)
+            .await;
+
+        let info = jellyfin_client.get_public_system_info().await?;
+        assert_eq!(info.server_name, "jellyfin");
+        assert_eq!(info.version, "10.8.13");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_retries_server_error() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/System/Info/Public"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/System/Info/Public"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "ServerName": "jellyfin",
+                "Version": "10.8.13"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let jellyfin_client = JellyfinApi::new(&server.uri(), "token")
+            .with_retry_backoff(Backoff::new(std::time::Duration::ZERO));
+        assert_eq!(
+            jellyfin_client.get_public_system_info().await?.server_name,
+            "jellyfin"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_html_error_body() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let jellyfin_client = JellyfinApi::new(&server.uri(), "token");
+        Mock::given(method("GET"))
+            .and(path("/Users"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string("<html><head><title>Sign in</title></head></html>"),
+            )
+            .mount(&server)
+            .await;
+
+        // a proxy's login page names the url and shows up in the error
+        let error = jellyfin_client.get_user_id("alyosha").await.unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(message.contains(&format!("{}/Users", server.uri())));
+        assert!(message.contains("<title>Sign in</title>"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auth_schemes() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Users"))
+            .and(header("X-Emby-Token", "token"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("[]"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/Users"))
+            .and(header("Authorization", "MediaBrowser Token=\"token\""))
+            .respond_with(ResponseTemplate::new(200).set_body_string("[]"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        JellyfinApi::new(&server.uri(), "token")
+            .get_user_id("alyosha")
+            .await?;
+        JellyfinApi::new(&server.uri(), "token")
+            .with_auth_scheme(AuthScheme::MediaBrowser)
+            .get_user_id("alyosha")
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_custom_headers() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Users"))
+            .and(header("X-Emby-Token", "token"))
+            .and(header("X-Forwarded-User", "alyosha"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("[]"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-User", "alyosha".parse()?);
+        // a custom token header doesn't replace the configured one
+        headers.insert("X-Emby-Token", "proxy".parse()?);
+        JellyfinApi::new(&server.uri(), "token")
+            .with_headers(headers)
+            .get_user_id("alyosha")
+            .await?;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_get_episodes() -> anyhow::Result<()> {
         let server = MockServer::start().await;
@@ -249,9 +1135,9 @@ mod tests {
             .respond_with(move |request: &wiremock::Request| {
                 let parent_id = request.url.query_pairs().find(|(key, _)| key == "parentId");
                 if parent_id.is_some() {
-                    return ResponseTemplate::new(200).set_body_json(json!({ "Items": [data[1]] }));
+                    ResponseTemplate::new(200).set_body_json(json!({ "Items": [data[1]] }))
                 } else {
-                    return ResponseTemplate::new(200).set_body_json(json!({ "Items": [data[0]] }));
+                    ResponseTemplate::new(200).set_body_json(json!({ "Items": [data[0]] }))
                 }
             })
             .mount(&server)
@@ -266,33 +1152,317 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_get_latest_episodes() -> anyhow::Result<()> {
+    async fn test_get_episodes_playback_reporting() -> anyhow::Result<()> {
         let server = MockServer::start().await;
-        let jellyfin_client = JellyfinApi::new(&server.uri(), "token");
-        let user_id = "123";
-        let data = json!([
-            {
-                "Id": "14",
-                "Type": "Series",
-                "Name": "test_series",
-                "IsFolder": true,
-                "UserData": {
-                    "Key": "42",
-                    "Played": false,
-                }
-            },
-            {
-                "Id": "15",
+        let episode = |id: &str, number: i32, played: bool| {
+            json!({
+                "Id": id,
                 "Type": "Episode",
-                "Name": "test_episode",
+                "Name": format!("episode {}", number),
                 "IsFolder": false,
-                "IndexNumber": 42, // episode 42
-                "ParentIndexNumber": 1, // season 1
+                "IndexNumber": number,
+                "ParentIndexNumber": 1,
                 "SeriesName": "test_series",
-                "ParentId": "14",
                 "SeriesId": "14",
-                "UserData": {
-                    "Played": true,
+                "UserData": { "Played": played, "Key": "episode" }
+            })
+        };
+        let series = json!({
+            "Id": "14",
+            "Type": "Series",
+            "Name": "test_series",
+            "IsFolder": true,
+            "UserData": { "Key": "42", "Played": false }
+        });
+        let episodes = json!([episode("1a", 1, true), episode("2b", 2, false)]);
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(move |request: &wiremock::Request| {
+                let parent_id = request.url.query_pairs().find(|(key, _)| key == "parentId");
+                match parent_id {
+                    Some(_) => ResponseTemplate::new(200)
+                        .set_body_json(json!({ "Items": episodes.clone() })),
+                    None => ResponseTemplate::new(200).set_body_json(json!({ "Items": [series] })),
+                }
+            })
+            .mount(&server)
+            .await;
+
+        // without the plugin, the played state is used
+        let jellyfin_client = JellyfinApi::new(&server.uri(), "token")
+            .with_watched_source(WatchedSource::PlaybackReporting);
+        let watched = |episodes: Vec<Episode>| -> Vec<bool> {
+            episodes.iter().map(|episode| episode.watched).collect()
+        };
+        assert_eq!(
+            watched(jellyfin_client.get_episodes("12-3").await?),
+            vec![true, false]
+        );
+
+        Mock::given(method("POST"))
+            .and(path("/user_usage_stats/submit_custom_query"))
+            .and(body_partial_json(json!({
+                "CustomQueryString": "SELECT DISTINCT ItemId FROM PlaybackActivity WHERE UserId = '123' AND ItemType IN ('Episode','Movie')"
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "colums": ["ItemId"],
+                "results": [["2B"]]
+            })))
+            .mount(&server)
+            .await;
+        assert_eq!(
+            watched(jellyfin_client.get_episodes("12-3").await?),
+            vec![false, true]
+        );
+        assert!(jellyfin_client
+            .get_played_item_ids("1' OR '1")
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_episodes_non_numeric_key() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let jellyfin_client = JellyfinApi::new(&server.uri(), "token");
+        let series = |id: &str, key: &str| {
+            json!({
+                "Id": id,
+                "Type": "Series",
+                "Name": format!("series_{}", id),
+                "IsFolder": false,
+                "UserData": { "Key": key, "Played": false }
+            })
+        };
+        let episode = |id: &str, series_id: &str| {
+            json!({
+                "Id": id,
+                "Type": "Episode",
+                "Name": "test_episode",
+                "IsFolder": false,
+                "IndexNumber": 1,
+                "ParentIndexNumber": 1,
+                "SeriesName": format!("series_{}", series_id),
+                "SeriesId": series_id,
+                "UserData": { "Played": true, "Key": "some_other_not_useful_id" }
+            })
+        };
+        let items = json!([
+            series("14", "42"),
+            series("24", "tmdb-not-a-tvdb-id"),
+            episode("15", "14"),
+            episode("25", "24"),
+        ]);
+
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "Items": items })))
+            .mount(&server)
+            .await;
+
+        let result = jellyfin_client.get_episodes("123").await?;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].tvdb_id, 42);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_episodes_id_sources() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let items = json!([
+            {
+                "Id": "14",
+                "Type": "Series",
+                "Name": "test_series",
+                "IsFolder": false,
+                "UserData": { "Key": "42", "Played": false },
+                "ProviderIds": { "Tvdb": "80644", "AniDB": "5841", "AniList": "4181" }
+            },
+            {
+                "Id": "15",
+                "Type": "Episode",
+                "Name": "test_episode",
+                "IsFolder": false,
+                "IndexNumber": 1,
+                "ParentIndexNumber": 1,
+                "SeriesName": "test_series",
+                "SeriesId": "14",
+                "UserData": { "Played": true, "Key": "some_other_not_useful_id" }
+            }
+        ]);
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "Items": items })))
+            .mount(&server)
+            .await;
+
+        for (id_source, expected) in [
+            (IdSource::UserDataKey, 42),
+            (IdSource::Tvdb, 80644),
+            (IdSource::AniDb, 5841),
+            (IdSource::AniList, 4181),
+        ] {
+            let jellyfin_client =
+                JellyfinApi::new(&server.uri(), "token").with_id_source(id_source);
+            let result = jellyfin_client.get_episodes("123").await?;
+            assert_eq!(result.len(), 1);
+            assert_eq!(result[0].tvdb_id, expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_episodes_imdb_fallback() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let items = json!([
+            {
+                "Id": "14",
+                "Type": "Series",
+                "Name": "Spirited Away",
+                "IsFolder": false,
+                "UserData": { "Key": "Spirited Away", "Played": false },
+                "ProviderIds": { "Imdb": "tt0245429" }
+            },
+            {
+                "Id": "15",
+                "Type": "Episode",
+                "Name": "Spirited Away",
+                "IsFolder": false,
+                "IndexNumber": 1,
+                "ParentIndexNumber": 1,
+                "SeriesName": "Spirited Away",
+                "SeriesId": "14",
+                "UserData": { "Played": true, "Key": "some_other_not_useful_id" }
+            }
+        ]);
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "Items": items })))
+            .mount(&server)
+            .await;
+
+        let result = JellyfinApi::new(&server.uri(), "token")
+            .get_episodes("123")
+            .await?;
+        assert_eq!(result[0].tvdb_id, -245429);
+        // the anidb and anilist mappings have no use for an imdb id
+        let result = JellyfinApi::new(&server.uri(), "token")
+            .with_id_source(IdSource::AniDb)
+            .get_episodes("123")
+            .await?;
+        assert!(result.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_episodes_movies() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let movie = |id: &str, provider_ids: serde_json::Value, played: bool| {
+            json!({
+                "Id": id,
+                "Type": "Movie",
+                "Name": format!("film {}", id),
+                "IsFolder": false,
+                "UserData": { "Key": "not_an_id", "Played": played },
+                "ProviderIds": provider_ids
+            })
+        };
+        let items = json!([
+            movie("1", json!({ "Imdb": "tt0245429", "AniDb": "112" }), true),
+            movie("2", json!({ "Imdb": "tt0347149" }), false),
+            // a live action film, without anything to map it by
+            movie("3", json!({ "Tmdb": "550" }), true),
+        ]);
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "Items": items })))
+            .mount(&server)
+            .await;
+
+        let jellyfin_client = JellyfinApi::new(&server.uri(), "token");
+        let latest_episodes =
+            jellyfin_client.latest_episodes(jellyfin_client.get_episodes("123").await?);
+        // only the watched film counts, as the first and only episode
+        assert_eq!(latest_episodes.len(), 1);
+        let film = &latest_episodes[&(-245429, 1)];
+        assert_eq!((film.number, film.series_id.as_str()), (1, "1"));
+        assert!(film.season_finished);
+
+        let result = JellyfinApi::new(&server.uri(), "token")
+            .with_id_source(IdSource::AniDb)
+            .get_episodes("123")
+            .await?;
+        let ids: Vec<i32> = result.iter().map(|episode| episode.tvdb_id).collect();
+        assert_eq!(ids, vec![112]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_episodes_movies_playback_reporting() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let items = json!([{
+            "Id": "1a",
+            "Type": "Movie",
+            "Name": "film",
+            "IsFolder": false,
+            "UserData": { "Key": "not_an_id", "Played": false },
+            "ProviderIds": { "Imdb": "tt0245429" }
+        }]);
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "Items": items })))
+            .mount(&server)
+            .await;
+        // the plugin only returns the film when the query asks for films
+        Mock::given(method("POST"))
+            .and(path("/user_usage_stats/submit_custom_query"))
+            .and(body_partial_json(json!({
+                "CustomQueryString": "SELECT DISTINCT ItemId FROM PlaybackActivity WHERE UserId = '123' AND ItemType IN ('Episode','Movie')"
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "colums": ["ItemId"],
+                "results": [["1A"]]
+            })))
+            .mount(&server)
+            .await;
+
+        // a film played according to the plugin counts as watched
+        let jellyfin_client = JellyfinApi::new(&server.uri(), "token")
+            .with_watched_source(WatchedSource::PlaybackReporting);
+        let latest_episodes =
+            jellyfin_client.latest_episodes(jellyfin_client.get_episodes("123").await?);
+        assert_eq!(latest_episodes[&(-245429, 1)].series_id, "1a");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_latest_episodes() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let jellyfin_client = JellyfinApi::new(&server.uri(), "token");
+        let user_id = "123";
+        let data = json!([
+            {
+                "Id": "14",
+                "Type": "Series",
+                "Name": "test_series",
+                "IsFolder": true,
+                "UserData": {
+                    "Key": "42",
+                    "Played": false,
+                }
+            },
+            {
+                "Id": "15",
+                "Type": "Episode",
+                "Name": "test_episode",
+                "IsFolder": false,
+                "IndexNumber": 42, // episode 42
+                "ParentIndexNumber": 1, // season 1
+                "SeriesName": "test_series",
+                "ParentId": "14",
+                "SeriesId": "14",
+                "UserData": {
+                    "Played": true,
                     "Key": "some_other_not_useful_id"
                 }
             },
@@ -349,19 +1519,736 @@ mod tests {
             .respond_with(move |request: &wiremock::Request| {
                 let parent_id = request.url.query_pairs().find(|(key, _)| key == "parentId");
                 if parent_id.is_some() {
-                    return ResponseTemplate::new(200)
-                        .set_body_json(json!({ "Items": [data[1], data[2], data[3]] }));
+                    ResponseTemplate::new(200)
+                        .set_body_json(json!({ "Items": [data[1], data[2], data[3]] }))
                 } else {
-                    return ResponseTemplate::new(200).set_body_json(json!({ "Items": [data[0]] }));
+                    ResponseTemplate::new(200).set_body_json(json!({ "Items": [data[0]] }))
                 }
             })
             .mount(&server)
             .await;
 
         let result = jellyfin_client.get_latest_episodes(user_id).await?;
+        // each season is tracked on its own
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[&(42, 1)].number, 42);
+        assert_eq!(result[&(42, 2)].number, 9);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_latest_volumes() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let jellyfin_client = JellyfinApi::new(&server.uri(), "token");
+        let book = |id: &str, volume: i32, played: bool| {
+            json!({
+                "Id": id,
+                "Type": "Book",
+                "Name": format!("Berserk Vol. {}", volume),
+                "IsFolder": false,
+                "IndexNumber": volume,
+                "SeriesName": "Berserk",
+                "UserData": { "Key": id, "Played": played }
+            })
+        };
+        let items = json!([book("1", 1, true), book("2", 2, true), book("3", 3, false)]);
+
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "Items": items })))
+            .mount(&server)
+            .await;
+
+        let result = jellyfin_client.get_latest_volumes("123").await?;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result["Berserk"], 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_latest_episodes_multi_season() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let episode = |id: &str, season: i32, number: i32, played: bool| {
+            json!({
+                "Id": id,
+                "Type": "Episode",
+                "Name": "test_episode",
+                "IsFolder": false,
+                "IndexNumber": number,
+                "ParentIndexNumber": season,
+                "SeriesName": "test_series",
+                "SeriesId": "14",
+                "UserData": { "Played": played, "Key": "some_other_not_useful_id" }
+            })
+        };
+        let items = json!([
+            {
+                "Id": "14",
+                "Type": "Series",
+                "Name": "test_series",
+                "IsFolder": false,
+                "UserData": { "Key": "42", "Played": false }
+            },
+            episode("15", 1, 12, true),
+            episode("16", 1, 13, true),
+            episode("17", 2, 1, true),
+            episode("18", 2, 2, false),
+            // nothing watched in season 3 yet
+            episode("19", 3, 1, false),
+        ]);
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "Items": items })))
+            .mount(&server)
+            .await;
+
+        let result = JellyfinApi::new(&server.uri(), "token")
+            .get_latest_episodes("123")
+            .await?;
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[&(42, 1)].number, 13);
+        assert_eq!(result[&(42, 2)].number, 1);
+        assert!(!result.contains_key(&(42, 3)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_latest_episodes_ignores_specials() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let episode = |id: &str, series_id: &str, season: i32, number: i32| {
+            json!({
+                "Id": id,
+                "Type": "Episode",
+                "Name": "test_episode",
+                "IsFolder": false,
+                "IndexNumber": number,
+                "ParentIndexNumber": season,
+                "SeriesName": format!("series_{}", series_id),
+                "SeriesId": series_id,
+                "UserData": { "Played": true, "Key": "some_other_not_useful_id" }
+            })
+        };
+        let series = |id: &str, key: &str| {
+            json!({
+                "Id": id,
+                "Type": "Series",
+                "Name": format!("series_{}", id),
+                "IsFolder": false,
+                "UserData": { "Key": key, "Played": false }
+            })
+        };
+        let items = json!([
+            series("14", "42"),
+            series("24", "43"),
+            episode("15", "14", 1, 3),
+            episode("16", "14", 0, 5),
+            // only specials watched
+            episode("25", "24", 0, 1),
+        ]);
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "Items": items })))
+            .mount(&server)
+            .await;
+
+        let result = JellyfinApi::new(&server.uri(), "token")
+            .get_latest_episodes("123")
+            .await?;
         assert_eq!(result.len(), 1);
-        assert_eq!(result[&42].number, 9);
-        assert_eq!(result[&42].season_number, 2);
+        assert_eq!(result[&(42, 1)].number, 3);
+
+        let result = JellyfinApi::new(&server.uri(), "token")
+            .with_ignored_seasons(vec![])
+            .get_latest_episodes("123")
+            .await?;
+        assert_eq!(result[&(43, 0)].number, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_episodes_index_number_end() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let items = json!([
+            {
+                "Id": "14",
+                "Type": "Series",
+                "Name": "test_series",
+                "IsFolder": false,
+                "UserData": { "Key": "42", "Played": false }
+            },
+            {
+                "Id": "15",
+                "Type": "Episode",
+                "Name": "episodes 1-2",
+                "IsFolder": false,
+                "IndexNumber": 1,
+                "IndexNumberEnd": 2,
+                "ParentIndexNumber": 1,
+                "SeriesName": "test_series",
+                "SeriesId": "14",
+                "UserData": { "Played": true, "Key": "some_other_not_useful_id" }
+            }
+        ]);
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "Items": items })))
+            .mount(&server)
+            .await;
+
+        let result = JellyfinApi::new(&server.uri(), "token")
+            .get_latest_episodes("123")
+            .await?;
+        assert_eq!(result[&(42, 1)].last_number(), 2);
+
+        let result = JellyfinApi::new(&server.uri(), "token")
+            .with_index_number_end(false)
+            .get_latest_episodes("123")
+            .await?;
+        assert_eq!(result[&(42, 1)].last_number(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_episodes_missing_season_number() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let items = json!([
+            {
+                "Id": "14",
+                "Type": "Series",
+                "Name": "test_series",
+                "IsFolder": false,
+                "UserData": { "Key": "42", "Played": false, "IsFavorite": true }
+            },
+            {
+                "Id": "15",
+                "Type": "Episode",
+                "Name": "loose episode",
+                "IsFolder": false,
+                "IndexNumber": 3,
+                "SeriesName": "test_series",
+                "SeriesId": "14",
+                "UserData": { "Played": true, "Key": "some_other_not_useful_id" }
+            }
+        ]);
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "Items": items })))
+            .mount(&server)
+            .await;
+
+        let episodes = JellyfinApi::new(&server.uri(), "token")
+            .get_episodes("123")
+            .await?;
+        assert_eq!(episodes.len(), 1);
+        assert_eq!(episodes[0].season_number, 1);
+        assert!(episodes[0].series_favorite);
+
+        let episodes = JellyfinApi::new(&server.uri(), "token")
+            .with_missing_season_number(Some(2))
+            .get_episodes("123")
+            .await?;
+        assert_eq!(episodes[0].season_number, 2);
+
+        let episodes = JellyfinApi::new(&server.uri(), "token")
+            .with_missing_season_number(None)
+            .get_episodes("123")
+            .await?;
+        assert!(episodes.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_episodes_duplicate_series() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let series = |id: &str, name: &str| {
+            json!({
+                "Id": id,
+                "Type": "Series",
+                "Name": name,
+                "IsFolder": false,
+                "UserData": { "Key": "42", "Played": false }
+            })
+        };
+        let episode = |id: &str, series_id: &str, number: i32| {
+            json!({
+                "Id": id,
+                "Type": "Episode",
+                "Name": format!("episode {}", number),
+                "IsFolder": false,
+                "IndexNumber": number,
+                "ParentIndexNumber": 1,
+                "SeriesName": "test_series",
+                "SeriesId": series_id,
+                "UserData": { "Played": true, "Key": "some_other_not_useful_id" }
+            })
+        };
+        let items = json!([
+            series("1", "test_series"),
+            series("2", "test_series (merged)"),
+            episode("3", "1", 4),
+            episode("4", "2", 7),
+        ]);
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "Items": items })))
+            .mount(&server)
+            .await;
+
+        let result = JellyfinApi::new(&server.uri(), "token")
+            .get_latest_episodes("123")
+            .await?;
+        assert_eq!(result[&(42, 1)].number, 7);
+
+        let result = JellyfinApi::new(&server.uri(), "token")
+            .with_duplicate_series(DuplicateSeries::Skip)
+            .get_latest_episodes("123")
+            .await?;
+        assert!(result.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_recently_played() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .and(query_param(
+                "minDateLastSavedForUser",
+                "2024-03-01T12:00:00Z",
+            ))
+            .and(query_param("recursive", "true"))
+            .and(query_param("includeItemTypes", "Episode,Movie"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "Items": [{
+                    "Id": "15",
+                    "Type": "Episode",
+                    "Name": "test_episode",
+                    "IsFolder": false,
+                    "IndexNumber": 3,
+                    "ParentIndexNumber": 1,
+                    "SeriesName": "test_series",
+                    "SeriesId": "14",
+                    "UserData": { "Played": true, "Key": "some_other_not_useful_id" }
+                }],
+                "TotalRecordCount": 1
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let since = DateTime::parse_from_rfc3339("2024-03-01T12:00:00Z")?.with_timezone(&Utc);
+        let items = JellyfinApi::new(&server.uri(), "token")
+            .get_recently_played("123", since)
+            .await?;
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].id, "15");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_strict_parse() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "Items": [{
+                    "Id": "15",
+                    "Type": "Episode",
+                    "Name": "test_episode",
+                    "IsFolder": false,
+                    "SeriesId": "14",
+                    "UserData": { "Played": true, "Key": "key", "PlayCount": 2 }
+                }],
+                "TotalRecordCount": 1
+            })))
+            .mount(&server)
+            .await;
+        let since = Utc::now();
+
+        // unknown fields are ignored by default
+        let jellyfin_client = JellyfinApi::new(&server.uri(), "token");
+        assert_eq!(
+            jellyfin_client
+                .get_recently_played("123", since)
+                .await?
+                .len(),
+            1
+        );
+
+        let Err(error) = jellyfin_client
+            .with_strict_parse(true)
+            .get_recently_played("123", since)
+            .await
+        else {
+            panic!("expected the unknown field to fail the parse");
+        };
+        assert!(format!("{:#}", error).contains("unexpected fields"));
+        assert!(format!("{:#}", error).contains("Items[].UserData.PlayCount"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_items_paginated() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let total = PAGE_SIZE + 2;
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(move |request: &wiremock::Request| {
+                let start_index: usize = request
+                    .url
+                    .query_pairs()
+                    .find(|(key, _)| key == "startIndex")
+                    .map(|(_, value)| value.parse().unwrap())
+                    .unwrap_or(0);
+                let items: Vec<serde_json::Value> = (start_index
+                    ..total.min(start_index + PAGE_SIZE))
+                    .map(|id| {
+                        json!({
+                            "Id": id.to_string(),
+                            "Type": "Episode",
+                            "Name": "test_episode",
+                            "IsFolder": false,
+                            "UserData": { "Played": true, "Key": "some_other_not_useful_id" }
+                        })
+                    })
+                    .collect();
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "Items": items, "TotalRecordCount": total }))
+            })
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let items = JellyfinApi::new(&server.uri(), "token")
+            .get_items("123", None)
+            .await?;
+        assert_eq!(items.len(), total);
+        assert_eq!(items[total - 1].id, (total - 1).to_string());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_items_skips_collections() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let library = |id: &str, collection_type: &str| {
+            json!({
+                "Id": id,
+                "Type": "CollectionFolder",
+                "Name": format!("{} library", collection_type),
+                "IsFolder": true,
+                "CollectionType": collection_type,
+                "UserData": { "Key": id, "Played": false }
+            })
+        };
+        let libraries = json!([
+            library("1", "tvshows"),
+            library("2", "music"),
+            library("3", "photos"),
+            library("4", "books"),
+        ]);
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(move |request: &wiremock::Request| {
+                let parent_id = request.url.query_pairs().find(|(key, _)| key == "parentId");
+                match parent_id {
+                    Some(_) => ResponseTemplate::new(200).set_body_json(json!({ "Items": [] })),
+                    None => ResponseTemplate::new(200).set_body_json(json!({ "Items": libraries })),
+                }
+            })
+            .mount(&server)
+            .await;
+
+        let items = JellyfinApi::new(&server.uri(), "token")
+            .get_items("123", None)
+            .await?;
+        let ids: Vec<&str> = items.iter().map(|item| item.id.as_str()).collect();
+        assert_eq!(ids, vec!["1"]);
+        // only the tvshows library was walked into
+        let walked = server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|request| request.url.query().unwrap_or_default().contains("parentId"))
+            .count();
+        assert_eq!(walked, 1);
+
+        let items = JellyfinApi::new(&server.uri(), "token")
+            .with_collection_types(None)
+            .get_items("123", None)
+            .await?;
+        assert_eq!(items.len(), 4);
+        Ok(())
+    }
+
+    fn partially_played_episode(played_percentage: f64) -> Episode {
+        Episode {
+            id: "15".to_string(),
+            number: 8,
+            number_end: None,
+            absolute_number: None,
+            name: "test_episode".to_string(),
+            season_number: 2,
+            series_name: "test_series".to_string(),
+            series_id: "42".to_string(),
+            tvdb_id: 42,
+            watched: false,
+            played_percentage: Some(played_percentage),
+            series_favorite: false,
+            season_finished: false,
+        }
+    }
+
+    #[test]
+    fn test_series_name_contains() {
+        let episode = |series_name: &str| Episode {
+            series_name: series_name.to_string(),
+            ..partially_played_episode(0.0)
+        };
+        let episodes = [
+            episode("Mobile Suit Gundam"),
+            episode("Cowboy Bebop"),
+            episode("GUNDAM Build Fighters"),
+        ];
+        let matching: Vec<&str> = episodes
+            .iter()
+            .filter(|episode| episode.series_name_contains("gundam"))
+            .map(|episode| episode.series_name.as_str())
+            .collect();
+        assert_eq!(
+            matching,
+            vec!["Mobile Suit Gundam", "GUNDAM Build Fighters"]
+        );
+    }
+
+    #[test]
+    fn test_is_watched_threshold() {
+        let jellyfin_client = JellyfinApi::new("http://localhost", "token")
+            .with_watched_percent_threshold(Some(90.0));
+        assert!(!jellyfin_client.is_watched(&partially_played_episode(85.0)));
+        assert!(jellyfin_client.is_watched(&partially_played_episode(90.0)));
+        assert!(jellyfin_client.is_watched(&partially_played_episode(95.0)));
+    }
+
+    #[test]
+    fn test_is_watched_without_threshold() {
+        let jellyfin_client = JellyfinApi::new("http://localhost", "token");
+        assert!(!jellyfin_client.is_watched(&partially_played_episode(95.0)));
+
+        let mut episode = partially_played_episode(0.0);
+        episode.watched = true;
+        assert!(jellyfin_client.is_watched(&episode));
+    }
+
+    #[test]
+    fn test_latest_episodes_season_finished() {
+        let jellyfin_client = JellyfinApi::new("http://localhost", "token");
+        let episode = |season_number: i32, number: i32, watched: bool| Episode {
+            number,
+            season_number,
+            watched,
+            ..partially_played_episode(0.0)
+        };
+        let latest_episodes = jellyfin_client.latest_episodes(vec![
+            // every episode of season 1 is played
+            episode(1, 1, true),
+            episode(1, 2, true),
+            // only some of season 2
+            episode(2, 1, true),
+            episode(2, 2, false),
+            // and none of season 3
+            episode(3, 1, false),
+        ]);
+        assert!(latest_episodes[&(42, 1)].season_finished);
+        assert!(!latest_episodes[&(42, 2)].season_finished);
+        assert!(!latest_episodes.contains_key(&(42, 3)));
+    }
+
+    #[test]
+    fn test_latest_episodes_episode_zero() {
+        let episode = |number: i32, watched: bool| Episode {
+            number,
+            season_number: 1,
+            watched,
+            ..partially_played_episode(0.0)
+        };
+        let episodes = || vec![episode(0, false), episode(1, true), episode(2, true)];
+        let latest_episodes =
+            JellyfinApi::new("http://localhost", "token").latest_episodes(episodes());
+        assert_eq!(latest_episodes[&(42, 1)].number, 2);
+        assert!(latest_episodes[&(42, 1)].season_finished);
+        // a prologue watched on its own isn't an episode
+        let latest_episodes =
+            JellyfinApi::new("http://localhost", "token").latest_episodes(vec![episode(0, true)]);
+        assert!(latest_episodes.is_empty());
+
+        let latest_episodes = JellyfinApi::new("http://localhost", "token")
+            .with_include_episode_zero(true)
+            .latest_episodes(episodes());
+        assert!(!latest_episodes[&(42, 1)].season_finished);
+    }
+
+    #[test]
+    fn test_latest_episodes_merged_versions() {
+        let jellyfin_client = JellyfinApi::new("http://localhost", "token");
+        let episode = |id: &str, number: i32, watched: bool| Episode {
+            id: id.to_string(),
+            number,
+            season_number: 1,
+            watched,
+            ..partially_played_episode(0.0)
+        };
+        let latest_episodes = jellyfin_client.latest_episodes(vec![
+            episode("4", 4, true),
+            // two releases of episode 5, only one of them played
+            episode("5-tv", 5, false),
+            episode("5-bd", 5, true),
+        ]);
+        let latest = &latest_episodes[&(42, 1)];
+        assert_eq!(latest.id, "5-bd");
+        assert!(latest.season_finished);
+    }
+
+    #[tokio::test]
+    async fn test_get_items_nested_folders() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let folder = |id: &str| {
+            json!({
+                "Id": id,
+                "Type": "Folder",
+                "Name": id,
+                "IsFolder": true,
+                "UserData": { "Key": id, "Played": false }
+            })
+        };
+        let video = |id: &str| {
+            json!({
+                "Id": id,
+                "Type": "Video",
+                "Name": id,
+                "IsFolder": false,
+                "UserData": { "Key": id, "Played": false }
+            })
+        };
+        // root -> a, b; a -> a1, a_video; a1 -> a1_video; b -> b_video
+        let tree: HashMap<&str, Vec<serde_json::Value>> = HashMap::from([
+            ("", vec![folder("a"), folder("b")]),
+            ("a", vec![folder("a1"), video("a_video")]),
+            ("a1", vec![video("a1_video")]),
+            ("b", vec![video("b_video")]),
+        ]);
+
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(move |request: &wiremock::Request| {
+                let parent_id = request
+                    .url
+                    .query_pairs()
+                    .find(|(key, _)| key == "parentId")
+                    .map(|(_, value)| value.to_string())
+                    .unwrap_or_default();
+                let items = tree.get(parent_id.as_str()).cloned().unwrap_or_default();
+                ResponseTemplate::new(200).set_body_json(json!({ "Items": items }))
+            })
+            .mount(&server)
+            .await;
+
+        for concurrency in [1, 2, 8] {
+            let jellyfin_client =
+                JellyfinApi::new(&server.uri(), "token").with_concurrency(concurrency);
+            let mut ids: Vec<String> = jellyfin_client
+                .get_items("123", None)
+                .await?
+                .into_iter()
+                .map(|item| item.id)
+                .collect();
+            ids.sort();
+            assert_eq!(ids, vec!["a", "a1", "a1_video", "a_video", "b", "b_video"]);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_episodes_boxset() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let series = |id: &str, tvdb_id: &str| {
+            json!({
+                "Id": id,
+                "Type": "Series",
+                "Name": id,
+                "IsFolder": true,
+                "UserData": { "Key": tvdb_id, "Played": false }
+            })
+        };
+        let episode = |id: &str, series_id: &str| {
+            json!({
+                "Id": id,
+                "Type": "Episode",
+                "Name": id,
+                "IsFolder": false,
+                "IndexNumber": 1,
+                "ParentIndexNumber": 1,
+                "SeriesName": series_id,
+                "SeriesId": series_id,
+                "UserData": { "Key": id, "Played": true }
+            })
+        };
+        // the boxset has an id of its own, and lists the same series as the library
+        let boxset = json!({
+            "Id": "monogatari",
+            "Type": "BoxSet",
+            "Name": "Monogatari",
+            "IsFolder": true,
+            "UserData": { "Key": "1000", "Played": false }
+        });
+        let tree: HashMap<&str, Vec<serde_json::Value>> = HashMap::from([
+            (
+                "",
+                vec![
+                    boxset,
+                    series("bakemonogatari", "102261"),
+                    series("nisemonogatari", "252475"),
+                ],
+            ),
+            (
+                "monogatari",
+                vec![
+                    series("bakemonogatari", "102261"),
+                    series("nisemonogatari", "252475"),
+                ],
+            ),
+            ("bakemonogatari", vec![episode("b1", "bakemonogatari")]),
+            ("nisemonogatari", vec![episode("n1", "nisemonogatari")]),
+        ]);
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(move |request: &wiremock::Request| {
+                let parent_id = request
+                    .url
+                    .query_pairs()
+                    .find(|(key, _)| key == "parentId")
+                    .map(|(_, value)| value.to_string())
+                    .unwrap_or_default();
+                let items = tree.get(parent_id.as_str()).cloned().unwrap_or_default();
+                ResponseTemplate::new(200).set_body_json(json!({ "Items": items }))
+            })
+            .mount(&server)
+            .await;
+
+        // skipping duplicates would drop both series if they were listed twice
+        let mut episodes: Vec<(String, i32)> = JellyfinApi::new(&server.uri(), "token")
+            .with_duplicate_series(DuplicateSeries::Skip)
+            .get_episodes("123")
+            .await?
+            .into_iter()
+            .map(|episode| (episode.id, episode.tvdb_id))
+            .collect();
+        episodes.sort();
+        assert_eq!(
+            episodes,
+            vec![("b1".to_string(), 102261), ("n1".to_string(), 252475)]
+        );
         Ok(())
     }
 }
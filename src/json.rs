@@ -0,0 +1,132 @@
+use std::collections::BTreeSet;
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::Response;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+// how much of an unparsable body makes it into the error
+const SNIPPET_CHARS: usize = 200;
+
+/// Reads a response's body as json. Parse errors name the url and quote the start of the body,
+/// since an html error page otherwise only shows up as "expected value at line 1 column 1".
+pub async fn parse_response<T: DeserializeOwned>(response: Response) -> Result<T> {
+    let url = response.url().clone();
+    let text = response.text().await?;
+    parse_body(&text, url.as_str())
+}
+
+pub fn parse_body<T: DeserializeOwned>(text: &str, url: &str) -> Result<T> {
+    serde_json::from_str(text).with_context(|| {
+        format!(
+            "unable to parse the response from {}: {}",
+            url,
+            snippet(text)
+        )
+    })
+}
+
+/// Like `parse_response`, but fails on fields the type doesn't know about, to catch changes to
+/// an api's responses.
+pub async fn parse_response_strict<T: DeserializeOwned + Serialize>(
+    response: Response,
+) -> Result<T> {
+    let url = response.url().clone();
+    let text = response.text().await?;
+    let raw: Value = parse_body(&text, url.as_str())?;
+    let parsed: T = parse_body(&text, url.as_str())?;
+    let unknown = unknown_fields(&raw, &serde_json::to_value(&parsed)?);
+    if !unknown.is_empty() {
+        return Err(anyhow!(
+            "unexpected fields in the response from {}: {}",
+            url,
+            unknown.into_iter().collect::<Vec<_>>().join(", ")
+        ));
+    }
+    Ok(parsed)
+}
+
+// the paths of the fields in `raw` that didn't survive parsing, found by comparing it with the
+// parsed value serialized again. array indices are left out, so each field is listed once
+fn unknown_fields(raw: &Value, parsed: &Value) -> BTreeSet<String> {
+    let mut unknown = BTreeSet::new();
+    collect_unknown_fields("", raw, parsed, &mut unknown);
+    unknown
+}
+
+fn collect_unknown_fields(path: &str, raw: &Value, parsed: &Value, unknown: &mut BTreeSet<String>) {
+    match (raw, parsed) {
+        (Value::Object(raw), Value::Object(parsed)) => {
+            for (key, raw_value) in raw {
+                let field_path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                match parsed.get(key) {
+                    Some(parsed_value) => {
+                        collect_unknown_fields(&field_path, raw_value, parsed_value, unknown)
+                    }
+                    None => {
+                        unknown.insert(field_path);
+                    }
+                }
+            }
+        }
+        (Value::Array(raw), Value::Array(parsed)) => {
+            for (raw_value, parsed_value) in raw.iter().zip(parsed) {
+                collect_unknown_fields(&format!("{}[]", path), raw_value, parsed_value, unknown);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn snippet(text: &str) -> String {
+    let text = text.trim();
+    match text.char_indices().nth(SNIPPET_CHARS) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_body_html() {
+        let html = format!(
+            "<html><body>502 Bad Gateway{}</body></html>",
+            "!".repeat(300)
+        );
+        let error = parse_body::<serde_json::Value>(&html, "http://jellyfin/Items").unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(message.starts_with(
+            "unable to parse the response from http://jellyfin/Items: <html><body>502 Bad Gateway!"
+        ));
+        assert!(message.contains("!!!...: expected value at line 1 column 1"));
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        #[derive(Serialize)]
+        struct Parsed {
+            id: i32,
+        }
+        let raw = serde_json::json!({ "items": [{ "id": 1, "extra": true }, { "id": 2 }], "n": 2 });
+        let parsed = serde_json::json!({ "items": [Parsed { id: 1 }, Parsed { id: 2 }] });
+        assert_eq!(
+            unknown_fields(&raw, &parsed),
+            BTreeSet::from(["items[].extra".to_string(), "n".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_snippet() {
+        assert_eq!(snippet(" short \n"), "short");
+        // cut on a char boundary
+        assert_eq!(snippet(&"é".repeat(250)), format!("{}...", "é".repeat(200)));
+    }
+}
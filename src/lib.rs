@@ -0,0 +1,62 @@
+use serde::Serialize;
+
+use mal::ListStatus;
+
+pub mod backoff;
+pub mod cache;
+pub mod config;
+pub mod jellyfin;
+pub mod json;
+pub mod logging;
+pub mod mal;
+pub mod manga;
+pub mod mapping;
+pub mod notify;
+pub mod oauth;
+pub mod overrides;
+pub mod rate_limit;
+pub mod report;
+pub mod search;
+pub mod state;
+pub mod sync;
+
+/// The outcome of syncing every series, with enough detail to render any kind of report.
+#[derive(Serialize, Default)]
+pub struct SyncReport {
+    pub updated: Vec<SeriesChange>,
+    pub skipped: Vec<SkippedSeries>,
+    pub failed: Vec<FailedSeries>,
+    // set when a shutdown stopped the sync before every series was synced
+    pub interrupted: bool,
+}
+
+/// A series whose progress was pushed to mal.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SeriesChange {
+    pub series_name: String,
+    pub tvdb_id: i32,
+    pub season: i32,
+    pub mal_id: i32,
+    pub old_episode: i32,
+    pub new_episode: i32,
+    pub status: ListStatus,
+}
+
+/// A series that didn't need to be updated.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SkippedSeries {
+    pub series_name: String,
+    pub tvdb_id: i32,
+    pub season: i32,
+    pub mal_id: Option<i32>,
+    pub reason: String,
+}
+
+/// A series that couldn't be synced.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FailedSeries {
+    pub series_name: String,
+    pub tvdb_id: i32,
+    pub season: i32,
+    pub error: String,
+}
@@ -0,0 +1,163 @@
+use std::io::Write;
+
+use chrono::Utc;
+use log::kv::{Error, Key, Value, VisitSource};
+use log::{log_enabled, trace, Level, Record};
+use reqwest::Response;
+use serde_json::{json, Map};
+
+// fields whose values must never be written to the logs
+const SECRET_FIELDS: [&str; 3] = ["token", "secret", "password"];
+
+/// Initializes the logger, either with the human readable format or as one json object per line.
+/// When an account label is given, every line is tagged with it.
+pub fn init(json_logs: bool, account_label: Option<String>) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if json_logs {
+        builder.format(move |buf, record| {
+            writeln!(buf, "{}", format_json(record, account_label.as_deref()))
+        });
+    } else if let Some(account_label) = account_label {
+        builder.format(move |buf, record| {
+            writeln!(buf, "{}", format_labelled(record, &account_label))
+        });
+    }
+    builder.init();
+}
+
+// env_logger's default format, with the account label in front of the message
+fn format_labelled(record: &Record, account_label: &str) -> String {
+    format!(
+        "[{:<5} {}] [{}] {}",
+        record.level(),
+        record.target(),
+        account_label,
+        record.args()
+    )
+}
+
+struct JsonFields(Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let key = key.as_str();
+        let value = if SECRET_FIELDS.iter().any(|secret| key.contains(secret)) {
+            json!("[redacted]")
+        } else if let Some(number) = value.to_i64() {
+            json!(number)
+        } else if let Some(boolean) = value.to_bool() {
+            json!(boolean)
+        } else {
+            json!(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+fn format_json(record: &Record, account_label: Option<&str>) -> serde_json::Value {
+    let mut fields = Map::new();
+    fields.insert("timestamp".to_string(), json!(Utc::now().to_rfc3339()));
+    fields.insert("level".to_string(), json!(record.level().as_str()));
+    fields.insert("msg".to_string(), json!(record.args().to_string()));
+    if let Some(account_label) = account_label {
+        fields.insert("account".to_string(), json!(account_label));
+    }
+    let mut fields = JsonFields(fields);
+    // a visitor that never fails can't make this error
+    let _ = record.key_values().visit(&mut fields);
+    serde_json::Value::Object(fields.0)
+}
+
+/// Logs an outgoing request at trace level. Only the names of the params are logged, and the
+/// auth only by the header it's sent in.
+pub fn trace_request(method: &str, url: &str, param_keys: &[&str], auth: &str) {
+    if log_enabled!(Level::Trace) {
+        trace!("{}", describe_request(method, url, param_keys, auth));
+    }
+}
+
+/// Logs the status and length of a response at trace level.
+pub fn trace_response(response: &Response) {
+    if log_enabled!(Level::Trace) {
+        let length = match response.content_length() {
+            Some(length) => format!("{} bytes", length),
+            None => "unknown length".to_string(),
+        };
+        trace!(
+            "{} {} ({})",
+            response.status().as_u16(),
+            response.url(),
+            length
+        );
+    }
+}
+
+fn describe_request(method: &str, url: &str, param_keys: &[&str], auth: &str) -> String {
+    let mut param_keys = param_keys.to_vec();
+    // sorted, since the params come from hash maps
+    param_keys.sort_unstable();
+    format!(
+        "{} {} params=[{}] auth={} [redacted]",
+        method,
+        url,
+        param_keys.join(", "),
+        auth
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_json() {
+        let fields: [(&str, Value); 3] = [
+            ("series_name", Value::from("Clannad")),
+            ("mal_id", Value::from(4181)),
+            ("access_token", Value::from("super-secret")),
+        ];
+        let line = format_json(
+            &Record::builder()
+                .level(Level::Info)
+                .args(format_args!("setting latest episode"))
+                .key_values(&fields)
+                .build(),
+            None,
+        );
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["msg"], "setting latest episode");
+        assert_eq!(line["series_name"], "Clannad");
+        assert_eq!(line["mal_id"], 4181);
+        assert_eq!(line["access_token"], "[redacted]");
+        assert!(!line.to_string().contains("super-secret"));
+        assert!(line.get("account").is_none());
+    }
+
+    #[test]
+    fn test_account_label() {
+        let record = Record::builder()
+            .level(Level::Info)
+            .target("jellymal_rs::sync")
+            .args(format_args!("setting latest episode"))
+            .build();
+        assert_eq!(
+            format_labelled(&record, "alyosha"),
+            "[INFO  jellymal_rs::sync] [alyosha] setting latest episode"
+        );
+        assert_eq!(format_json(&record, Some("alyosha"))["account"], "alyosha");
+    }
+
+    #[test]
+    fn test_describe_request() {
+        assert_eq!(
+            describe_request(
+                "GET",
+                "http://jellyfin/Items",
+                &["userId", "Fields"],
+                "X-Emby-Token"
+            ),
+            "GET http://jellyfin/Items params=[Fields, userId] auth=X-Emby-Token [redacted]"
+        );
+    }
+}
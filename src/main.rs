@@ -1,75 +1,815 @@
-use log::{debug, info};
-use mal::MyAnimeListApi;
-use mapping::tvdb_id_to_mal_id;
+use jellymal_rs::mal::{MyAnimeListApi, PlannedRequest};
+use jellymal_rs::manga::{self, MyMangaListApi};
+use jellymal_rs::mapping::{self, MappingStats, MappingTable};
+use jellymal_rs::overrides::Overrides;
+use jellymal_rs::state::{self, Checkpoint, Completions};
+use jellymal_rs::sync::SyncOptions;
+use jellymal_rs::{logging, notify, oauth, report, sync, SyncReport};
+use log::{debug, info, warn};
 
 use anyhow::anyhow;
-use jellyfin::JellyfinApi;
-use std::env;
-
-mod jellyfin;
-mod mal;
-mod mapping;
-mod oauth;
+use chrono::Utc;
+use clap::Parser;
+use jellymal_rs::backoff::REQUEST_RETRY_BACKOFF;
+use jellymal_rs::cache::Cache;
+use jellymal_rs::config::{require, require_secret, Config, MissingSetting};
+use jellymal_rs::jellyfin::{self, IdSource, JellyfinApi, DEFAULT_COLLECTION_TYPES};
+use jellymal_rs::oauth::TokenOptions;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
 
 const MAL_AUTH_URL: &str = "https://myanimelist.net/v1/oauth2/authorize";
 const MAL_TOKEN_URL: &str = "https://myanimelist.net/v1/oauth2/token";
 const MAL_TOKEN_PATH: &str = "/data/token.json";
+const ANIDB_MAPPING_PATH: &str = "anime-list-master.xml";
+const MAL_MAPPING_PATH: &str = "anime-list-full.json";
+// returned with --exit-code-on-nochange when a sync didn't update anything
+const NO_CHANGES_EXIT_CODE: u8 = 3;
+// returned for a missing setting, like clap does for invalid arguments
+const CONFIG_EXIT_CODE: u8 = 2;
+// how long the series being synced gets to finish after a shutdown signal
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
-
-    let jellyfin_host = &env::var("JELLYFIN_HOST")?;
-    let jellyfin_token = &env::var("JELLYFIN_TOKEN")?;
-    let jellyfin_user = &env::var("JELLYFIN_USER")?;
-
-    // initialize the api
-    debug!("initializing the jellyfin api");
-    let jellyfin_api = JellyfinApi::new(jellyfin_host, jellyfin_token);
+// the token is kept in the secrets file when there is one
+fn mal_token_path(config: &Config) -> &str {
+    config.mal_secrets_file.as_deref().unwrap_or(MAL_TOKEN_PATH)
+}
 
-    // get the latest episode the user has watched for all series
-    debug!("getting the user id");
-    let user_id = jellyfin_api
-        .get_user_id(jellyfin_user)
-        .await?
-        .ok_or(anyhow!("user does not exist"))?;
-    let latest_episodes = jellyfin_api.get_latest_episodes(&user_id).await?;
+fn has_mal_token(config: &Config) -> bool {
+    match &config.mal_secrets_file {
+        Some(path) => oauth::SecretsFile::load(path).is_ok_and(|secrets| secrets.token.is_some()),
+        None => Path::new(MAL_TOKEN_PATH).exists(),
+    }
+}
 
+async fn init_mal_api(config: &Config, client: &reqwest::Client) -> anyhow::Result<MyAnimeListApi> {
     // load or refresh the token
     debug!("getting an access token to communicate with the mal api");
+    let credentials = config.mal_credentials()?;
     let mal_token = oauth::load_or_refresh_token(
-        &env::var("MAL_CLIENT_ID")?,
-        &env::var("MAL_CLIENT_SECRET")?,
+        &credentials.client_id,
+        &credentials.client_secret,
         MAL_AUTH_URL,
         MAL_TOKEN_URL,
-        &env::var("MAL_API_REDIRECT_URL")?,
-        MAL_TOKEN_PATH,
+        &credentials.redirect_url,
+        mal_token_path(config),
+        &TokenOptions {
+            reauth: config.reauth,
+            always_refresh_after: config.always_refresh_after_secs.map(Duration::from_secs),
+            retry_backoff: config.retry_backoff(oauth::TOKEN_RETRY_BACKOFF),
+            in_secrets_file: credentials.secrets_file.is_some(),
+        },
     )
     .await?;
 
-    // initialize the mal api
-    let mal_api: MyAnimeListApi = MyAnimeListApi::new(mal_token);
-
-    // for each series, find the mal id. if the user's latest watched on
-    // jellyfin is greater than the latest watch on MAL, update the user's
-    for (tvdb_id, episode) in latest_episodes {
-        let mal_id = tvdb_id_to_mal_id(
-            tvdb_id,
-            episode.season_number,
-            "anime-list-master.xml",
-            "anime-list-full.json",
-        )?;
-        let mal_latest_episode_number = mal_api.get_latest_episode_number(mal_id).await?;
-        if episode.number > mal_latest_episode_number {
-            info!(
-                "setting latest episode of series {} (mal-id: {}) to {}",
-                episode.series_name, mal_id, episode.number
+    // initialize the mal api, making sure the token works before doing anything with it
+    let mal_api = MyAnimeListApi::new(mal_token)
+        .with_client(client.clone())
+        .with_endpoint(&config.mal_endpoint)
+        .with_read_fallback(config.mal_read_fallback_endpoint.as_deref())
+        .with_rate_limit(config.mal_requests_per_second)
+        .with_retry_backoff(config.retry_backoff(REQUEST_RETRY_BACKOFF))
+        .with_write_delay(Duration::from_millis(config.write_delay_ms))
+        .with_completed_ratio(config.completed_ratio)
+        .with_read_before_write(config.mal_read_before_write)
+        .with_anime_list_ttl(Some(Duration::from_secs(config.mal_list_ttl_secs)))
+        .with_annotations(config.mal_comments.clone(), config.mal_tags.clone());
+    let user_info = mal_api.get_user_info().await?;
+    debug!("authenticated with mal as {}", user_info.name);
+    Ok(mal_api)
+}
+
+// the libraries walked in jellyfin, or none to walk them all
+fn collection_types(config: &Config) -> Option<Vec<String>> {
+    if config.all_collections {
+        return None;
+    }
+    let mut collection_types: Vec<String> = DEFAULT_COLLECTION_TYPES
+        .iter()
+        .map(|collection_type| collection_type.to_string())
+        .collect();
+    if config.sync_manga {
+        collection_types.push("books".to_string());
+    }
+    Some(collection_types)
+}
+
+fn new_jellyfin_api(config: &Config, client: &reqwest::Client) -> anyhow::Result<JellyfinApi> {
+    Ok(JellyfinApi::new(
+        require(&config.jellyfin_host, "JELLYFIN_HOST")?,
+        &require_secret(
+            &config.jellyfin_token,
+            &config.jellyfin_token_file,
+            "JELLYFIN_TOKEN",
+        )?,
+    )
+    .with_client(client.clone())
+    .with_auth_scheme(config.jellyfin_auth_scheme)
+    .with_concurrency(if config.sequential {
+        1
+    } else {
+        config.jellyfin_concurrency
+    })
+    .with_watched_percent_threshold(config.watched_percent_threshold)
+    .with_ignored_seasons(config.ignored_seasons.clone())
+    .with_include_episode_zero(config.include_episode_zero)
+    .with_id_source(config.jellyfin_id_source)
+    .with_watched_source(config.jellyfin_watched_source)
+    .with_retry_backoff(config.retry_backoff(REQUEST_RETRY_BACKOFF))
+    .with_duplicate_series(config.duplicate_series)
+    .with_index_number_end(!config.ignore_index_number_end)
+    .with_strict_parse(config.strict_parse)
+    .with_missing_season_number(if config.skip_missing_season {
+        None
+    } else {
+        Some(config.missing_season_number)
+    })
+    .with_collection_types(collection_types(config))
+    .with_headers(config.jellyfin_headers.iter().cloned().collect()))
+}
+
+/// Initializes the jellyfin api, and resolves the configured users' ids.
+async fn init_jellyfin_api(
+    config: &Config,
+    client: &reqwest::Client,
+) -> anyhow::Result<(JellyfinApi, Vec<String>)> {
+    // initialize the api
+    debug!("initializing the jellyfin api");
+    let jellyfin_api = new_jellyfin_api(config, client)?;
+
+    debug!("getting the user ids");
+    let user_ids = jellyfin_api
+        .resolve_user_ids(&config.jellyfin_user, config.jellyfin_user_id.as_deref())
+        .await?;
+    Ok((jellyfin_api, user_ids))
+}
+
+/// Checks that both jellyfin and mal can be reached, printing the result for each. Returns
+/// whether both checks passed.
+async fn check(config: &Config, client: &reqwest::Client) -> bool {
+    let jellyfin_ok = match new_jellyfin_api(config, client) {
+        Ok(jellyfin_api) => match jellyfin_api.get_public_system_info().await {
+            Ok(info) => {
+                println!(
+                    "jellyfin: ok ({} running version {})",
+                    info.server_name, info.version
+                );
+                true
+            }
+            Err(e) => {
+                println!(
+                    "jellyfin: unreachable, check JELLYFIN_HOST and that the server is up ({})",
+                    e
+                );
+                false
+            }
+        },
+        Err(e) => {
+            println!("jellyfin: {}", e);
+            false
+        }
+    };
+
+    // never start the interactive authorization from a check
+    let mal_ok = if !has_mal_token(config) {
+        println!(
+            "mal: no token at {}, run with --init-auth",
+            mal_token_path(config)
+        );
+        false
+    } else {
+        match init_mal_api(config, client).await {
+            Ok(_) => {
+                println!("mal: ok");
+                true
+            }
+            Err(e) => {
+                println!("mal: {}", e);
+                false
+            }
+        }
+    };
+
+    jellyfin_ok && mal_ok
+}
+
+fn load_mapping_table(config: &Config, id_source: IdSource) -> anyhow::Result<MappingTable> {
+    debug!("loading the anime mappings");
+    if config.mapping_max_age_days > 0 {
+        let max_age = Duration::from_secs(config.mapping_max_age_days * 24 * 60 * 60);
+        mapping::warn_stale_mappings(ANIDB_MAPPING_PATH, MAL_MAPPING_PATH, max_age);
+    }
+    let cache = if config.no_cache {
+        Cache::disabled()
+    } else {
+        Cache::new(config.cache_dir.as_deref())
+    };
+    let consolidated_mapping_path = match (&config.consolidated_mapping_path, config.no_cache) {
+        (_, true) => None,
+        (Some(path), false) => Some(path.clone()),
+        (None, false) => cache.path("consolidated-mapping.json")?,
+    };
+    Ok(MappingTable::load(
+        ANIDB_MAPPING_PATH,
+        MAL_MAPPING_PATH,
+        consolidated_mapping_path.as_deref(),
+        id_source,
+    )?
+    .with_prefer_absolute(config.prefer_absolute_when_present))
+}
+
+// prints a line of the checklist, returning whether the check passed
+fn print_check(name: &str, result: anyhow::Result<String>) -> bool {
+    match result {
+        Ok(detail) => {
+            println!("[ok]   {}: {}", name, detail);
+            true
+        }
+        Err(e) => {
+            println!("[fail] {}: {:#}", name, e);
+            false
+        }
+    }
+}
+
+/// Checks everything a sync depends on, printing a line for each check. Returns whether all of
+/// them passed.
+async fn config_check(config: &Config, client: &reqwest::Client) -> bool {
+    let missing = config.missing_settings();
+    let settings = if missing.is_empty() {
+        Ok("all set".to_string())
+    } else {
+        Err(anyhow!("missing {}", missing.join(", ")))
+    };
+    let mut passed = print_check("settings", settings);
+
+    let jellyfin = async {
+        let info = new_jellyfin_api(config, client)?
+            .get_public_system_info()
+            .await?;
+        Ok(format!(
+            "{} running version {}",
+            info.server_name, info.version
+        ))
+    };
+    passed &= print_check("jellyfin", jellyfin.await);
+    let jellyfin_user = async {
+        let (_, user_ids) = init_jellyfin_api(config, client).await?;
+        Ok(format!("user id {}", user_ids.join(", ")))
+    };
+    passed &= print_check("jellyfin user", jellyfin_user.await);
+
+    // never start the interactive authorization from a check
+    let mal = async {
+        if !has_mal_token(config) {
+            return Err(anyhow!(
+                "no token at {}, run with --init-auth",
+                mal_token_path(config)
+            ));
+        }
+        let user_info = init_mal_api(config, client).await?.get_user_info().await?;
+        Ok(format!("authorized as {}", user_info.name))
+    };
+    passed &= print_check("mal token", mal.await);
+
+    let mappings =
+        load_mapping_table(config, config.jellyfin_id_source).map(|_| "loaded".to_string());
+    passed &= print_check("mappings", mappings);
+    let known_mappings = load_mapping_table(config, IdSource::Tvdb).and_then(|mapping_table| {
+        let failed: Vec<&str> = mapping_table
+            .check_known_mappings()
+            .into_iter()
+            .filter(|(_, result)| result.is_err())
+            .map(|(known, _)| known.name)
+            .collect();
+        if failed.is_empty() {
+            Ok(format!(
+                "{} known series resolved",
+                mapping::KNOWN_MAPPINGS.len()
+            ))
+        } else {
+            Err(anyhow!("unable to resolve {}", failed.join(", ")))
+        }
+    });
+    passed &= print_check("mapping self-test", known_mappings);
+    passed
+}
+
+/// Resolves the known mappings, printing the result for each. Returns whether all of them passed.
+fn test_mapping(config: &Config) -> anyhow::Result<bool> {
+    let mapping_table = load_mapping_table(config, IdSource::Tvdb)?;
+    let mut passed = true;
+    for (known, result) in mapping_table.check_known_mappings() {
+        match result {
+            Ok(()) => println!("{}: ok", known.name),
+            Err(e) => {
+                println!("{}: {}", known.name, e);
+                passed = false;
+            }
+        }
+    }
+    if !passed {
+        println!(
+            "the mapping files may be outdated or corrupt, download {} and {} again",
+            ANIDB_MAPPING_PATH, MAL_MAPPING_PATH
+        );
+    }
+    Ok(passed)
+}
+
+/// Prints how many of the seasons in jellyfin could be mapped, and which ones couldn't.
+fn print_mapping_stats(stats: &MappingStats) {
+    let percentage = |count: usize| 100.0 * count as f64 / stats.total().max(1) as f64;
+    for (label, count) in [
+        ("mapped", stats.mapped),
+        ("unmapped at tvdb", stats.unmapped_tvdb.len()),
+        ("unmapped at anidb", stats.unmapped_anidb.len()),
+    ] {
+        println!("{:<18} {:>6} {:>6.1}%", label, count, percentage(count));
+    }
+    for (stage, unmapped) in [
+        ("tvdb", &stats.unmapped_tvdb),
+        ("anidb", &stats.unmapped_anidb),
+    ] {
+        for (series_name, season) in unmapped {
+            println!(
+                "  {} season {} (unmapped at {})",
+                series_name, season, stage
             );
-            mal_api
-                .set_latest_episode_number(mal_id, episode.number)
-                .await?;
         }
     }
+}
 
+/// Removes the entries on the mal list that aren't in the library, or only lists them unless
+/// `confirm` is set.
+async fn prune_missing(
+    mal_api: &MyAnimeListApi,
+    library_mal_ids: &HashSet<i32>,
+    confirm: bool,
+) -> anyhow::Result<()> {
+    // an empty library is much more likely a jellyfin problem than a wish to clear the list
+    if library_mal_ids.is_empty() {
+        warn!("not pruning the mal list, none of the jellyfin series could be mapped");
+        return Ok(());
+    }
+    let anime_list = mal_api.get_anime_list().await?;
+    for datum in sync::find_missing(&anime_list, library_mal_ids) {
+        if confirm {
+            println!("removing {} (mal-id: {})", datum.node.title, datum.node.id);
+            mal_api.remove_from_list(datum.node.id).await?;
+        } else {
+            println!(
+                "would remove {} (mal-id: {}), pass --confirm-prune to remove it",
+                datum.node.title, datum.node.id
+            );
+        }
+    }
     Ok(())
 }
+
+/// The verify report, along with the requests the sync would send.
+#[derive(Serialize)]
+struct DryRunReport<'a> {
+    #[serde(flatten)]
+    report: &'a SyncReport,
+    requests: Vec<PlannedRequest>,
+}
+
+/// Prints the outcome of the sync as the last line on stdout. Logs go to stderr, so this is
+/// printed regardless of the log level and is easy to grep for.
+fn print_summary(updated: usize, report: &SyncReport) {
+    let mut summary = format!(
+        "SYNC_DONE updated={} skipped={} failed={}",
+        updated,
+        report.skipped.len(),
+        report.failed.len()
+    );
+    if report.interrupted {
+        summary.push_str(" interrupted=true");
+    }
+    println!("{}", summary);
+}
+
+/// Cancels the returned token on SIGINT or SIGTERM, and exits if the sync hasn't wrapped up within
+/// the shutdown timeout.
+fn handle_shutdown_signals() -> anyhow::Result<CancellationToken> {
+    let shutdown = CancellationToken::new();
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let cancel = shutdown.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = interrupt.recv() => {},
+        }
+        info!("shutting down once the series being synced is done");
+        cancel.cancel();
+        tokio::time::sleep(SHUTDOWN_TIMEOUT).await;
+        warn!("timed out waiting for the sync to finish");
+        std::process::exit(1);
+    });
+    Ok(shutdown)
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let config = Config::parse();
+    match run(config).await {
+        Err(e) if e.is::<MissingSetting>() => {
+            eprintln!("Error: {:?}", e);
+            Ok(ExitCode::from(CONFIG_EXIT_CODE))
+        }
+        result => Ok(result?),
+    }
+}
+
+async fn run(config: Config) -> anyhow::Result<ExitCode> {
+    // the max runtime counts from the start, fetching from jellyfin and mal included
+    let deadline = config
+        .max_runtime
+        .map(|max_runtime| std::time::Instant::now() + max_runtime);
+    logging::init(config.json_logs, config.account_label.clone());
+    // every api shares one connection pool. the list and item payloads compress well
+    let client = reqwest::Client::builder()
+        .gzip(true)
+        .deflate(true)
+        .build()?;
+
+    // the second half of an authorization started on a machine without a terminal
+    if let Some(received_redirect_url) = &config.complete_auth {
+        let credentials = config.mal_credentials()?;
+        oauth::complete_auth(
+            &credentials.client_id,
+            &credentials.client_secret,
+            MAL_AUTH_URL,
+            MAL_TOKEN_URL,
+            &credentials.redirect_url,
+            mal_token_path(&config),
+            credentials.secrets_file.is_some(),
+            received_redirect_url,
+            &config.retry_backoff(oauth::TOKEN_RETRY_BACKOFF),
+        )
+        .await?;
+        println!("Token saved to {}", mal_token_path(&config));
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // only set up the token, so that scheduled runs can rely on it existing
+    if config.init_auth {
+        init_mal_api(&config, &client).await?;
+        println!("Token saved to {}", mal_token_path(&config));
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // the cleanup only looks at the user's mal list, so no jellyfin data is needed
+    if config.cleanup_completed {
+        let mal_api = init_mal_api(&config, &client).await?;
+        let fixed = mal_api.cleanup_completed().await?;
+        info!("marked {} series as completed", fixed);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // a single write straight to mal, kept apart from the sync
+    if let Some(simulated) = &config.simulate_watch {
+        let (mal_id, episodes) = (simulated[0], simulated[1]);
+        let mal_api = init_mal_api(&config, &client).await?;
+        return Ok(
+            match mal_api
+                .write_progress(mal_id, episodes, config.simulate_status)
+                .await?
+            {
+                Some(status) => {
+                    info!(
+                        "mal id {}: set to {} episodes ({}), mal answered {}",
+                        mal_id,
+                        episodes,
+                        config.simulate_status.as_str(),
+                        status
+                    );
+                    if status.is_success() {
+                        ExitCode::SUCCESS
+                    } else {
+                        ExitCode::FAILURE
+                    }
+                }
+                None => {
+                    info!("mal id {}: nothing to send", mal_id);
+                    ExitCode::SUCCESS
+                }
+            },
+        );
+    }
+
+    if config.check {
+        return Ok(if check(&config, &client).await {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
+
+    if config.config_check {
+        return Ok(if config_check(&config, &client).await {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
+
+    if config.test_mapping {
+        return Ok(if test_mapping(&config)? {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
+
+    // each hop of the mapping on its own, to tell which of the mapping files is at fault
+    if let Some(tvdb) = &config.anidb_only {
+        let (tvdb_id, season) = (tvdb[0], tvdb[1]);
+        return Ok(
+            match mapping::tvdb_id_to_anidb_id(ANIDB_MAPPING_PATH, tvdb_id, season) {
+                Ok(anidb_ids) => {
+                    for anidb_id in anidb_ids {
+                        println!(
+                            "tvdb id {} season {}: anidb id {}",
+                            tvdb_id, season, anidb_id
+                        );
+                    }
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    println!("tvdb id {} season {}: {}", tvdb_id, season, e);
+                    ExitCode::FAILURE
+                }
+            },
+        );
+    }
+    if let Some(anidb_id) = config.mal_only {
+        return Ok(
+            match mapping::anidb_id_to_mal_id(MAL_MAPPING_PATH, anidb_id) {
+                Ok(mal_id) => {
+                    println!("anidb id {}: mal id {}", anidb_id, mal_id);
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    println!("anidb id {}: {}", anidb_id, e);
+                    ExitCode::FAILURE
+                }
+            },
+        );
+    }
+
+    if let Some(export_path) = &config.export_mapping {
+        // seasons only exist in the tvdb mapping, whatever the configured id source
+        let mapping_table = load_mapping_table(&config, IdSource::Tvdb)?;
+        mapping_table.save(export_path)?;
+        println!("Mapping exported to {}", export_path);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if config.mapping_stats {
+        let (jellyfin_api, user_ids) = init_jellyfin_api(&config, &client).await?;
+        let mut episodes = jellyfin_api.get_episodes_of(&user_ids).await?;
+        // ignored seasons are never synced, so they don't need to be mapped either
+        episodes.retain(|episode| !config.ignored_seasons.contains(&episode.season_number));
+        let mapping_table = load_mapping_table(&config, config.jellyfin_id_source)?
+            .with_season_lengths(jellyfin::season_lengths(&episodes));
+        print_mapping_stats(&mapping_table.stats(&episodes));
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // show what was read from jellyfin, without touching mal at all
+    if config.dump_episodes {
+        let (jellyfin_api, user_ids) = init_jellyfin_api(&config, &client).await?;
+        let latest_episodes =
+            jellyfin_api.latest_episodes(jellyfin_api.get_episodes_of(&user_ids).await?);
+        // the mal ids are left out when the mappings can't be loaded
+        let mapping_table = load_mapping_table(&config, config.jellyfin_id_source)
+            .map_err(|error| warn!("not resolving mal ids: {:#}", error))
+            .ok();
+        let summaries = report::summarize_latest_episodes(&latest_episodes, mapping_table.as_ref());
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // show what was read from mal, the counterpart to --dump-episodes
+    if config.dump_mal_list {
+        let mal_api = init_mal_api(&config, &client).await?;
+        let anime_list = mal_api.get_anime_list().await?;
+        println!("{}", serde_json::to_string_pretty(anime_list.as_slice())?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // get the latest episode the user has watched for all series, while the mal list is fetched
+    let ((jellyfin_api, user_ids, started_at, episodes), mal_api) = sync::fetch_concurrently(
+        async {
+            let (jellyfin_api, user_ids) = init_jellyfin_api(&config, &client).await?;
+            // anything played from now on is left for the next run
+            let started_at = Utc::now();
+            let episodes = jellyfin_api.get_episodes_of(&user_ids).await?;
+            Ok((jellyfin_api, user_ids, started_at, episodes))
+        },
+        async {
+            let mal_api = init_mal_api(&config, &client).await?;
+            // cached, so the sync doesn't have to wait for it again
+            mal_api.get_anime_list().await?;
+            Ok(mal_api)
+        },
+    )
+    .await?;
+
+    let mapping_table = load_mapping_table(&config, config.jellyfin_id_source)?
+        .with_season_lengths(jellyfin::season_lengths(&episodes));
+    let overrides = match &config.overrides_path {
+        Some(overrides_path) => Overrides::load(overrides_path)?,
+        None => Overrides::default(),
+    };
+    // everything in the library keeps its entry, watched or not
+    let library_mal_ids = config
+        .prune_missing
+        .then(|| sync::library_mal_ids(&episodes, &mapping_table, &overrides));
+    let mut latest_episodes = jellyfin_api.latest_episodes(episodes);
+    if let Some(needle) = &config.series_name_contains {
+        latest_episodes.retain(|_, episode| episode.series_name_contains(needle));
+        info!(
+            "only syncing the {} seasons of series matching \"{}\"",
+            latest_episodes.len(),
+            needle
+        );
+    }
+    let last_run = match (&config.last_run_path, config.only_watched_since_last_run) {
+        (Some(last_run_path), true) if !config.full => state::load_last_run(last_run_path)?,
+        _ => None,
+    };
+    if let Some(last_run) = last_run {
+        let mut played_series: HashSet<String> = HashSet::new();
+        for user_id in &user_ids {
+            let played = jellyfin_api.get_recently_played(user_id, last_run).await?;
+            // a film is its own series
+            played_series.extend(played.into_iter().filter_map(
+                |item| match item.media_type.as_str() {
+                    "Movie" => Some(item.id),
+                    _ => item.series_id,
+                },
+            ));
+        }
+        latest_episodes.retain(|_, episode| played_series.contains(&episode.series_id));
+        info!(
+            "only syncing the {} seasons played since {}",
+            latest_episodes.len(),
+            last_run
+        );
+    }
+    let sync_options = SyncOptions {
+        min_episodes_watched: config.min_episodes_watched,
+        favorites_only: config.favorites_only,
+        series_timeout: (config.series_timeout_secs > 0)
+            .then(|| Duration::from_secs(config.series_timeout_secs)),
+        deadline,
+        allow_decrease: config.allow_decrease,
+        complete_after_runs: config.complete_after_runs,
+        completed_ratio: config.completed_ratio,
+        search_min_score: config.search_fallback.then_some(config.search_min_score),
+    };
+
+    // report how jellyfin and mal differ, without writing anything
+    if config.verify {
+        let report = sync::verify(
+            &mal_api,
+            latest_episodes,
+            &mapping_table,
+            &overrides,
+            &sync_options,
+        )
+        .await;
+        if !config.dry_run {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(ExitCode::SUCCESS);
+        }
+        // built just like the real requests, they're only left unsent
+        let mut requests = vec![];
+        for change in &report.updated {
+            if let Some(planned) = mal_api
+                .plan_progress(change.mal_id, change.new_episode, change.status)
+                .await
+            {
+                requests.push(planned);
+            }
+        }
+        let dry_run = DryRunReport {
+            report: &report,
+            requests,
+        };
+        println!("{}", serde_json::to_string_pretty(&dry_run)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let shutdown = handle_shutdown_signals()?;
+    if config.complete_after_runs > 1 && config.completion_state_path.is_none() {
+        warn!("COMPLETE_AFTER_RUNS needs COMPLETION_STATE_PATH to count runs, nothing will be completed");
+    }
+    let mut checkpoint = Checkpoint::load_from(
+        config
+            .input_state
+            .as_deref()
+            .or(config.state_path.as_deref()),
+        config
+            .output_state
+            .as_deref()
+            .or(config.state_path.as_deref()),
+    )?
+    .with_completions(Completions::load(config.completion_state_path.as_deref())?);
+
+    let report = sync::sync(
+        &mal_api,
+        latest_episodes,
+        &mapping_table,
+        &overrides,
+        &sync_options,
+        &mut checkpoint,
+        &shutdown,
+    )
+    .await;
+    // keep the checkpoint of an interrupted sync, so the next run can pick it back up
+    if report.interrupted {
+        checkpoint.save()?;
+        print_summary(report.updated.len(), &report);
+        return Ok(ExitCode::FAILURE);
+    }
+    checkpoint.clear()?;
+    let mut updated = report.updated.len();
+
+    // manga syncing is opt in, and only runs when a mapping has been provided
+    if let (true, Some(manga_mapping_path)) = (config.sync_manga, &config.manga_mapping_path) {
+        let manga_api = MyMangaListApi::new(&mal_api);
+        // sorted like the anime, so that the order is the same on every run
+        let mut latest_volumes: BTreeMap<String, i32> = BTreeMap::new();
+        for user_id in &user_ids {
+            for (series_name, volume_number) in jellyfin_api.get_latest_volumes(user_id).await? {
+                let latest = latest_volumes.entry(series_name).or_insert(volume_number);
+                *latest = (*latest).max(volume_number);
+            }
+        }
+        for (series_name, volume_number) in latest_volumes {
+            let Some(mal_id) =
+                manga::series_name_to_mal_manga_id(&series_name, manga_mapping_path)?
+            else {
+                debug!("no manga mapping for series {}", series_name);
+                continue;
+            };
+            let mal_latest_volume_number = manga_api.get_latest_volume_number(mal_id).await?;
+            if volume_number > mal_latest_volume_number {
+                info!(
+                    series_name = series_name.as_str(), mal_id = mal_id;
+                    "setting latest volume of manga {} (mal-id: {}) to {}",
+                    series_name, mal_id, volume_number
+                );
+                manga_api
+                    .set_latest_volume_number(mal_id, volume_number)
+                    .await?;
+                updated += 1;
+            }
+        }
+    }
+
+    info!(
+        updated = updated, skipped = report.skipped.len(), failed = report.failed.len();
+        "sync complete: {} updated, {} skipped, {} failed",
+        updated, report.skipped.len(), report.failed.len()
+    );
+    // a notification that can't be sent shouldn't fail a sync that worked
+    if let Some(webhook_url) = &config.notify_webhook {
+        if let Err(e) = notify::notify_webhook(&client, webhook_url, &report).await {
+            warn!("unable to notify the webhook: {}", e);
+        }
+    }
+    if let Some(library_mal_ids) = &library_mal_ids {
+        prune_missing(&mal_api, library_mal_ids, config.confirm_prune).await?;
+    }
+
+    if let Some(output_format) = config.output_format {
+        let rendered = report::render(&report, output_format)?;
+        match &config.output_path {
+            Some(output_path) => std::fs::write(output_path, rendered)?,
+            None => print!("{}", rendered),
+        }
+    }
+    print_summary(updated, &report);
+    // a filtered run leaves the other series' plays for the next full one
+    if let (Some(last_run_path), None) = (&config.last_run_path, &config.series_name_contains) {
+        state::save_last_run(last_run_path, started_at, &report)?;
+    }
+    if !report.failed.is_empty() {
+        return Ok(ExitCode::FAILURE);
+    }
+    if updated == 0 && config.exit_code_on_nochange {
+        return Ok(ExitCode::from(NO_CHANGES_EXIT_CODE));
+    }
+    Ok(ExitCode::SUCCESS)
+}
@@ -1,46 +1,208 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use futures::stream::{self, Stream, TryStreamExt};
+use log::{debug, info, warn};
 use reqwest::header::HeaderMap;
-use reqwest::Response;
+use reqwest::{Response, StatusCode};
 use serde::Deserialize;
 use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
 
+use crate::backoff::{Backoff, REQUEST_RETRY_BACKOFF};
+use crate::json::parse_response;
+use crate::logging;
 use crate::oauth::ClientToken;
+use crate::rate_limit::RateLimiter;
+use crate::search::Candidate;
 
-const MAL_ENDPOINT: &str = "https://api.myanimelist.net/v2";
+pub const MAL_ENDPOINT: &str = "https://api.myanimelist.net/v2";
+// number of entries requested per page of the list, the most mal allows
+const LIST_PAGE_SIZE: usize = 1000;
 
 #[derive(Serialize, Deserialize)]
 struct UserAnimeListResponse {
     data: Vec<UserAnimeListDatum>,
+    #[serde(default)]
+    paging: Paging,
 }
 
+#[derive(Serialize, Deserialize, Default)]
+struct Paging {
+    // the url of the next page, missing on the last one
+    next: Option<String>,
+}
+
+/// A series on the user's list.
 #[derive(Serialize, Deserialize)]
+pub struct UserAnimeListDatum {
+    pub node: UserAnimeListNode,
+    pub list_status: UserAnimeListStatus,
+}
 
-struct UserAnimeListDatum {
-    node: UserAnimeListNode,
-    list_status: UserAnimeListStatus,
+#[derive(Serialize, Deserialize)]
+pub struct UserAnimeListNode {
+    pub id: i32,
+    pub title: String,
+    // 0 when the total isn't known yet
+    #[serde(default)]
+    pub num_episodes: i32,
 }
 
 #[derive(Serialize, Deserialize)]
-struct UserAnimeListNode {
+pub struct UserAnimeListStatus {
+    pub num_episodes_watched: i32,
+    pub status: Option<ListStatus>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum ListStatus {
+    Watching,
+    Completed,
+    OnHold,
+    Dropped,
+    PlanToWatch,
+}
+
+impl ListStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListStatus::Watching => "watching",
+            ListStatus::Completed => "completed",
+            ListStatus::OnHold => "on_hold",
+            ListStatus::Dropped => "dropped",
+            ListStatus::PlanToWatch => "plan_to_watch",
+        }
+    }
+}
+
+/// The user's current entry for a series, as returned by the series' details.
+#[derive(Deserialize, Default)]
+struct MyListStatus {
+    status: Option<ListStatus>,
+    #[serde(default)]
+    num_episodes_watched: i32,
+    comments: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct MyListStatusResponse {
+    my_list_status: Option<MyListStatus>,
+}
+
+impl MyListStatus {
+    // the entry's values, named like the form fields that set them
+    fn form_values(&self) -> BTreeMap<&'static str, String> {
+        let mut values = BTreeMap::new();
+        values.insert(
+            "num_watched_episodes",
+            self.num_episodes_watched.to_string(),
+        );
+        if let Some(status) = self.status {
+            values.insert("status", status.as_str().to_string());
+        }
+        if let Some(comments) = &self.comments {
+            values.insert("comments", comments.clone());
+        }
+        values.insert("tags", self.tags.join(","));
+        values
+    }
+}
+
+/// A series' details. Only the fields asked for are sent, so the rest are left empty.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct AnimeDetails {
+    pub id: i32,
+    pub title: String,
+    // mal reports 0 when the total number of episodes isn't known yet
+    pub num_episodes: Option<i32>,
+    // whether it's still airing, e.g. "finished_airing" or "currently_airing"
+    pub status: Option<String>,
+    // e.g. "tv", "movie" or "ova"
+    pub media_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SearchResponse {
+    data: Vec<SearchDatum>,
+}
+
+#[derive(Deserialize)]
+struct SearchDatum {
+    node: SearchNode,
+}
+
+#[derive(Deserialize)]
+struct SearchNode {
     id: i32,
     title: String,
+    #[serde(default)]
+    alternative_titles: AlternativeTitles,
+}
+
+#[derive(Deserialize, Default)]
+struct AlternativeTitles {
+    #[serde(default)]
+    synonyms: Vec<String>,
+    // empty when there's no english title
+    #[serde(default)]
+    en: String,
 }
 
 #[derive(Serialize, Deserialize)]
-struct UserAnimeListStatus {
-    num_episodes_watched: i32,
+pub struct UserInfo {
+    pub id: i32,
+    pub name: String,
+}
+
+struct CachedAnimeList {
+    fetched_at: Instant,
+    data: Arc<Vec<UserAnimeListDatum>>,
 }
 
 pub struct MyAnimeListApi {
     pub client: reqwest::Client,
     pub token: ClientToken,
+    endpoint: String,
+    // where the list is read from when mal can't be, never written to
+    read_fallback_endpoint: Option<String>,
+    anime_list: Mutex<Option<CachedAnimeList>>,
+    // the cached list is fetched again once it's older than this
+    anime_list_ttl: Option<Duration>,
+    rate_limiter: Option<RateLimiter>,
+    // how failed requests are retried
+    retry_backoff: Backoff,
+    // spaces out writes on top of the rate limit, to be gentle on mal
+    write_limiter: Option<RateLimiter>,
+    // only sent when set, so that notes written on mal aren't overwritten
+    comments: Option<String>,
+    tags: Option<String>,
+    // the share of episodes that has to be watched for a series to count as completed
+    completed_ratio: f64,
+    // fetch the entry before writing it, only sending the fields that changed
+    read_before_write: bool,
 }
 
-enum RequestType {
+/// A write to the list, built but not yet sent.
+#[derive(Serialize)]
+pub struct PlannedRequest {
+    pub method: &'static str,
+    pub url: String,
+    #[serde(skip)]
+    route: String,
+    pub form_data: BTreeMap<&'static str, String>,
+}
+
+pub(crate) enum RequestType {
     Get,
     Patch,
+    Delete,
 }
 
 impl MyAnimeListApi {
@@ -48,21 +210,131 @@ impl MyAnimeListApi {
         MyAnimeListApi {
             client: reqwest::Client::new(),
             token,
+            endpoint: MAL_ENDPOINT.to_string(),
+            read_fallback_endpoint: None,
+            anime_list: Mutex::new(None),
+            anime_list_ttl: None,
+            rate_limiter: None,
+            retry_backoff: Backoff::new(REQUEST_RETRY_BACKOFF),
+            write_limiter: None,
+            comments: None,
+            tags: None,
+            completed_ratio: 1.0,
+            read_before_write: false,
         }
     }
 
-    async fn request(
+    /// Uses an existing client, so that its connection pool is shared with other apis.
+    pub fn with_client(mut self, client: reqwest::Client) -> MyAnimeListApi {
+        self.client = client;
+        self
+    }
+
+    /// Fetches the user's list again once the cached one is older than `ttl`, so that changes
+    /// made on mal in the meantime are picked up. Without a ttl the list is cached for good.
+    pub fn with_anime_list_ttl(mut self, ttl: Option<Duration>) -> MyAnimeListApi {
+        self.anime_list_ttl = ttl;
+        self
+    }
+
+    /// Points the api at a different base url, e.g. a mock server.
+    pub fn with_endpoint(mut self, endpoint: &str) -> MyAnimeListApi {
+        self.endpoint = endpoint.to_string();
+        self
+    }
+
+    /// Reads the user's list from `endpoint` when mal fails, e.g. a caching proxy serving the
+    /// same api. The token is sent along, so it has to be trusted. Writes always go to mal.
+    pub fn with_read_fallback(mut self, endpoint: Option<&str>) -> MyAnimeListApi {
+        self.read_fallback_endpoint = endpoint.map(|endpoint| endpoint.to_string());
+        self
+    }
+
+    /// Sets how requests that failed with a connection error, a 429 or a 5xx are retried.
+    pub fn with_retry_backoff(mut self, retry_backoff: Backoff) -> MyAnimeListApi {
+        self.retry_backoff = retry_backoff;
+        self
+    }
+
+    /// Limits the requests made through this api to a number per second. Values of 0 or less
+    /// disable the limit.
+    pub fn with_rate_limit(mut self, requests_per_second: f64) -> MyAnimeListApi {
+        self.rate_limiter =
+            (requests_per_second > 0.0).then(|| RateLimiter::new(requests_per_second));
+        self
+    }
+
+    /// Waits at least `write_delay` between writes, whatever the rate limit. A delay of zero
+    /// disables it.
+    pub fn with_write_delay(mut self, write_delay: Duration) -> MyAnimeListApi {
+        self.write_limiter =
+            (!write_delay.is_zero()).then(|| RateLimiter::with_interval(write_delay));
+        self
+    }
+
+    /// Sets the share of episodes that has to be watched for a series to count as completed, for
+    /// when the number of episodes on mal doesn't quite match the files. Clamped to 0 to 1.
+    pub fn with_completed_ratio(mut self, completed_ratio: f64) -> MyAnimeListApi {
+        self.completed_ratio = completed_ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Fetches each entry before it's written, so that only the fields that actually change are
+    /// sent, and nothing at all when none do.
+    pub fn with_read_before_write(mut self, read_before_write: bool) -> MyAnimeListApi {
+        self.read_before_write = read_before_write;
+        self
+    }
+
+    /// Sets the comments and comma separated tags saved on every entry that gets synced.
+    pub fn with_annotations(
+        mut self,
+        comments: Option<String>,
+        tags: Option<String>,
+    ) -> MyAnimeListApi {
+        self.comments = comments;
+        self.tags = tags;
+        self
+    }
+
+    pub(crate) async fn request(
+        &self,
+        request_type: RequestType,
+        route: &str,
+        params: Option<HashMap<&str, &str>>,
+        form_data: Option<HashMap<&str, String>>,
+    ) -> anyhow::Result<Response> {
+        self.request_to(&self.endpoint, request_type, route, params, form_data)
+            .await
+    }
+
+    async fn request_to(
         &self,
+        endpoint: &str,
         request_type: RequestType,
         route: &str,
         params: Option<HashMap<&str, &str>>,
         form_data: Option<HashMap<&str, String>>,
     ) -> anyhow::Result<Response> {
         let headers: HeaderMap = HeaderMap::new();
-        let url = format!("{}{}", MAL_ENDPOINT, route);
+        let url = format!("{}{}", endpoint, route);
+        let is_write = !matches!(request_type, RequestType::Get);
+        let method = match request_type {
+            RequestType::Get => "GET",
+            RequestType::Patch => "PATCH",
+            RequestType::Delete => "DELETE",
+        };
+        let param_keys: Vec<&str> = params
+            .iter()
+            .flat_map(|p| p.keys())
+            .chain(form_data.iter().flat_map(|f| f.keys()))
+            .copied()
+            .collect();
+        logging::trace_request(method, &url, &param_keys, "Bearer");
         let mut request_builder = match request_type {
             RequestType::Get => self.client.get(url),
             RequestType::Patch => self.client.patch(url),
+            RequestType::Delete => self.client.delete(url),
         };
         request_builder = request_builder.headers(headers);
         if let Some(p) = params {
@@ -72,24 +344,136 @@ impl MyAnimeListApi {
             request_builder = request_builder.form(&f);
         }
 
-        let response: Response = request_builder
-            .bearer_auth(&self.token.access_token)
-            .send()
+        if let (true, Some(write_limiter)) = (is_write, &self.write_limiter) {
+            write_limiter.acquire().await;
+        }
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+        let response: Response = self
+            .retry_backoff
+            .send(request_builder.bearer_auth(&self.token.access_token))
             .await?;
+        logging::trace_response(&response);
 
         Ok(response)
     }
 
-    pub async fn get_latest_episode_number(&self, series_id: i32) -> Result<i32> {
+    /// Gets the user the token belongs to, failing with an actionable message when the token
+    /// isn't usable.
+    pub async fn get_user_info(&self) -> Result<UserInfo> {
+        let response = self
+            .request(RequestType::Get, "/users/@me", None, None)
+            .await?;
+        match response.status() {
+            StatusCode::UNAUTHORIZED => {
+                return Err(anyhow!(
+                    "the mal token is invalid or expired, run with --init-auth to authorize again"
+                ))
+            }
+            StatusCode::FORBIDDEN => {
+                return Err(anyhow!(
+                "the mal token lacks the read/write scope, run with --init-auth to authorize again"
+            ))
+            }
+            _ => {}
+        }
+        parse_response(response.error_for_status()?).await
+    }
+
+    /// Gets the user's list. It's fetched once and reused until the ttl runs out.
+    pub async fn get_anime_list(&self) -> Result<Arc<Vec<UserAnimeListDatum>>> {
+        // holding the lock while fetching makes concurrent callers share a single fetch
+        let mut anime_list = self.anime_list.lock().await;
+        if let Some(cached) = anime_list.as_ref() {
+            let expired = self
+                .anime_list_ttl
+                .is_some_and(|ttl| cached.fetched_at.elapsed() >= ttl);
+            if !expired {
+                return Ok(cached.data.clone());
+            }
+        }
+
+        let user_anime_list = match (
+            self.fetch_anime_list(&self.endpoint).await,
+            &self.read_fallback_endpoint,
+        ) {
+            (Ok(user_anime_list), _) => user_anime_list,
+            (Err(error), Some(fallback_endpoint)) => {
+                warn!(
+                    "unable to get the list from mal, reading it from {}: {:#}",
+                    fallback_endpoint, error
+                );
+                self.fetch_anime_list(fallback_endpoint).await?
+            }
+            (Err(error), None) => return Err(error),
+        };
+        let data = Arc::new(user_anime_list);
+        *anime_list = Some(CachedAnimeList {
+            fetched_at: Instant::now(),
+            data: data.clone(),
+        });
+        Ok(data)
+    }
+
+    async fn fetch_anime_list(&self, endpoint: &str) -> Result<Vec<UserAnimeListDatum>> {
+        self.anime_list_pages(endpoint).try_collect().await
+    }
+
+    /// Streams the user's list, fetching each page as the previous one runs out. Unlike
+    /// `get_anime_list`, nothing is cached.
+    pub fn get_anime_list_paginated(&self) -> impl Stream<Item = Result<UserAnimeListDatum>> + '_ {
+        self.anime_list_pages(&self.endpoint)
+    }
+
+    fn anime_list_pages<'a>(
+        &'a self,
+        endpoint: &'a str,
+    ) -> impl Stream<Item = Result<UserAnimeListDatum>> + 'a {
+        stream::try_unfold(Some(0), move |offset| async move {
+            let Some(offset) = offset else {
+                return Ok(None);
+            };
+            let page = self.fetch_anime_list_page(endpoint, offset).await?;
+            // an empty page can't move the offset on, so it ends the list as well
+            let next = (page.paging.next.is_some() && !page.data.is_empty())
+                .then(|| offset + page.data.len());
+            let data = stream::iter(page.data.into_iter().map(Ok));
+            Ok::<_, anyhow::Error>(Some((data, next)))
+        })
+        .try_flatten()
+    }
+
+    async fn fetch_anime_list_page(
+        &self,
+        endpoint: &str,
+        offset: usize,
+    ) -> Result<UserAnimeListResponse> {
+        let limit = LIST_PAGE_SIZE.to_string();
+        let offset = offset.to_string();
         let mut params: HashMap<&str, &str> = HashMap::new();
-        params.insert("limit", "1000");
-        params.insert("fields", "list_status");
-        let user_anime_list_response = self
-            .request(RequestType::Get, "/users/@me/animelist", Some(params), None)
+        params.insert("limit", &limit);
+        params.insert("offset", &offset);
+        params.insert("fields", "list_status,num_episodes");
+        let response = self
+            .request_to(
+                endpoint,
+                RequestType::Get,
+                "/users/@me/animelist",
+                Some(params),
+                None,
+            )
             .await?;
-        let text = user_anime_list_response.text().await?;
-        let user_anime_list: UserAnimeListResponse = serde_json::from_str(&text)?;
-        for datum in user_anime_list.data {
+        parse_response(response.error_for_status()?).await
+    }
+
+    /// Drops the cached list, so that the next lookup fetches it again.
+    pub async fn refresh_anime_list(&self) {
+        *self.anime_list.lock().await = None;
+    }
+
+    pub async fn get_latest_episode_number(&self, series_id: i32) -> Result<i32> {
+        for datum in self.get_anime_list().await?.iter() {
             if datum.node.id == series_id {
                 return Ok(datum.list_status.num_episodes_watched);
             }
@@ -97,21 +481,933 @@ impl MyAnimeListApi {
         Ok(0)
     }
 
+    /// The status of a series on the user's list, or none when it isn't on the list.
+    pub async fn get_list_status(&self, series_id: i32) -> Result<Option<ListStatus>> {
+        Ok(self
+            .get_anime_list()
+            .await?
+            .iter()
+            .find(|datum| datum.node.id == series_id)
+            .and_then(|datum| datum.list_status.status))
+    }
+
+    /// The number of episodes of a series on the user's list, or none when it isn't on the list
+    /// or mal doesn't know the total yet.
+    pub async fn get_num_episodes(&self, series_id: i32) -> Result<Option<i32>> {
+        Ok(self
+            .get_anime_list()
+            .await?
+            .iter()
+            .find(|datum| datum.node.id == series_id)
+            .map(|datum| datum.node.num_episodes)
+            .filter(|num_episodes| *num_episodes > 0))
+    }
+
+    // the user's entry for a series, fetched fresh instead of read from the cached list
+    async fn get_my_list_status(&self, series_id: i32) -> Result<MyListStatus> {
+        let mut params: HashMap<&str, &str> = HashMap::new();
+        params.insert("fields", "my_list_status");
+        let response = self
+            .request(
+                RequestType::Get,
+                &format!("/anime/{}", series_id),
+                Some(params),
+                None,
+            )
+            .await?;
+        let response: MyListStatusResponse = parse_response(response.error_for_status()?).await?;
+        Ok(response.my_list_status.unwrap_or_default())
+    }
+
+    /// Fetches a series' details, with the given `fields` on top of the id and title.
+    pub async fn get_anime_details(&self, series_id: i32, fields: &[&str]) -> Result<AnimeDetails> {
+        let fields = fields.join(",");
+        let mut params: HashMap<&str, &str> = HashMap::new();
+        params.insert("fields", &fields);
+        let response = self
+            .request(
+                RequestType::Get,
+                &format!("/anime/{}", series_id),
+                Some(params),
+                None,
+            )
+            .await?;
+        parse_response(response.error_for_status()?).await
+    }
+
+    /// Searches mal for series by title, with their english titles and synonyms.
+    pub async fn search_anime(&self, title: &str, limit: usize) -> Result<Vec<Candidate>> {
+        let limit = limit.to_string();
+        let mut params: HashMap<&str, &str> = HashMap::new();
+        params.insert("q", title);
+        params.insert("limit", &limit);
+        params.insert("fields", "alternative_titles");
+        let response = self
+            .request(RequestType::Get, "/anime", Some(params), None)
+            .await?;
+        let response: SearchResponse = parse_response(response.error_for_status()?).await?;
+        Ok(response
+            .data
+            .into_iter()
+            .map(|datum| {
+                let alternative_titles = datum.node.alternative_titles;
+                Candidate {
+                    mal_id: datum.node.id,
+                    title: datum.node.title,
+                    alternative_titles: std::iter::once(alternative_titles.en)
+                        .filter(|en| !en.is_empty())
+                        .chain(alternative_titles.synonyms)
+                        .collect(),
+                }
+            })
+            .collect())
+    }
+
+    // looks a series up in the cached list, without fetching it
+    async fn cached_list_status(&self, series_id: i32) -> Option<(Option<ListStatus>, i32)> {
+        let anime_list = self.anime_list.lock().await;
+        anime_list
+            .as_ref()?
+            .data
+            .iter()
+            .find(|datum| datum.node.id == series_id)
+            .map(|datum| {
+                (
+                    datum.list_status.status,
+                    datum.list_status.num_episodes_watched,
+                )
+            })
+    }
+
+    /// Builds the request that sets the number of episodes watched, marking the series as
+    /// watching. A completed series isn't touched unless the count goes up, so it's never
+    /// demoted back to watching, and there's no request.
+    pub async fn plan_latest_episode_number(
+        &self,
+        series_id: i32,
+        episode_number: i32,
+    ) -> Option<PlannedRequest> {
+        self.plan_progress(series_id, episode_number, ListStatus::Watching)
+            .await
+    }
+
+    /// Like `plan_latest_episode_number`, with the status the series is given.
+    pub async fn plan_progress(
+        &self,
+        series_id: i32,
+        episode_number: i32,
+        status: ListStatus,
+    ) -> Option<PlannedRequest> {
+        if let Some((Some(ListStatus::Completed), watched)) =
+            self.cached_list_status(series_id).await
+        {
+            if episode_number <= watched {
+                debug!(
+                    "not updating {}, it's already completed at episode {}",
+                    series_id, watched
+                );
+                return None;
+            }
+        }
+        let mut form_data: BTreeMap<&'static str, String> = BTreeMap::new();
+        form_data.insert("num_watched_episodes", episode_number.to_string());
+        form_data.insert("status", status.as_str().to_string());
+        if let Some(comments) = &self.comments {
+            form_data.insert("comments", comments.clone());
+        }
+        if let Some(tags) = &self.tags {
+            form_data.insert("tags", tags.clone());
+        }
+        Some(PlannedRequest {
+            method: "PATCH",
+            url: format!("{}/anime/{}/my_list_status", self.endpoint, series_id),
+            route: format!("/anime/{}/my_list_status", series_id),
+            form_data,
+        })
+    }
+
     pub async fn set_latest_episode_number(
         &self,
         series_id: i32,
         episode_number: i32,
     ) -> Result<()> {
+        self.set_progress(series_id, episode_number, ListStatus::Watching)
+            .await
+    }
+
+    /// Sets the number of episodes watched along with the series' status, e.g. completed once
+    /// every episode has been watched.
+    pub async fn set_progress(
+        &self,
+        series_id: i32,
+        episode_number: i32,
+        status: ListStatus,
+    ) -> Result<()> {
+        match self
+            .write_progress(series_id, episode_number, status)
+            .await?
+        {
+            Some(status) if !status.is_success() => Err(anyhow!(
+                "mal rejected the update of {} with {}",
+                series_id,
+                status
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Like `set_progress`, returning the status mal answered with, or None when nothing had
+    /// to be sent. A rejected update isn't an error here.
+    pub async fn write_progress(
+        &self,
+        series_id: i32,
+        episode_number: i32,
+        status: ListStatus,
+    ) -> Result<Option<StatusCode>> {
+        let Some(mut planned) = self.plan_progress(series_id, episode_number, status).await else {
+            return Ok(None);
+        };
+        if self.read_before_write {
+            let current = self.get_my_list_status(series_id).await?.form_values();
+            planned
+                .form_data
+                .retain(|field, value| current.get(field) != Some(value));
+            if planned.form_data.is_empty() {
+                debug!("not updating {}, nothing changed", series_id);
+                return Ok(None);
+            }
+        }
+        let response = self
+            .request(
+                RequestType::Patch,
+                &planned.route,
+                None,
+                Some(planned.form_data.into_iter().collect()),
+            )
+            .await?;
+        Ok(Some(response.status()))
+    }
+
+    pub async fn set_status(&self, series_id: i32, status: ListStatus) -> Result<()> {
         let mut form_data: HashMap<&str, String> = HashMap::new();
-        form_data.insert("num_watched_episodes", episode_number.to_string());
-        form_data.insert("status", "watching".to_string());
+        form_data.insert("status", status.as_str().to_string());
         self.request(
             RequestType::Patch,
             &format!("/anime/{}/my_list_status", series_id),
             None,
             Some(form_data),
         )
-        .await?;
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    /// Removes a series from the user's list. A series that isn't on the list is already gone,
+    /// so that counts as removed too.
+    pub async fn remove_from_list(&self, series_id: i32) -> Result<()> {
+        let response = self
+            .request(
+                RequestType::Delete,
+                &format!("/anime/{}/my_list_status", series_id),
+                None,
+                None,
+            )
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        response.error_for_status()?;
+        Ok(())
+    }
+
+    /// Marks every series that is still "watching" but has all of its episodes watched (or the
+    /// completed ratio of them) as completed. Returns the number of series that were fixed.
+    pub async fn cleanup_completed(&self) -> Result<usize> {
+        let mut fixed = 0;
+        for datum in self.get_anime_list().await?.iter() {
+            if datum.list_status.status != Some(ListStatus::Watching) {
+                continue;
+            }
+            if is_completed(
+                datum.list_status.num_episodes_watched,
+                datum.node.num_episodes,
+                self.completed_ratio,
+            ) {
+                info!(
+                    "marking series {} (mal-id: {}) as completed",
+                    datum.node.title, datum.node.id
+                );
+                self.set_status(datum.node.id, ListStatus::Completed)
+                    .await?;
+                fixed += 1;
+            }
+        }
+        Ok(fixed)
+    }
+}
+
+/// Whether `num_episodes_watched` covers at least `completed_ratio` of the episodes. Series
+/// without a known number of episodes can't be told to be completed.
+pub fn is_completed(num_episodes_watched: i32, num_episodes: i32, completed_ratio: f64) -> bool {
+    num_episodes > 0 && num_episodes_watched as f64 >= completed_ratio * num_episodes as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use serde_json::json;
+    use wiremock::matchers::{body_string_contains, header_regex, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    use crate::backoff::DEFAULT_RETRIES;
+
+    fn test_token() -> ClientToken {
+        ClientToken {
+            refresh_token: "refresh".to_string(),
+            access_token: "access".to_string(),
+            expiration_date: 0,
+            last_refreshed: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_retries_rate_limit() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/users/@me"))
+            .respond_with(ResponseTemplate::new(429))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/users/@me"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "id": 1, "name": "user" })),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mal_api = MyAnimeListApi::new(test_token())
+            .with_endpoint(&server.uri())
+            .with_retry_backoff(Backoff::new(Duration::ZERO));
+        assert_eq!(mal_api.get_user_info().await?.name, "user");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_user_info() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let mal_api = MyAnimeListApi::new(test_token()).with_endpoint(&server.uri());
+        Mock::given(method("GET"))
+            .and(path("/users/@me"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "id": 7, "name": "alyosha" })),
+            )
+            .mount(&server)
+            .await;
+
+        let user_info = mal_api.get_user_info().await?;
+        assert_eq!(user_info.id, 7);
+        assert_eq!(user_info.name, "alyosha");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_user_info_unauthorized() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let mal_api = MyAnimeListApi::new(test_token()).with_endpoint(&server.uri());
+        Mock::given(method("GET"))
+            .and(path("/users/@me"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+
+        let error = mal_api.get_user_info().await.err().unwrap();
+        assert!(error.to_string().contains("--init-auth"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remove_from_list() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let mal_api = MyAnimeListApi::new(test_token())
+            .with_endpoint(&server.uri())
+            .with_retry_backoff(Backoff::new(Duration::ZERO));
+        Mock::given(method("DELETE"))
+            .and(path("/anime/4181/my_list_status"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/anime/4182/my_list_status"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/anime/4183/my_list_status"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        mal_api.remove_from_list(4181).await?;
+        mal_api.remove_from_list(4182).await?;
+        assert!(mal_api.remove_from_list(4183).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_latest_episode_number_annotations() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("PATCH"))
+            .and(path("/anime/4181/my_list_status"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        MyAnimeListApi::new(test_token())
+            .with_endpoint(&server.uri())
+            .set_latest_episode_number(4181, 5)
+            .await?;
+        MyAnimeListApi::new(test_token())
+            .with_endpoint(&server.uri())
+            .with_annotations(None, Some("jellyfin-sync".to_string()))
+            .set_latest_episode_number(4181, 6)
+            .await?;
+
+        let requests = server.received_requests().await.unwrap();
+        let bodies: Vec<String> = requests
+            .iter()
+            .map(|request| String::from_utf8_lossy(&request.body).to_string())
+            .collect();
+        assert!(!bodies[0].contains("tags") && !bodies[0].contains("comments"));
+        assert!(bodies[1].contains("tags=jellyfin-sync"));
+        assert!(!bodies[1].contains("comments"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_plan_latest_episode_number() {
+        let planned = MyAnimeListApi::new(test_token())
+            .with_endpoint("http://mal")
+            .with_annotations(Some("synced".to_string()), None)
+            .plan_latest_episode_number(4181, 5)
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(planned).unwrap(),
+            json!({
+                "method": "PATCH",
+                "url": "http://mal/anime/4181/my_list_status",
+                "form_data": {
+                    "comments": "synced",
+                    "num_watched_episodes": "5",
+                    "status": "watching"
+                }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_plan_progress_completed() {
+        let planned = MyAnimeListApi::new(test_token())
+            .plan_progress(4181, 24, ListStatus::Completed)
+            .await
+            .unwrap();
+        assert_eq!(planned.form_data["status"], "completed");
+        assert_eq!(planned.form_data["num_watched_episodes"], "24");
+    }
+
+    #[tokio::test]
+    async fn test_set_latest_episode_number_read_before_write() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/anime/4181"))
+            .and(query_param("fields", "my_list_status"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": 4181,
+                "title": "Clannad: After Story",
+                "my_list_status": {
+                    "status": "watching",
+                    "score": 9,
+                    "num_episodes_watched": 3,
+                    "tags": []
+                }
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path("/anime/4181/my_list_status"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mal_api = MyAnimeListApi::new(test_token())
+            .with_endpoint(&server.uri())
+            .with_read_before_write(true);
+        mal_api.set_latest_episode_number(4181, 5).await?;
+        // nothing changes, so nothing is sent
+        mal_api.set_latest_episode_number(4181, 3).await?;
+
+        let requests = server.received_requests().await.unwrap();
+        let patches: Vec<String> = requests
+            .iter()
+            .filter(|request| request.method == wiremock::http::Method::PATCH)
+            .map(|request| String::from_utf8_lossy(&request.body).to_string())
+            .collect();
+        // only the episodes changed, so the status and score are left alone
+        assert_eq!(patches, vec!["num_watched_episodes=5"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_anime_details_subset() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/anime/4181"))
+            .and(query_param("fields", "num_episodes,media_type"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": 4181,
+                "title": "Clannad: After Story",
+                "num_episodes": 24,
+                "media_type": "tv"
+            })))
+            .mount(&server)
+            .await;
+
+        let details = MyAnimeListApi::new(test_token())
+            .with_endpoint(&server.uri())
+            .get_anime_details(4181, &["num_episodes", "media_type"])
+            .await?;
+        assert_eq!(
+            details,
+            AnimeDetails {
+                id: 4181,
+                title: "Clannad: After Story".to_string(),
+                num_episodes: Some(24),
+                status: None,
+                media_type: Some("tv".to_string()),
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_search_anime() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/anime"))
+            .and(query_param("q", "clannad"))
+            .and(query_param("limit", "2"))
+            .and(query_param("fields", "alternative_titles"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [
+                    {
+                        "node": {
+                            "id": 2167,
+                            "title": "Clannad",
+                            "alternative_titles": { "synonyms": [], "en": "Clannad", "ja": "" }
+                        }
+                    },
+                    {
+                        "node": {
+                            "id": 4181,
+                            "title": "Clannad: After Story",
+                            "alternative_titles": { "synonyms": ["Clannad AS"], "en": "" }
+                        }
+                    }
+                ],
+                "paging": {}
+            })))
+            .mount(&server)
+            .await;
+
+        let candidates = MyAnimeListApi::new(test_token())
+            .with_endpoint(&server.uri())
+            .search_anime("clannad", 2)
+            .await?;
+        assert_eq!(
+            candidates,
+            vec![
+                Candidate {
+                    mal_id: 2167,
+                    title: "Clannad".to_string(),
+                    alternative_titles: vec!["Clannad".to_string()],
+                },
+                Candidate {
+                    mal_id: 4181,
+                    title: "Clannad: After Story".to_string(),
+                    alternative_titles: vec!["Clannad AS".to_string()],
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_progress_status() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("PATCH"))
+            .and(path("/anime/4181/my_list_status"))
+            .respond_with(ResponseTemplate::new(400))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let mal_api = MyAnimeListApi::new(test_token()).with_endpoint(&server.uri());
+        let status = mal_api.write_progress(4181, 5, ListStatus::OnHold).await?;
+        assert_eq!(status, Some(StatusCode::BAD_REQUEST));
+        // the sync goes through set_progress, which counts it as failed
+        assert!(mal_api
+            .set_progress(4181, 5, ListStatus::OnHold)
+            .await
+            .is_err());
+        let requests = server.received_requests().await.unwrap();
+        let body = String::from_utf8_lossy(&requests[0].body).to_string();
+        let mut fields: Vec<&str> = body.split('&').collect();
+        fields.sort();
+        assert_eq!(fields, vec!["num_watched_episodes=5", "status=on_hold"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_latest_episode_number_keeps_completed() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let mal_api = MyAnimeListApi::new(test_token()).with_endpoint(&server.uri());
+        Mock::given(method("GET"))
+            .and(path("/users/@me/animelist"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [{
+                    "node": { "id": 4181, "title": "Clannad: After Story", "num_episodes": 24 },
+                    "list_status": { "num_episodes_watched": 24, "status": "completed" }
+                }]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path("/anime/4181/my_list_status"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        assert_eq!(mal_api.get_latest_episode_number(4181).await?, 24);
+        assert!(mal_api.plan_latest_episode_number(4181, 24).await.is_none());
+        mal_api.set_latest_episode_number(4181, 24).await?;
+        mal_api.set_latest_episode_number(4181, 20).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let mal_api = MyAnimeListApi::new(test_token())
+            .with_endpoint(&server.uri())
+            .with_rate_limit(20.0);
+        Mock::given(method("GET"))
+            .and(path("/users/@me"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "id": 7, "name": "alyosha" })),
+            )
+            .mount(&server)
+            .await;
+
+        // the first request goes out immediately, the other four wait 50ms each
+        let start = std::time::Instant::now();
+        futures::future::try_join_all((0..5).map(|_| mal_api.get_user_info())).await?;
+        assert!(start.elapsed() >= std::time::Duration::from_millis(200));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_delay() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let mal_api = MyAnimeListApi::new(test_token())
+            .with_endpoint(&server.uri())
+            .with_write_delay(std::time::Duration::from_millis(100));
+        Mock::given(method("PATCH"))
+            .and(path("/anime/4181/my_list_status"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/users/@me"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "id": 7, "name": "alyosha" })),
+            )
+            .mount(&server)
+            .await;
+
+        // reads aren't held up by the delay
+        let start = std::time::Instant::now();
+        mal_api.get_user_info().await?;
+        mal_api.get_user_info().await?;
+        assert!(start.elapsed() < std::time::Duration::from_millis(100));
+
+        let start = std::time::Instant::now();
+        mal_api.set_status(4181, ListStatus::Watching).await?;
+        mal_api.set_status(4181, ListStatus::Completed).await?;
+        assert!(start.elapsed() >= std::time::Duration::from_millis(100));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_anime_list_paginated() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let entry = |id: i32| {
+            json!({
+                "node": { "id": id, "title": format!("series {}", id) },
+                "list_status": { "num_episodes_watched": 1, "status": "watching" }
+            })
+        };
+        Mock::given(method("GET"))
+            .and(path("/users/@me/animelist"))
+            .and(query_param("offset", "0"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [entry(1), entry(2)],
+                "paging": { "next": format!("{}/users/@me/animelist?offset=2", server.uri()) }
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/users/@me/animelist"))
+            .and(query_param("offset", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [entry(3)],
+                "paging": {}
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mal_api = MyAnimeListApi::new(test_token()).with_endpoint(&server.uri());
+        let anime_list: Vec<UserAnimeListDatum> =
+            mal_api.get_anime_list_paginated().try_collect().await?;
+        let ids: Vec<i32> = anime_list.iter().map(|datum| datum.node.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_anime_list_with_num_episodes() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let mal_api = MyAnimeListApi::new(test_token()).with_endpoint(&server.uri());
+        Mock::given(method("GET"))
+            .and(path("/users/@me/animelist"))
+            .and(query_param("fields", "list_status,num_episodes"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [{
+                    "node": {
+                        "id": 4181,
+                        "title": "Clannad: After Story",
+                        "main_picture": { "medium": "https://example.com/a.jpg" },
+                        "num_episodes": 24
+                    },
+                    "list_status": {
+                        "status": "watching",
+                        "score": 10,
+                        "num_episodes_watched": 8,
+                        "is_rewatching": false,
+                        "updated_at": "2024-01-01T00:00:00+00:00"
+                    }
+                }]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let anime_list = mal_api.get_anime_list().await?;
+        assert_eq!(anime_list[0].node.num_episodes, 24);
+        // only what's needed is kept, so dumping the list can't leak anything else
+        let dumped = serde_json::to_value(anime_list.as_slice())?;
+        assert_eq!(
+            dumped,
+            json!([{
+                "node": { "id": 4181, "title": "Clannad: After Story", "num_episodes": 24 },
+                "list_status": { "num_episodes_watched": 8, "status": "watching" }
+            }])
+        );
+        assert_eq!(anime_list[0].list_status.num_episodes_watched, 8);
+        // the list is cached, so this doesn't fetch it again
+        assert_eq!(mal_api.get_latest_episode_number(4181).await?, 8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_anime_list_read_fallback() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let fallback = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/users/@me/animelist"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1 + DEFAULT_RETRIES as u64)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/users/@me/animelist"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [{
+                    "node": { "id": 4181, "title": "Clannad: After Story" },
+                    "list_status": { "num_episodes_watched": 3, "status": "watching" }
+                }]
+            })))
+            .expect(1)
+            .mount(&fallback)
+            .await;
+        // the write still goes to mal
+        Mock::given(method("PATCH"))
+            .and(path("/anime/4181/my_list_status"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mal_api = MyAnimeListApi::new(test_token())
+            .with_endpoint(&server.uri())
+            .with_read_fallback(Some(&fallback.uri()))
+            .with_retry_backoff(Backoff::new(Duration::ZERO));
+        assert_eq!(mal_api.get_latest_episode_number(4181).await?, 3);
+        mal_api.set_latest_episode_number(4181, 4).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_list_status() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/users/@me/animelist"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [{
+                    "node": { "id": 4181, "title": "Clannad: After Story" },
+                    "list_status": { "num_episodes_watched": 24, "status": "completed" }
+                }]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mal_api = MyAnimeListApi::new(test_token()).with_endpoint(&server.uri());
+        assert_eq!(
+            mal_api.get_list_status(4181).await?,
+            Some(ListStatus::Completed)
+        );
+        // read from the cached list
+        assert_eq!(mal_api.get_list_status(1).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_anime_list_gzip() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let body = json!({
+            "data": [{
+                "node": { "id": 4181, "title": "Clannad: After Story" },
+                "list_status": { "num_episodes_watched": 3, "status": "watching" }
+            }]
+        });
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(body.to_string().as_bytes())?;
+        Mock::given(method("GET"))
+            .and(path("/users/@me/animelist"))
+            .and(header_regex("Accept-Encoding", "gzip"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("Content-Encoding", "gzip")
+                    .insert_header("Content-Type", "application/json")
+                    .set_body_bytes(encoder.finish()?),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mal_api = MyAnimeListApi::new(test_token()).with_endpoint(&server.uri());
+        assert_eq!(mal_api.get_latest_episode_number(4181).await?, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_anime_list_ttl() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let mal_api = MyAnimeListApi::new(test_token())
+            .with_endpoint(&server.uri())
+            .with_anime_list_ttl(Some(std::time::Duration::from_millis(50)));
+        Mock::given(method("GET"))
+            .and(path("/users/@me/animelist"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": [] })))
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        // within the ttl the cached list is used
+        mal_api.get_anime_list().await?;
+        mal_api.get_anime_list().await?;
+        // once it runs out the list is fetched again
+        tokio::time::sleep(std::time::Duration::from_millis(60)).await;
+        mal_api.get_anime_list().await?;
+        // and a refresh forces it regardless
+        mal_api.refresh_anime_list().await;
+        mal_api.get_anime_list().await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_completed() {
+        assert!(is_completed(12, 12, 1.0));
+        assert!(!is_completed(11, 12, 1.0));
+        // 19 of 20 is exactly 0.95
+        assert!(is_completed(19, 20, 0.95));
+        assert!(!is_completed(18, 20, 0.95));
+        assert!(!is_completed(3, 0, 0.95));
+    }
+
+    #[test]
+    fn test_completed_ratio_is_clamped() {
+        let mal_api = MyAnimeListApi::new(test_token()).with_completed_ratio(1.5);
+        assert_eq!(mal_api.completed_ratio, 1.0);
+        let mal_api = MyAnimeListApi::new(test_token()).with_completed_ratio(-0.5);
+        assert_eq!(mal_api.completed_ratio, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_completed() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let mal_api = MyAnimeListApi::new(test_token()).with_endpoint(&server.uri());
+        let entry = |id: i32, watched: i32, num_episodes: i32, status: &str| {
+            json!({
+                "node": { "id": id, "title": format!("series {}", id), "num_episodes": num_episodes },
+                "list_status": { "num_episodes_watched": watched, "status": status }
+            })
+        };
+
+        Mock::given(method("GET"))
+            .and(path("/users/@me/animelist"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [
+                    entry(1, 12, 12, "watching"),  // fully watched, but stuck as watching
+                    entry(2, 5, 12, "watching"),   // still in progress
+                    entry(3, 12, 12, "completed"), // already completed
+                    entry(4, 3, 0, "watching"),    // unknown number of episodes
+                ]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path("/anime/1/my_list_status"))
+            .and(body_string_contains("status=completed"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let fixed = mal_api.cleanup_completed().await?;
+        assert_eq!(fixed, 1);
         Ok(())
     }
 }
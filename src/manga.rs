@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::{fs::File, io::BufReader};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::mal::{MyAnimeListApi, RequestType};
+
+#[derive(Serialize, Deserialize)]
+struct UserMangaListResponse {
+    data: Vec<UserMangaListDatum>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserMangaListDatum {
+    node: UserMangaListNode,
+    list_status: UserMangaListStatus,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserMangaListNode {
+    id: i32,
+    title: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserMangaListStatus {
+    num_volumes_read: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MangaMapping {
+    series_name: String,
+    mal_id: i32,
+}
+
+/// Tracks manga progress on MAL, sharing the token and request plumbing of the anime api.
+pub struct MyMangaListApi<'a> {
+    mal_api: &'a MyAnimeListApi,
+}
+
+impl<'a> MyMangaListApi<'a> {
+    pub fn new(mal_api: &'a MyAnimeListApi) -> MyMangaListApi<'a> {
+        MyMangaListApi { mal_api }
+    }
+
+    pub async fn get_latest_volume_number(&self, series_id: i32) -> Result<i32> {
+        let mut params: HashMap<&str, &str> = HashMap::new();
+        params.insert("limit", "1000");
+        params.insert("fields", "list_status");
+        let user_manga_list_response = self
+            .mal_api
+            .request(RequestType::Get, "/users/@me/mangalist", Some(params), None)
+            .await?;
+        let text = user_manga_list_response.text().await?;
+        let user_manga_list: UserMangaListResponse = serde_json::from_str(&text)?;
+        for datum in user_manga_list.data {
+            if datum.node.id == series_id {
+                return Ok(datum.list_status.num_volumes_read);
+            }
+        }
+        Ok(0)
+    }
+
+    pub async fn set_latest_volume_number(&self, series_id: i32, volume_number: i32) -> Result<()> {
+        let mut form_data: HashMap<&str, String> = HashMap::new();
+        form_data.insert("num_volumes_read", volume_number.to_string());
+        form_data.insert("status", "reading".to_string());
+        self.mal_api
+            .request(
+                RequestType::Patch,
+                &format!("/manga/{}/my_list_status", series_id),
+                None,
+                Some(form_data),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// Looks up the MAL manga id for a jellyfin book series in a user provided mapping file.
+pub fn series_name_to_mal_manga_id(series_name: &str, mapping_path: &str) -> Result<Option<i32>> {
+    let f = File::open(mapping_path)?;
+    let reader = BufReader::new(f);
+    let mappings: Vec<MangaMapping> = serde_json::from_reader(reader)?;
+    Ok(mappings
+        .into_iter()
+        .find(|mapping| mapping.series_name == series_name)
+        .map(|mapping| mapping.mal_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_series_name_to_mal_manga_id() -> Result<(), anyhow::Error> {
+        let mal_id = series_name_to_mal_manga_id("Berserk", "tests/fixtures/manga-mapping.json")?;
+        assert_eq!(mal_id, Some(2));
+        let mal_id = series_name_to_mal_manga_id("Unknown", "tests/fixtures/manga-mapping.json")?;
+        assert_eq!(mal_id, None);
+        Ok(())
+    }
+}
@@ -1,14 +1,51 @@
-use std::{fs::File, io::BufReader};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind},
+};
 
 use anyhow::{anyhow, Result};
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use serde_xml_rs::from_reader;
 
+use crate::jellyfin::{Episode, IdSource, SeasonKey};
+
+/// The season of entries mapped by absolute episode numbers, `defaulttvdbseason="a"` in the
+/// anidb mapping.
+pub const ABSOLUTE_SEASON: i32 = -1;
+
+const ANIDB_MAPPING_URL: &str =
+    "https://raw.githubusercontent.com/Anime-Lists/anime-lists/master/anime-list-master.xml";
+const MAL_MAPPING_URL: &str =
+    "https://raw.githubusercontent.com/Fribb/anime-lists/master/anime-list-full.json";
+
 #[derive(Serialize, Deserialize)]
 struct Anime {
     anidbid: String,
     tvdbid: String,
     defaulttvdbseason: String,
+    #[serde(default)]
+    episodeoffset: String,
+    #[serde(rename = "mapping-list")]
+    mapping_list: Option<MappingList>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MappingList {
+    #[serde(rename = "$value", default)]
+    mappings: Vec<SeasonMapping>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SeasonMapping {
+    anidbseason: String,
+    tvdbseason: String,
+    start: Option<String>,
+    end: Option<String>,
+    offset: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -20,47 +57,665 @@ struct AnimeList {
 #[derive(Serialize, Deserialize)]
 struct OfflineAnime {
     anidb_id: Option<i32>,
+    anilist_id: Option<i32>,
+    // e.g. "tt0245429", mostly set for films
+    imdb_id: Option<String>,
     mal_id: Option<i32>,
 }
 
-pub fn tvdb_id_to_mal_id(
-    tvdb_id: i32,
-    tvdb_season_number: i32,
+/// The key a series with only an imdb id is looked up by. It's the id's number negated, e.g.
+/// -245429 for tt0245429, so it can't collide with a tvdb id.
+pub fn imdb_key(imdb_id: &str) -> Option<i32> {
+    let number: i32 = imdb_id.strip_prefix("tt")?.parse().ok()?;
+    (number > 0).then_some(-number)
+}
+
+/// A single tvdb season resolved through both mapping files.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ConsolidatedEntry {
+    // or the `imdb_key` of a film without a tvdb id
+    pub tvdb_id: i32,
+    // `ABSOLUTE_SEASON` for entries numbered by the series' absolute episode numbers
+    pub tvdb_season: i32,
+    pub anidb_id: i32,
+    // the anidb entry may not be known to the mal mapping
+    pub mal_id: Option<i32>,
+    // tvdb episode = anidb episode + offset
+    #[serde(default)]
+    pub episode_offset: i32,
+    // anidb episodes that are explicitly mapped to other tvdb seasons
+    #[serde(default)]
+    pub season_ranges: Vec<SeasonRange>,
+}
+
+/// Maps the anidb episodes `start..=end` to `tvdb_season`, where tvdb episode = anidb episode +
+/// offset.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct SeasonRange {
+    pub tvdb_season: i32,
+    pub start: i32,
+    pub end: i32,
+    pub offset: i32,
+}
+
+/// The stage of the mapping chain a series couldn't get past.
+#[derive(Debug, PartialEq)]
+pub enum MappingError {
+    /// no anidb entry for the tvdb season
+    NoAnidbEntry,
+    /// no mal entry for the anidb (or anilist) id
+    NoMalEntry(IdSource),
+}
+
+impl std::fmt::Display for MappingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MappingError::NoAnidbEntry => write!(f, "unable to map tvdb to anidb"),
+            MappingError::NoMalEntry(IdSource::AniList) => {
+                write!(f, "unable to map anilist id to mal id")
+            }
+            MappingError::NoMalEntry(_) => write!(f, "unable to map anidb id to mal id"),
+        }
+    }
+}
+
+impl std::error::Error for MappingError {}
+
+/// How much of a library the mappings cover, counted per season.
+#[derive(Default)]
+pub struct MappingStats {
+    pub mapped: usize,
+    // series names and seasons that failed at each stage
+    pub unmapped_tvdb: Vec<(String, i32)>,
+    pub unmapped_anidb: Vec<(String, i32)>,
+}
+
+impl MappingStats {
+    pub fn total(&self) -> usize {
+        self.mapped + self.unmapped_tvdb.len() + self.unmapped_anidb.len()
+    }
+}
+
+/// A series whose mapping is known, to check the mapping files with.
+pub struct KnownMapping {
+    pub name: &'static str,
+    pub tvdb_id: i32,
+    pub tvdb_season: i32,
+    pub mal_id: i32,
+}
+
+pub const KNOWN_MAPPINGS: [KnownMapping; 4] = [
+    KnownMapping {
+        name: "Cowboy Bebop",
+        tvdb_id: 76885,
+        tvdb_season: 1,
+        mal_id: 1,
+    },
+    KnownMapping {
+        name: "Death Note",
+        tvdb_id: 79481,
+        tvdb_season: 1,
+        mal_id: 1535,
+    },
+    KnownMapping {
+        name: "Fullmetal Alchemist: Brotherhood",
+        tvdb_id: 85249,
+        tvdb_season: 1,
+        mal_id: 5114,
+    },
+    KnownMapping {
+        name: "Clannad: After Story",
+        tvdb_id: 80644,
+        tvdb_season: 2,
+        mal_id: 4181,
+    },
+];
+
+/// A jellyfin episode resolved to its mal entry, numbered the way mal numbers it.
+#[derive(PartialEq, Debug)]
+pub struct ResolvedEpisode {
+    pub mal_id: i32,
+    pub episode_number: i32,
+}
+
+impl ConsolidatedEntry {
+    fn resolve(&self, anidb_episode_number: i32) -> Result<ResolvedEpisode> {
+        Ok(ResolvedEpisode {
+            mal_id: self
+                .mal_id
+                .ok_or(MappingError::NoMalEntry(IdSource::AniDb))?,
+            episode_number: anidb_episode_number,
+        })
+    }
+}
+
+/// Resolves tvdb seasons to mal ids with a single lookup.
+pub struct MappingTable {
+    // entries for each tvdb id, in the order of the mapping files
+    entries: HashMap<i32, Vec<ConsolidatedEntry>>,
+    // set for anidb and anilist ids, which map straight to mal ids without any seasons
+    direct: Option<DirectMapping>,
+    // the files the table was loaded from, so it can be reloaded once they change
+    source: Option<MappingSource>,
+    // whether an episode's absolute number is tried before its season, see `resolve_episode`
+    prefer_absolute: bool,
+    // the number of episodes in each season, for seasons that continue the season 1 entry
+    season_lengths: HashMap<SeasonKey, i32>,
+}
+
+struct MappingSource {
+    anidb_mapping_path: String,
+    mal_mapping_path: String,
+    consolidated_mapping_path: Option<String>,
+    id_source: IdSource,
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl MappingSource {
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [&self.anidb_mapping_path, &self.mal_mapping_path]
+            .iter()
+            .map(|path| {
+                std::fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+struct DirectMapping {
+    id_source: IdSource,
+    mal_ids: HashMap<i32, i32>,
+}
+
+impl MappingTable {
+    /// Loads the consolidated mapping if one is given and exists, otherwise builds it from the
+    /// anidb and mal mapping files, or when those are newer. A newly built mapping is saved to the
+    /// consolidated path.
+    /// Anidb and anilist ids only need the mal mapping file, so the rest is skipped for them.
+    pub fn load(
+        anidb_mapping_path: &str,
+        mal_mapping_path: &str,
+        consolidated_mapping_path: Option<&str>,
+        id_source: IdSource,
+    ) -> Result<MappingTable> {
+        let mut source = MappingSource {
+            anidb_mapping_path: anidb_mapping_path.to_string(),
+            mal_mapping_path: mal_mapping_path.to_string(),
+            consolidated_mapping_path: consolidated_mapping_path.map(str::to_string),
+            id_source,
+            modified: vec![],
+        };
+        // taken before reading, so a file changing while it's read is picked up on the next reload
+        source.modified = source.modified();
+        let mut table = MappingTable::build(
+            anidb_mapping_path,
+            mal_mapping_path,
+            consolidated_mapping_path,
+            id_source,
+        )?;
+        table.source = Some(source);
+        Ok(table)
+    }
+
+    /// Loads the table again from the files it was loaded from, but only if one of the mapping
+    /// files changed since. Returns whether it was reloaded.
+    pub fn reload_if_changed(&mut self) -> Result<bool> {
+        let Some(source) = &self.source else {
+            return Ok(false);
+        };
+        if source.modified() == source.modified {
+            return Ok(false);
+        }
+        info!("the mapping files changed, reloading them");
+        *self = MappingTable::load(
+            &source.anidb_mapping_path,
+            &source.mal_mapping_path,
+            source.consolidated_mapping_path.as_deref(),
+            source.id_source,
+        )?
+        .with_prefer_absolute(self.prefer_absolute)
+        .with_season_lengths(std::mem::take(&mut self.season_lengths));
+        Ok(true)
+    }
+
+    fn build(
+        anidb_mapping_path: &str,
+        mal_mapping_path: &str,
+        consolidated_mapping_path: Option<&str>,
+        id_source: IdSource,
+    ) -> Result<MappingTable> {
+        if matches!(id_source, IdSource::AniDb | IdSource::AniList) {
+            return MappingTable::from_mal_mapping(mal_mapping_path, id_source);
+        }
+        if let Some(path) = consolidated_mapping_path {
+            if Path::new(path).exists()
+                && !is_outdated(path, &[anidb_mapping_path, mal_mapping_path])
+            {
+                return MappingTable::from_consolidated(path);
+            }
+        }
+        let table =
+            MappingTable::from_entries(build_consolidated(anidb_mapping_path, mal_mapping_path)?);
+        if let Some(path) = consolidated_mapping_path {
+            table.save(path)?;
+        }
+        Ok(table)
+    }
+
+    pub fn from_consolidated(mapping_path: &str) -> Result<MappingTable> {
+        let f = File::open(mapping_path)?;
+        let reader = BufReader::new(f);
+        let entries: Vec<ConsolidatedEntry> = serde_json::from_reader(reader)?;
+        Ok(MappingTable::from_entries(entries))
+    }
+
+    pub fn from_entries(entries: Vec<ConsolidatedEntry>) -> MappingTable {
+        let mut by_tvdb_id: HashMap<i32, Vec<ConsolidatedEntry>> = HashMap::new();
+        for entry in entries {
+            by_tvdb_id.entry(entry.tvdb_id).or_default().push(entry);
+        }
+        MappingTable {
+            entries: by_tvdb_id,
+            direct: None,
+            source: None,
+            prefer_absolute: true,
+            season_lengths: HashMap::new(),
+        }
+    }
+
+    /// Sets whether episodes with an absolute number are mapped by it first, when the series has
+    /// `defaulttvdbseason="a"` entries. When not, the season is mapped first, and the absolute
+    /// number only used if that fails. Either way, series without absolute entries are always
+    /// mapped by season.
+    pub fn with_prefer_absolute(mut self, prefer_absolute: bool) -> MappingTable {
+        self.prefer_absolute = prefer_absolute;
+        self
+    }
+
+    /// Sets the number of episodes in each season, e.g. from `jellyfin::season_lengths`. A season
+    /// without an entry of its own continues the season 1 entry after the earlier seasons'
+    /// episodes, so it can only be mapped once they're all known.
+    pub fn with_season_lengths(mut self, season_lengths: HashMap<SeasonKey, i32>) -> MappingTable {
+        self.season_lengths = season_lengths;
+        self
+    }
+
+    /// Builds a table that maps the given id source's ids straight to mal ids.
+    pub fn from_mal_mapping(mal_mapping_path: &str, id_source: IdSource) -> Result<MappingTable> {
+        let animes = read_mal_mapping(mal_mapping_path)?;
+        let mut mal_ids: HashMap<i32, i32> = HashMap::new();
+        for anime in animes {
+            let id = match id_source {
+                IdSource::AniList => anime.anilist_id,
+                _ => anime.anidb_id,
+            };
+            if let (Some(id), Some(mal_id)) = (id, anime.mal_id) {
+                mal_ids.entry(id).or_insert(mal_id);
+            }
+        }
+        Ok(MappingTable {
+            entries: HashMap::new(),
+            direct: Some(DirectMapping { id_source, mal_ids }),
+            source: None,
+            prefer_absolute: true,
+            season_lengths: HashMap::new(),
+        })
+    }
+
+    pub fn save(&self, mapping_path: &str) -> Result<()> {
+        let mut entries: Vec<&ConsolidatedEntry> = self.entries.values().flatten().collect();
+        entries.sort_by_key(|entry| entry.tvdb_id);
+        let f = File::create(mapping_path)?;
+        let writer = BufWriter::new(f);
+        serde_json::to_writer_pretty(writer, &entries)?;
+        Ok(())
+    }
+
+    /// Resolves a tvdb episode to its mal entry. For anidb and anilist ids the episode is used
+    /// as is, since those already number episodes per entry. Otherwise, when no entry matches
+    /// the season exactly, the entries of the series are searched for one that maps the season
+    /// explicitly, and finally the season 1 entry is used, counting on from the episodes of the
+    /// earlier seasons.
+    pub fn resolve(
+        &self,
+        tvdb_id: i32,
+        tvdb_season_number: i32,
+        episode_number: i32,
+    ) -> Result<ResolvedEpisode> {
+        if let Some(direct) = &self.direct {
+            let mal_id = direct
+                .mal_ids
+                .get(&tvdb_id)
+                .ok_or(MappingError::NoMalEntry(direct.id_source))?;
+            return Ok(ResolvedEpisode {
+                mal_id: *mal_id,
+                episode_number,
+            });
+        }
+
+        let entries = self
+            .entries
+            .get(&tvdb_id)
+            .ok_or(MappingError::NoAnidbEntry)?;
+
+        // seasons split across several entries are told apart by their offsets, so use the
+        // entry with the largest offset that the episode comes after
+        let mut exact: Option<&ConsolidatedEntry> = None;
+        for entry in entries {
+            if entry.tvdb_season == tvdb_season_number
+                && episode_number > entry.episode_offset
+                && exact.is_none_or(|other| entry.episode_offset > other.episode_offset)
+            {
+                exact = Some(entry);
+            }
+        }
+        if let Some(entry) = exact {
+            return entry.resolve(episode_number - entry.episode_offset);
+        }
+
+        for entry in entries {
+            for range in &entry.season_ranges {
+                let anidb_episode_number = episode_number - range.offset;
+                if range.tvdb_season == tvdb_season_number
+                    && (range.start..=range.end).contains(&anidb_episode_number)
+                {
+                    info!(
+                        "mapped tvdb id {} season {} episode {} to anidb id {} through its \
+                         season mapping",
+                        tvdb_id, tvdb_season_number, episode_number, entry.anidb_id
+                    );
+                    return entry.resolve(anidb_episode_number);
+                }
+            }
+        }
+
+        if let Some(entry) = entries.iter().find(|entry| entry.tvdb_season == 1) {
+            // guessing without the earlier seasons would put the season over season 1's progress
+            let Some(earlier_episodes) = (1..tvdb_season_number)
+                .map(|season| self.season_lengths.get(&(tvdb_id, season)).copied())
+                .sum::<Option<i32>>()
+            else {
+                info!(
+                    "no mapping for tvdb id {} season {}, and the earlier seasons' episodes \
+                     aren't known to fall back to the season 1 entry",
+                    tvdb_id, tvdb_season_number
+                );
+                return Err(MappingError::NoAnidbEntry.into());
+            };
+            info!(
+                "no mapping for tvdb id {} season {}, falling back to the season 1 entry \
+                 (anidb id {}) after {} episodes of earlier seasons",
+                tvdb_id, tvdb_season_number, entry.anidb_id, earlier_episodes
+            );
+            return entry.resolve(episode_number + earlier_episodes - entry.episode_offset);
+        }
+
+        Err(MappingError::NoAnidbEntry.into())
+    }
+
+    /// Resolves a jellyfin episode, by its absolute number when it has one and the series is
+    /// mapped by absolute numbers, and by its season and number otherwise. With
+    /// `with_prefer_absolute(false)` the season is tried first instead.
+    pub fn resolve_episode(&self, tvdb_id: i32, episode: &Episode) -> Result<ResolvedEpisode> {
+        let by_season = || self.resolve(tvdb_id, episode.season_number, episode.last_number());
+        if self.prefer_absolute {
+            if let Some(resolved) = self.resolve_absolute(tvdb_id, episode) {
+                return resolved;
+            }
+            return by_season();
+        }
+        match (by_season(), self.resolve_absolute(tvdb_id, episode)) {
+            (Err(_), Some(resolved)) => resolved,
+            (by_season, _) => by_season,
+        }
+    }
+
+    // none when the episode has no absolute number, or the series isn't mapped by them
+    fn resolve_absolute(&self, tvdb_id: i32, episode: &Episode) -> Option<Result<ResolvedEpisode>> {
+        let (Some(absolute_number), None) = (episode.last_absolute_number(), &self.direct) else {
+            return None;
+        };
+        // split the same way as seasons, by the largest offset the episode comes after
+        let entry = self
+            .entries
+            .get(&tvdb_id)
+            .into_iter()
+            .flatten()
+            .filter(|entry| {
+                entry.tvdb_season == ABSOLUTE_SEASON && absolute_number > entry.episode_offset
+            })
+            .max_by_key(|entry| entry.episode_offset)?;
+        Some(entry.resolve(absolute_number - entry.episode_offset))
+    }
+
+    /// Tries to map the first episode of every season, counting where the ones that can't be
+    /// mapped got stuck.
+    pub fn stats(&self, episodes: &[Episode]) -> MappingStats {
+        let mut seasons: BTreeMap<SeasonKey, &Episode> = BTreeMap::new();
+        for episode in episodes {
+            let first = seasons
+                .entry((episode.tvdb_id, episode.season_number))
+                .or_insert(episode);
+            if episode.number < first.number {
+                *first = episode;
+            }
+        }
+
+        let mut stats = MappingStats::default();
+        for ((tvdb_id, season), episode) in seasons {
+            let Err(e) = self.resolve(tvdb_id, season, episode.number) else {
+                stats.mapped += 1;
+                continue;
+            };
+            let unmapped = (episode.series_name.clone(), season);
+            match e.downcast_ref::<MappingError>() {
+                Some(MappingError::NoAnidbEntry) => stats.unmapped_tvdb.push(unmapped),
+                _ => stats.unmapped_anidb.push(unmapped),
+            }
+        }
+        stats
+    }
+
+    /// Resolves each of the known mappings, pairing them with whether they resolved correctly.
+    pub fn check_known_mappings(&self) -> Vec<(&'static KnownMapping, Result<()>)> {
+        KNOWN_MAPPINGS
+            .iter()
+            .map(|known| {
+                let result = match self.resolve(known.tvdb_id, known.tvdb_season, 1) {
+                    Ok(resolved) if resolved.mal_id == known.mal_id => Ok(()),
+                    Ok(resolved) => Err(anyhow!(
+                        "expected mal id {}, got {}",
+                        known.mal_id,
+                        resolved.mal_id
+                    )),
+                    Err(e) => Err(e),
+                };
+                (known, result)
+            })
+            .collect()
+    }
+}
+
+// a consolidated mapping built before its source files were updated has to be built again
+fn is_outdated(path: &str, source_paths: &[&str]) -> bool {
+    let modified = |path: &str| std::fs::metadata(path).and_then(|metadata| metadata.modified());
+    let Ok(built) = modified(path) else {
+        return true;
+    };
+    source_paths
+        .iter()
+        .any(|source_path| modified(source_path).is_ok_and(|source| source > built))
+}
+
+/// Joins the tvdb to anidb mapping with the anidb to mal mapping, so that each tvdb season only
+/// has to be resolved once.
+pub fn build_consolidated(
     anidb_mapping_path: &str,
     mal_mapping_path: &str,
-) -> Result<i32> {
-    let anidb_id = tvdb_id_to_anidb_id(tvdb_id, tvdb_season_number, anidb_mapping_path)?;
-    let mal_id = anidb_id_to_mal_id(anidb_id, mal_mapping_path)?;
-    Ok(mal_id)
-}
+) -> Result<Vec<ConsolidatedEntry>> {
+    let anime_list = read_anidb_mapping(anidb_mapping_path)?;
+    let animes = read_mal_mapping(mal_mapping_path)?;
+    let mut mal_ids: HashMap<i32, i32> = HashMap::new();
+    for anime in animes.iter() {
+        if let (Some(anidb_id), Some(mal_id)) = (anime.anidb_id, anime.mal_id) {
+            mal_ids.entry(anidb_id).or_insert(mal_id);
+        }
+    }
 
-fn tvdb_id_to_anidb_id(tvdb_id: i32, tvdb_season_number: i32, mapping_path: &str) -> Result<i32> {
-    let f = File::open(mapping_path)?;
-    let reader = BufReader::new(f);
-    let anime_list: AnimeList = from_reader(reader)?;
+    let mut entries: Vec<ConsolidatedEntry> = vec![];
     for anime in anime_list.animes {
-        if anime.tvdbid == tvdb_id.to_string()
-            && anime.defaulttvdbseason == tvdb_season_number.to_string()
+        // entries without a numeric tvdb id or season (e.g. movies) can't be looked up
+        let tvdb_season = match anime.defaulttvdbseason.as_str() {
+            "a" => Ok(ABSOLUTE_SEASON),
+            season => season.parse(),
+        };
+        let (Ok(tvdb_id), Ok(tvdb_season), Ok(anidb_id)) =
+            (anime.tvdbid.parse(), tvdb_season, anime.anidbid.parse())
+        else {
+            continue;
+        };
+        entries.push(ConsolidatedEntry {
+            tvdb_id,
+            tvdb_season,
+            anidb_id,
+            mal_id: mal_ids.get(&anidb_id).copied(),
+            episode_offset: anime.episodeoffset.parse().unwrap_or(0),
+            season_ranges: anime
+                .mapping_list
+                .map(|mapping_list| season_ranges(mapping_list.mappings))
+                .unwrap_or_default(),
+        });
+    }
+    // films often only have an imdb id, which the anidb mapping doesn't know about
+    for anime in animes {
+        let imdb_key = anime.imdb_id.as_deref().and_then(imdb_key);
+        if let (Some(imdb_key), Some(anidb_id), Some(mal_id)) =
+            (imdb_key, anime.anidb_id, anime.mal_id)
         {
-            return Ok(anime.anidbid.parse()?);
+            entries.push(ConsolidatedEntry {
+                tvdb_id: imdb_key,
+                tvdb_season: 1,
+                anidb_id,
+                mal_id: Some(mal_id),
+                episode_offset: 0,
+                season_ranges: vec![],
+            });
         }
     }
-    Err(anyhow!("unable to map tvdb to anidb"))
+    Ok(entries)
+}
+
+/// Looks a tvdb season up in the anidb mapping file alone, for debugging the first hop of the
+/// mapping. A season split across several anidb entries returns all of them.
+pub fn tvdb_id_to_anidb_id(
+    anidb_mapping_path: &str,
+    tvdb_id: i32,
+    tvdb_season: i32,
+) -> Result<Vec<i32>> {
+    let tvdb_id = tvdb_id.to_string();
+    let tvdb_season = tvdb_season.to_string();
+    let anidb_ids: Vec<i32> = read_anidb_mapping(anidb_mapping_path)?
+        .animes
+        .into_iter()
+        .filter(|anime| anime.tvdbid == tvdb_id)
+        .filter(|anime| {
+            anime.defaulttvdbseason == tvdb_season
+                || anime.mapping_list.as_ref().is_some_and(|mapping_list| {
+                    mapping_list.mappings.iter().any(|mapping| {
+                        mapping.anidbseason == "1" && mapping.tvdbseason == tvdb_season
+                    })
+                })
+        })
+        .filter_map(|anime| anime.anidbid.parse().ok())
+        .collect();
+    if anidb_ids.is_empty() {
+        return Err(MappingError::NoAnidbEntry.into());
+    }
+    Ok(anidb_ids)
+}
+
+/// Looks an anidb id up in the mal mapping file alone, for debugging the second hop of the
+/// mapping.
+pub fn anidb_id_to_mal_id(mal_mapping_path: &str, anidb_id: i32) -> Result<i32> {
+    read_mal_mapping(mal_mapping_path)?
+        .into_iter()
+        .find(|anime| anime.anidb_id == Some(anidb_id) && anime.mal_id.is_some())
+        .and_then(|anime| anime.mal_id)
+        .ok_or(MappingError::NoMalEntry(IdSource::AniDb).into())
 }
 
-fn anidb_id_to_mal_id(anidb_id: i32, mapping_path: &str) -> Result<i32> {
-    let f = File::open(mapping_path)?;
+fn read_anidb_mapping(anidb_mapping_path: &str) -> Result<AnimeList> {
+    let f = open_mapping(anidb_mapping_path, ANIDB_MAPPING_URL)?;
     let reader = BufReader::new(f);
-    let animes: Vec<OfflineAnime> = serde_json::from_reader(reader)?;
-    animes
-        .iter()
-        .find_map(|anime| {
-            if anime.anidb_id == Some(anidb_id) {
-                return anime.mal_id;
-            }
-            None
+    Ok(from_reader(reader)?)
+}
+
+fn read_mal_mapping(mal_mapping_path: &str) -> Result<Vec<OfflineAnime>> {
+    let f = open_mapping(mal_mapping_path, MAL_MAPPING_URL)?;
+    let reader = BufReader::new(f);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// Warns about mapping files last changed more than `max_age` ago, since shows added to the lists
+/// after that won't map. Returns the stale files. Missing ones are left for loading to report.
+pub fn warn_stale_mappings(
+    anidb_mapping_path: &str,
+    mal_mapping_path: &str,
+    max_age: Duration,
+) -> Vec<String> {
+    let mut stale = vec![];
+    for (mapping_path, url) in [
+        (anidb_mapping_path, ANIDB_MAPPING_URL),
+        (mal_mapping_path, MAL_MAPPING_URL),
+    ] {
+        let age = std::fs::metadata(mapping_path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        if let Some(age) = age.filter(|age| *age > max_age) {
+            warn!(
+                "mapping file {} is {} days old, newly added shows may not map. download a fresh \
+                 copy from {}",
+                mapping_path,
+                age.as_secs() / (24 * 60 * 60),
+                url
+            );
+            stale.push(mapping_path.to_string());
+        }
+    }
+    stale
+}
+
+// a missing mapping file is the usual first run problem, so say where to get it
+fn open_mapping(mapping_path: &str, url: &str) -> Result<File> {
+    File::open(mapping_path).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => anyhow!(
+            "mapping file {} not found, download it from {} (the docker image already includes it)",
+            mapping_path,
+            url
+        ),
+        _ => anyhow!("unable to open mapping file {}: {}", mapping_path, e),
+    })
+}
+
+// only ranged mappings of regular (anidb season 1) episodes can be used to find a season
+fn season_ranges(mappings: Vec<SeasonMapping>) -> Vec<SeasonRange> {
+    mappings
+        .into_iter()
+        .filter(|mapping| mapping.anidbseason == "1")
+        .filter_map(|mapping| {
+            Some(SeasonRange {
+                tvdb_season: mapping.tvdbseason.parse().ok()?,
+                start: mapping.start?.parse().ok()?,
+                end: mapping.end?.parse().ok()?,
+                offset: mapping
+                    .offset
+                    .map_or(Some(0), |offset| offset.parse().ok())?,
+            })
         })
-        .ok_or(anyhow!("unable to map anidb id to mal id"))
+        .collect()
 }
 
 #[cfg(test)]
@@ -69,13 +724,464 @@ mod tests {
 
     #[test]
     fn test_tvdb_id_to_mal_id() -> Result<(), anyhow::Error> {
-        let mal_id = tvdb_id_to_mal_id(
-            80644,
-            2,
+        let mapping_table = MappingTable::load(
+            "tests/fixtures/tvdb-to-anidb.xml",
+            "tests/fixtures/anidb-to-mal.json",
+            None,
+            IdSource::UserDataKey,
+        )?;
+        let resolved = mapping_table.resolve(80644, 2, 1)?;
+        assert_eq!(resolved.mal_id, 4181);
+        Ok(())
+    }
+
+    #[test]
+    fn test_single_hops() -> Result<(), anyhow::Error> {
+        assert_eq!(
+            tvdb_id_to_anidb_id("tests/fixtures/tvdb-to-anidb.xml", 80644, 2)?,
+            vec![5841]
+        );
+        // split seasons and explicitly mapped seasons are found too
+        let seasons = "tests/fixtures/tvdb-to-anidb-seasons.xml";
+        assert_eq!(tvdb_id_to_anidb_id(seasons, 100, 1)?, vec![1001, 1002]);
+        assert_eq!(tvdb_id_to_anidb_id(seasons, 200, 1)?, vec![2001]);
+        let e = tvdb_id_to_anidb_id(seasons, 300, 2).unwrap_err();
+        assert_eq!(e.downcast_ref(), Some(&MappingError::NoAnidbEntry));
+
+        assert_eq!(
+            anidb_id_to_mal_id("tests/fixtures/anidb-to-mal.json", 5841)?,
+            4181
+        );
+        let e = anidb_id_to_mal_id("tests/fixtures/anidb-to-mal.json", 1).unwrap_err();
+        assert_eq!(
+            e.downcast_ref(),
+            Some(&MappingError::NoMalEntry(IdSource::AniDb))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_consolidated() -> Result<(), anyhow::Error> {
+        let entries = build_consolidated(
+            "tests/fixtures/tvdb-to-anidb.xml",
+            "tests/fixtures/anidb-to-mal.json",
+        )?;
+        assert_eq!(
+            entries,
+            vec![
+                ConsolidatedEntry {
+                    tvdb_id: 80644,
+                    tvdb_season: 2,
+                    anidb_id: 5841,
+                    mal_id: Some(4181),
+                    episode_offset: 0,
+                    season_ranges: vec![],
+                },
+                // the film only known by its imdb id
+                ConsolidatedEntry {
+                    tvdb_id: -245429,
+                    tvdb_season: 1,
+                    anidb_id: 112,
+                    mal_id: Some(199),
+                    episode_offset: 0,
+                    season_ranges: vec![],
+                }
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_imdb_id() -> Result<(), anyhow::Error> {
+        assert_eq!(imdb_key("tt0245429"), Some(-245429));
+        assert_eq!(imdb_key("tt10872600"), Some(-10872600));
+        assert_eq!(imdb_key("245429"), None);
+
+        let mapping_table = MappingTable::from_entries(build_consolidated(
+            "tests/fixtures/tvdb-to-anidb.xml",
+            "tests/fixtures/anidb-to-mal.json",
+        )?);
+        let imdb_key = imdb_key("tt0245429").unwrap();
+        assert_eq!(mapping_table.resolve(imdb_key, 1, 1)?.mal_id, 199);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reload_if_changed() -> Result<(), anyhow::Error> {
+        let anidb_path = std::env::temp_dir().join("jellymal-test-reload.xml");
+        let anidb_path = anidb_path.to_str().unwrap();
+        std::fs::copy("tests/fixtures/tvdb-to-anidb.xml", anidb_path)?;
+        let mut mapping_table = MappingTable::load(
+            anidb_path,
+            "tests/fixtures/anidb-to-mal.json",
+            None,
+            IdSource::UserDataKey,
+        )?;
+
+        // nothing changed, so nothing is rebuilt
+        assert!(!mapping_table.reload_if_changed()?);
+        assert!(!mapping_table.reload_if_changed()?);
+
+        let anidb_file = File::options().write(true).open(anidb_path)?;
+        anidb_file.set_modified(SystemTime::now() + std::time::Duration::from_secs(60))?;
+        assert!(mapping_table.reload_if_changed()?);
+        assert!(!mapping_table.reload_if_changed()?);
+        assert_eq!(mapping_table.resolve(80644, 2, 1)?.mal_id, 4181);
+        std::fs::remove_file(anidb_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_warn_stale_mappings() -> Result<(), anyhow::Error> {
+        let anidb_path = std::env::temp_dir().join("jellymal-test-stale.xml");
+        let anidb_path = anidb_path.to_str().unwrap();
+        std::fs::copy("tests/fixtures/tvdb-to-anidb.xml", anidb_path)?;
+        let max_age = Duration::from_secs(30 * 24 * 60 * 60);
+        assert!(warn_stale_mappings(anidb_path, "tests/fixtures/missing.json", max_age).is_empty());
+
+        let anidb_file = File::options().write(true).open(anidb_path)?;
+        anidb_file.set_modified(SystemTime::now() - 2 * max_age)?;
+        assert_eq!(
+            warn_stale_mappings(anidb_path, "tests/fixtures/missing.json", max_age),
+            vec![anidb_path.to_string()]
+        );
+        std::fs::remove_file(anidb_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_prefers_consolidated() -> Result<(), anyhow::Error> {
+        let path = std::env::temp_dir().join("jellymal-test-consolidated.json");
+        let path = path.to_str().unwrap();
+        MappingTable::from_entries(vec![ConsolidatedEntry {
+            tvdb_id: 1,
+            tvdb_season: 1,
+            anidb_id: 2,
+            mal_id: Some(3),
+            episode_offset: 0,
+            season_ranges: vec![],
+        }])
+        .save(path)?;
+
+        // the source mappings don't exist, so this can only succeed through the consolidated file
+        let mapping_table = MappingTable::load(
+            "missing.xml",
+            "missing.json",
+            Some(path),
+            IdSource::UserDataKey,
+        )?;
+        assert_eq!(mapping_table.resolve(1, 1, 1)?.mal_id, 3);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_and_import() -> Result<(), anyhow::Error> {
+        let path = std::env::temp_dir().join("jellymal-test-export.json");
+        let path = path.to_str().unwrap();
+        let mapping_table = season_mapping_table()?;
+        mapping_table.save(path)?;
+
+        let imported = MappingTable::from_consolidated(path)?;
+        for (tvdb_id, season, episode_number) in [(100, 1, 3), (100, 1, 15), (200, 1, 4)] {
+            let expected = mapping_table.resolve(tvdb_id, season, episode_number)?;
+            assert_eq!(imported.resolve(tvdb_id, season, episode_number)?, expected);
+        }
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    fn season_mapping_table() -> Result<MappingTable> {
+        MappingTable::load(
+            "tests/fixtures/tvdb-to-anidb-seasons.xml",
+            "tests/fixtures/anidb-to-mal-seasons.json",
+            None,
+            IdSource::UserDataKey,
+        )
+    }
+
+    #[test]
+    fn test_resolve_absolute_episode() -> Result<(), anyhow::Error> {
+        let mapping_table = season_mapping_table()?;
+        let episode = |season_number: i32, number: i32, absolute_number: Option<i32>| Episode {
+            id: number.to_string(),
+            number,
+            number_end: None,
+            absolute_number,
+            name: "test_episode".to_string(),
+            season_number,
+            series_name: "series_400".to_string(),
+            series_id: "400".to_string(),
+            tvdb_id: 400,
+            watched: true,
+            played_percentage: None,
+            series_favorite: false,
+            season_finished: false,
+        };
+
+        // season 2 episode 4 is the 30th episode, in the second absolute entry
+        let resolved = mapping_table.resolve_episode(400, &episode(2, 4, Some(30)))?;
+        assert_eq!(
+            resolved,
+            ResolvedEpisode {
+                mal_id: 6,
+                episode_number: 4
+            }
+        );
+        let resolved = mapping_table.resolve_episode(400, &episode(1, 3, Some(3)))?;
+        assert_eq!(resolved.mal_id, 5);
+        // without an absolute number the season has to be mapped
+        assert!(mapping_table
+            .resolve_episode(400, &episode(2, 4, None))
+            .is_err());
+        // and series mapped by season ignore the absolute number
+        let resolved = mapping_table.resolve_episode(
+            100,
+            &Episode {
+                tvdb_id: 100,
+                ..episode(1, 15, Some(40))
+            },
+        )?;
+        assert_eq!(resolved.mal_id, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_episode_prefer_absolute() -> Result<(), anyhow::Error> {
+        let entry = |tvdb_season: i32, anidb_id: i32, mal_id: i32| ConsolidatedEntry {
+            tvdb_id: 500,
+            tvdb_season,
+            anidb_id,
+            mal_id: Some(mal_id),
+            episode_offset: 0,
+            season_ranges: vec![],
+        };
+        // the series is mapped both ways
+        let mapping_table = || {
+            MappingTable::from_entries(vec![
+                entry(1, 5001, 51),
+                entry(2, 5002, 52),
+                entry(ABSOLUTE_SEASON, 5000, 50),
+            ])
+        };
+        let episode = Episode {
+            id: "1".to_string(),
+            number: 4,
+            number_end: None,
+            absolute_number: Some(16),
+            name: "test_episode".to_string(),
+            season_number: 2,
+            series_name: "series_500".to_string(),
+            series_id: "500".to_string(),
+            tvdb_id: 500,
+            watched: true,
+            played_percentage: None,
+            series_favorite: false,
+            season_finished: false,
+        };
+
+        let resolved = mapping_table().resolve_episode(500, &episode)?;
+        assert_eq!((resolved.mal_id, resolved.episode_number), (50, 16));
+        let resolved = mapping_table()
+            .with_prefer_absolute(false)
+            .resolve_episode(500, &episode)?;
+        assert_eq!((resolved.mal_id, resolved.episode_number), (52, 4));
+
+        // a series only mapped by absolute numbers still falls back to them
+        let mapping_table = season_mapping_table()?.with_prefer_absolute(false);
+        let resolved = mapping_table.resolve_episode(
+            400,
+            &Episode {
+                tvdb_id: 400,
+                absolute_number: Some(30),
+                ..episode
+            },
+        )?;
+        assert_eq!(resolved.mal_id, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_split_season() -> Result<(), anyhow::Error> {
+        let mapping_table = season_mapping_table()?;
+        assert_eq!(
+            mapping_table.resolve(100, 1, 5)?,
+            ResolvedEpisode {
+                mal_id: 1,
+                episode_number: 5
+            }
+        );
+        // the second half of the season continues as its own entry
+        assert_eq!(
+            mapping_table.resolve(100, 1, 14)?,
+            ResolvedEpisode {
+                mal_id: 2,
+                episode_number: 2
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_mismatched_season() -> Result<(), anyhow::Error> {
+        let mapping_table = season_mapping_table()?;
+        // jellyfin calls it season 1, but the entry's default season is 2
+        assert_eq!(
+            mapping_table.resolve(200, 1, 3)?,
+            ResolvedEpisode {
+                mal_id: 3,
+                episode_number: 3
+            }
+        );
+        // no entry for season 3 at all, so it continues the season 1 entry, but only once the
+        // length of season 2 is known too
+        assert!(mapping_table.resolve(300, 3, 4).is_err());
+        let mapping_table =
+            mapping_table.with_season_lengths(HashMap::from([((300, 1), 12), ((300, 2), 13)]));
+        assert_eq!(
+            mapping_table.resolve(300, 3, 4)?,
+            ResolvedEpisode {
+                mal_id: 4,
+                episode_number: 29
+            }
+        );
+        assert!(mapping_table.resolve(400, 1, 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_id_sources() -> Result<(), anyhow::Error> {
+        let resolve = |id_source: IdSource, id: i32| -> Result<ResolvedEpisode> {
+            MappingTable::load(
+                "tests/fixtures/tvdb-to-anidb.xml",
+                "tests/fixtures/anidb-to-mal.json",
+                None,
+                id_source,
+            )?
+            .resolve(id, 2, 3)
+        };
+        let expected = ResolvedEpisode {
+            mal_id: 4181,
+            episode_number: 3,
+        };
+        assert_eq!(resolve(IdSource::UserDataKey, 80644)?, expected);
+        assert_eq!(resolve(IdSource::Tvdb, 80644)?, expected);
+        assert_eq!(resolve(IdSource::AniDb, 5841)?, expected);
+        assert_eq!(resolve(IdSource::AniList, 4181)?, expected);
+        // anidb and anilist ids are never looked up as tvdb ids
+        assert!(resolve(IdSource::AniDb, 80644).is_err());
+        assert!(resolve(IdSource::Tvdb, 5841).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_direct_mapping_skips_anidb_file() -> Result<(), anyhow::Error> {
+        let mapping_table = MappingTable::load(
+            "missing.xml",
+            "tests/fixtures/anidb-to-mal.json",
+            None,
+            IdSource::AniDb,
+        )?;
+        assert_eq!(mapping_table.resolve(5841, 1, 1)?.mal_id, 4181);
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_mapping_file() {
+        let error = MappingTable::load(
+            "missing.xml",
+            "tests/fixtures/anidb-to-mal.json",
+            None,
+            IdSource::UserDataKey,
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(error.contains("missing.xml"));
+        assert!(error.contains(ANIDB_MAPPING_URL));
+    }
+
+    #[test]
+    fn test_check_known_mappings() -> Result<(), anyhow::Error> {
+        let mapping_table = MappingTable::load(
             "tests/fixtures/tvdb-to-anidb.xml",
             "tests/fixtures/anidb-to-mal.json",
+            None,
+            IdSource::Tvdb,
         )?;
-        assert_eq!(mal_id, 4181);
+        // the fixtures only hold clannad
+        let results = mapping_table.check_known_mappings();
+        assert_eq!(results.len(), KNOWN_MAPPINGS.len());
+        for (known, result) in results {
+            assert_eq!(result.is_ok(), known.tvdb_id == 80644, "{}", known.name);
+        }
+
+        let mapping_table = MappingTable::from_entries(vec![ConsolidatedEntry {
+            tvdb_id: 76885,
+            tvdb_season: 1,
+            anidb_id: 23,
+            mal_id: Some(2),
+            episode_offset: 0,
+            season_ranges: vec![],
+        }]);
+        let error = mapping_table.check_known_mappings()[0]
+            .1
+            .as_ref()
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(error, "expected mal id 1, got 2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_mapping_stats() -> Result<(), anyhow::Error> {
+        let mapping_table = MappingTable::from_entries(vec![
+            ConsolidatedEntry {
+                tvdb_id: 1,
+                tvdb_season: 1,
+                anidb_id: 10,
+                mal_id: Some(100),
+                episode_offset: 0,
+                season_ranges: vec![],
+            },
+            ConsolidatedEntry {
+                tvdb_id: 2,
+                tvdb_season: 1,
+                anidb_id: 20,
+                mal_id: None,
+                episode_offset: 0,
+                season_ranges: vec![],
+            },
+        ]);
+        let episode = |tvdb_id: i32, number: i32| Episode {
+            id: number.to_string(),
+            number,
+            number_end: None,
+            absolute_number: None,
+            name: "test_episode".to_string(),
+            season_number: 1,
+            series_name: format!("series_{}", tvdb_id),
+            series_id: tvdb_id.to_string(),
+            tvdb_id,
+            watched: false,
+            played_percentage: None,
+            series_favorite: false,
+            season_finished: false,
+        };
+
+        let stats =
+            mapping_table.stats(&[episode(1, 1), episode(1, 2), episode(2, 1), episode(3, 1)]);
+        assert_eq!(stats.mapped, 1);
+        assert_eq!(stats.unmapped_anidb, vec![("series_2".to_string(), 1)]);
+        assert_eq!(stats.unmapped_tvdb, vec![("series_3".to_string(), 1)]);
+        assert_eq!(stats.total(), 3);
+
+        let error = mapping_table.resolve(3, 1, 1).err().unwrap();
+        assert_eq!(
+            error.downcast_ref::<MappingError>(),
+            Some(&MappingError::NoAnidbEntry)
+        );
         Ok(())
     }
 }
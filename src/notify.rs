@@ -0,0 +1,105 @@
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{FailedSeries, SeriesChange, SyncReport};
+
+/// The body posted to the webhook. `content` and `text` hold the same message, since discord
+/// reads the former and slack the latter.
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    content: String,
+    text: String,
+    updated: &'a [SeriesChange],
+    failed: &'a [FailedSeries],
+}
+
+fn message(report: &SyncReport) -> String {
+    let mut message = format!(
+        "jellymal sync complete: {} updated, {} skipped, {} failed",
+        report.updated.len(),
+        report.skipped.len(),
+        report.failed.len()
+    );
+    for change in &report.updated {
+        message.push_str(&format!(
+            "\n- {} season {}: episode {} -> {}",
+            change.series_name, change.season, change.old_episode, change.new_episode
+        ));
+    }
+    for failed in &report.failed {
+        message.push_str(&format!(
+            "\n- {} season {} failed: {}",
+            failed.series_name, failed.season, failed.error
+        ));
+    }
+    message
+}
+
+/// Posts a summary of the run to a webhook, e.g. a discord or slack channel.
+pub async fn notify_webhook(
+    client: &reqwest::Client,
+    webhook_url: &str,
+    report: &SyncReport,
+) -> Result<()> {
+    let message = message(report);
+    let payload = WebhookPayload {
+        content: message.clone(),
+        text: message,
+        updated: &report.updated,
+        failed: &report.failed,
+    };
+    client
+        .post(webhook_url)
+        .json(&payload)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    use crate::mal::ListStatus;
+
+    #[tokio::test]
+    async fn test_notify_webhook() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let report = SyncReport {
+            updated: vec![SeriesChange {
+                series_name: "Clannad".to_string(),
+                tvdb_id: 80644,
+                season: 2,
+                mal_id: 4181,
+                old_episode: 3,
+                new_episode: 5,
+                status: ListStatus::Watching,
+            }],
+            ..Default::default()
+        };
+        Mock::given(method("POST"))
+            .and(path("/webhook"))
+            .and(body_partial_json(json!({
+                "content": "jellymal sync complete: 1 updated, 0 skipped, 0 failed\n\
+                            - Clannad season 2: episode 3 -> 5",
+                "updated": [{ "mal_id": 4181, "new_episode": 5 }],
+                "failed": []
+            })))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        notify_webhook(
+            &reqwest::Client::new(),
+            &format!("{}/webhook", server.uri()),
+            &report,
+        )
+        .await?;
+        Ok(())
+    }
+}
@@ -1,24 +1,129 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use chrono::Utc;
 use log::debug;
 use oauth2::basic::{BasicClient, BasicTokenType};
 use oauth2::reqwest::async_http_client;
 use oauth2::{
     AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EmptyExtraTokenFields,
-    PkceCodeChallenge, RedirectUrl, RefreshToken, Scope, StandardTokenResponse, TokenResponse,
-    TokenUrl,
+    HttpRequest, HttpResponse, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RefreshToken,
+    Scope, StandardTokenResponse, TokenResponse, TokenUrl,
 };
 use serde::{Deserialize, Serialize};
 use std::fs::File;
-use std::io::{self, BufReader, BufWriter, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write};
 use std::path::Path;
+use std::time::Duration;
 use url::Url;
 
-#[derive(Serialize, Deserialize)]
+use crate::backoff::{Backoff, Failure};
+
+// multiplied by the attempt number, so the waits grow a little each time
+pub const TOKEN_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ClientToken {
     pub refresh_token: String,
     pub access_token: String,
     pub expiration_date: i64,
+    // when the token was last issued or refreshed, in milliseconds since the epoch. 0 for
+    // tokens saved before this was tracked
+    #[serde(default)]
+    pub last_refreshed: i64,
+}
+
+/// The MAL client settings and token, kept together in one file. Every field is optional, so
+/// that any of them can be set in the environment instead.
+#[derive(Serialize, Deserialize, Default)]
+pub struct SecretsFile {
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub redirect_url: Option<String>,
+    pub token: Option<ClientToken>,
+}
+
+impl SecretsFile {
+    pub fn load(path: &str) -> Result<SecretsFile> {
+        let file = File::open(path).with_context(|| format!("unable to open {}", path))?;
+        serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("unable to parse the secrets file {}", path))
+    }
+}
+
+// only a few characters of each secret are shown, enough to tell tokens apart
+const REDACTED_PREFIX_LEN: usize = 4;
+
+fn redact(secret: &str) -> String {
+    // a prefix of a short secret would give away most of it
+    if secret.chars().count() < 2 * REDACTED_PREFIX_LEN {
+        return "...".to_string();
+    }
+    let prefix: String = secret.chars().take(REDACTED_PREFIX_LEN).collect();
+    format!("{}...", prefix)
+}
+
+// written by hand so that the secrets can't end up in the logs
+impl std::fmt::Debug for ClientToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientToken")
+            .field("refresh_token", &redact(&self.refresh_token))
+            .field("access_token", &redact(&self.access_token))
+            .field("expiration_date", &self.expiration_date)
+            .field("last_refreshed", &self.last_refreshed)
+            .finish()
+    }
+}
+
+/// How a stored token is reused.
+pub struct TokenOptions {
+    // ignore any stored token and always run the authorization flow
+    pub reauth: bool,
+    // refresh tokens last refreshed longer ago than this, however far they are from expiring
+    pub always_refresh_after: Option<Duration>,
+    // the wait between attempts of a failed token request
+    pub retry_backoff: Backoff,
+    // the token path is a `SecretsFile`, and the token is only its `token`
+    pub in_secrets_file: bool,
+}
+
+impl Default for TokenOptions {
+    fn default() -> TokenOptions {
+        TokenOptions {
+            reauth: false,
+            always_refresh_after: None,
+            retry_backoff: Backoff::new(TOKEN_RETRY_BACKOFF),
+            in_secrets_file: false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum TokenAction {
+    Reuse,
+    Refresh,
+    Initialize,
+}
+
+// the token is refreshed this long before it expires
+const EXPIRY_BUFFER_MILLIS: i64 = 1000 * 60 * 60 * 24 * 5;
+
+fn token_action(
+    client_token: &ClientToken,
+    now_millis: i64,
+    options: &TokenOptions,
+) -> TokenAction {
+    if client_token.expiration_date <= now_millis {
+        return TokenAction::Initialize;
+    }
+    if client_token.expiration_date - now_millis <= EXPIRY_BUFFER_MILLIS {
+        return TokenAction::Refresh;
+    }
+    let refreshed_long_ago = options
+        .always_refresh_after
+        .is_some_and(|age| now_millis - client_token.last_refreshed >= age.as_millis() as i64);
+    if refreshed_long_ago {
+        return TokenAction::Refresh;
+    }
+    TokenAction::Reuse
 }
 
 impl TryFrom<StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>> for ClientToken {
@@ -38,10 +143,32 @@ impl TryFrom<StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>> for C
                 .to_string(),
             access_token: token_response.access_token().secret().to_string(),
             expiration_date: current_time_millis + expires_in.as_millis() as i64,
+            last_refreshed: current_time_millis,
         })
     }
 }
 
+// retries token requests that failed because of the network, the server or a rate limit.
+// anything else, like an invalid grant, is a real answer and is returned straight away
+async fn retrying_http_client(
+    request: HttpRequest,
+    retry_backoff: &Backoff,
+) -> Result<HttpResponse, oauth2::reqwest::Error<reqwest::Error>> {
+    retry_backoff
+        .retry(
+            || async_http_client(request.clone()),
+            |result| match result {
+                Ok(response) if response.status_code.as_u16() == 429 => Some(Failure::RateLimited),
+                Ok(response) if response.status_code.is_server_error() => {
+                    Some(Failure::ServerError)
+                }
+                Ok(_) => None,
+                Err(_) => Some(Failure::Connection),
+            },
+        )
+        .await
+}
+
 fn get_query_param(
     param: &str,
     query_pairs: url::form_urlencoded::Parse<'_>,
@@ -58,56 +185,164 @@ fn get_query_param(
     Ok(result)
 }
 
-pub async fn initialize_token(
+fn read_redirect_url(mut input: impl BufRead) -> Result<String> {
+    let mut redirect_url = String::new();
+    if input.read_line(&mut redirect_url)? == 0 {
+        return Err(anyhow!(
+            "stdin was closed before the redirect url was entered, run --init-auth in a terminal"
+        ));
+    }
+    Ok(redirect_url)
+}
+
+/// The secrets of an authorization url that hasn't been answered yet, kept so that the redirect
+/// url can be pasted into a later run.
+#[derive(Serialize, Deserialize)]
+struct PendingAuth {
+    pkce_verifier: String,
+    csrf_token: String,
+}
+
+/// Where the pending authorization is kept, next to the token it's going to become.
+pub fn pending_auth_path(token_path: &str) -> String {
+    format!("{}.pending", token_path)
+}
+
+fn oauth_client(
     client_id: &str,
     client_secret: &str,
     auth_url: &str,
     token_url: &str,
     redirect_url: &str,
-) -> Result<ClientToken> {
-    // initialize the oauth client
-    let client = BasicClient::new(
+) -> Result<BasicClient> {
+    Ok(BasicClient::new(
         ClientId::new(client_id.to_string()),
         Some(ClientSecret::new(client_secret.to_string())),
         AuthUrl::new(auth_url.to_string())?,
         Some(TokenUrl::new(token_url.to_string())?),
     )
-    .set_redirect_uri(RedirectUrl::new(redirect_url.to_string())?);
+    .set_redirect_uri(RedirectUrl::new(redirect_url.to_string())?))
+}
 
+// builds the authorization url, saving its verifier so the exchange can happen in another run
+fn start_auth(client: &BasicClient, pending_auth_path: &str) -> Result<Url> {
     // generate a challenge - mal only supports plain
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_plain();
 
     // get the authorization url
-    let (auth_url, _) = client
+    let (auth_url, csrf_token) = client
         .authorize_url(CsrfToken::new_random)
         .add_scope(Scope::new("read".to_string()))
         .add_scope(Scope::new("write".to_string()))
         .set_pkce_challenge(pkce_challenge)
         .url();
 
-    // have the user go to the authorization url
-    println!("Open this authorization url in a browser: {}", auth_url);
+    let pending_auth = PendingAuth {
+        pkce_verifier: pkce_verifier.secret().to_string(),
+        csrf_token: csrf_token.secret().to_string(),
+    };
+    serde_json::to_writer(File::create(pending_auth_path)?, &pending_auth)?;
+    Ok(auth_url)
+}
+
+// exchanges the code in the redirect url for a token, removing the pending authorization once
+// it's been used
+async fn finish_auth(
+    client: &BasicClient,
+    pending_auth_path: &str,
+    redirect_url: &str,
+    retry_backoff: &Backoff,
+) -> Result<ClientToken> {
+    let pending_auth: PendingAuth = match File::open(pending_auth_path) {
+        Ok(f) => serde_json::from_reader(BufReader::new(f))?,
+        Err(_) => {
+            return Err(anyhow!(
+                "no authorization is waiting to be completed, run --init-auth first"
+            ))
+        }
+    };
 
     // parse the authorization code from the redirect url
-    print!("Copy the redirect url here: ");
-    io::stdout().flush()?;
-    let mut redirect_url = String::new();
-    io::stdin().read_line(&mut redirect_url)?;
-    let parsed_url = Url::parse(&redirect_url)?;
-    let query_pairs: url::form_urlencoded::Parse<'_> = parsed_url.query_pairs();
-    let code: String = get_query_param("code", query_pairs)?;
+    let parsed_url = Url::parse(redirect_url.trim())?;
+    let code: String = get_query_param("code", parsed_url.query_pairs())?;
+    // a redirect from another authorization url won't match its verifier either
+    if let Ok(state) = get_query_param("state", parsed_url.query_pairs()) {
+        if state != pending_auth.csrf_token {
+            return Err(anyhow!(
+                "the redirect url belongs to a different authorization, run --init-auth again"
+            ));
+        }
+    }
 
     // exchange the code for a token
     let token_result: StandardTokenResponse<EmptyExtraTokenFields, oauth2::basic::BasicTokenType> =
         client
             .exchange_code(AuthorizationCode::new(code))
-            .set_pkce_verifier(pkce_verifier)
-            .request_async(async_http_client)
+            .set_pkce_verifier(PkceCodeVerifier::new(pending_auth.pkce_verifier))
+            .request_async(|request| retrying_http_client(request, retry_backoff))
             .await?;
+    std::fs::remove_file(pending_auth_path)?;
+
+    ClientToken::try_from(token_result)
+}
+
+pub async fn initialize_token(
+    client_id: &str,
+    client_secret: &str,
+    auth_url: &str,
+    token_url: &str,
+    redirect_url: &str,
+    pending_auth_path: &str,
+    retry_backoff: &Backoff,
+) -> Result<ClientToken> {
+    let client = oauth_client(client_id, client_secret, auth_url, token_url, redirect_url)?;
+    let auth_url = start_auth(&client, pending_auth_path)?;
+
+    // have the user go to the authorization url
+    println!("Open this authorization url in a browser: {}", auth_url);
+
+    // without a terminal nobody can answer the prompt, so it would wait forever
+    if !io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "interactive auth required, run --complete-auth with the redirect url to finish it"
+        ));
+    }
+
+    print!("Copy the redirect url here: ");
+    io::stdout().flush()?;
+    let redirect_url = read_redirect_url(io::stdin().lock())?;
+    let client_token =
+        finish_auth(&client, pending_auth_path, &redirect_url, retry_backoff).await?;
 
     println!("Token initialized! Please close the process (Ctrl-C)...");
 
-    ClientToken::try_from(token_result)
+    Ok(client_token)
+}
+
+/// Finishes an authorization started by an earlier run, using the redirect url the browser was
+/// sent to, and saves the token.
+#[allow(clippy::too_many_arguments)]
+pub async fn complete_auth(
+    client_id: &str,
+    client_secret: &str,
+    auth_url: &str,
+    token_url: &str,
+    redirect_url: &str,
+    token_path: &str,
+    in_secrets_file: bool,
+    received_redirect_url: &str,
+    retry_backoff: &Backoff,
+) -> Result<ClientToken> {
+    let client = oauth_client(client_id, client_secret, auth_url, token_url, redirect_url)?;
+    let client_token = finish_auth(
+        &client,
+        &pending_auth_path(token_path),
+        received_redirect_url,
+        retry_backoff,
+    )
+    .await?;
+    store_token(token_path, &client_token, in_secrets_file)?;
+    Ok(client_token)
 }
 
 pub async fn refresh_token(
@@ -116,6 +351,7 @@ pub async fn refresh_token(
     auth_url: &str,
     token_url: &str,
     client_token: ClientToken,
+    retry_backoff: &Backoff,
 ) -> Result<ClientToken> {
     // initialize the oauth client
     let client = BasicClient::new(
@@ -129,12 +365,14 @@ pub async fn refresh_token(
     let token = RefreshToken::new(client_token.refresh_token);
     let token_result = client
         .exchange_refresh_token(&token)
-        .request_async(async_http_client)
+        .request_async(|request| retrying_http_client(request, retry_backoff))
         .await?;
 
     ClientToken::try_from(token_result)
 }
 
+/// Loads the token stored at `token_path`, refreshing it or running the authorization flow again
+/// as needed.
 pub async fn load_or_refresh_token(
     client_id: &str,
     client_secret: &str,
@@ -142,40 +380,376 @@ pub async fn load_or_refresh_token(
     token_url: &str,
     redirect_url: &str,
     token_path: &str,
+    options: &TokenOptions,
 ) -> Result<ClientToken> {
-    let mut client_token: ClientToken;
-    debug!("loading the token from {}", token_path.to_string());
+    debug!("loading the token from {}", token_path);
+    let stored_token = match options.reauth {
+        true => None,
+        false => load_token(token_path, options.in_secrets_file)?,
+    };
+    let mut client_token = match stored_token {
+        // reuse the existing token stored in the token file
+        Some(client_token) => client_token,
+        None => {
+            initialize_token(
+                client_id,
+                client_secret,
+                auth_url,
+                token_url,
+                redirect_url,
+                &pending_auth_path(token_path),
+                &options.retry_backoff,
+            )
+            .await?
+        }
+    };
+
+    match token_action(&client_token, Utc::now().timestamp_millis(), options) {
+        // the client token has expired! generate a new one from scratch
+        TokenAction::Initialize => {
+            debug!("generating a new token from scratch");
+            client_token = initialize_token(
+                client_id,
+                client_secret,
+                auth_url,
+                token_url,
+                redirect_url,
+                &pending_auth_path(token_path),
+                &options.retry_backoff,
+            )
+            .await?;
+        }
+        // the client token is close to expiration, or hasn't been refreshed in a while
+        TokenAction::Refresh => {
+            debug!("refreshing the token");
+            client_token = refresh_token(
+                client_id,
+                client_secret,
+                auth_url,
+                token_url,
+                client_token,
+                &options.retry_backoff,
+            )
+            .await?;
+        }
+        TokenAction::Reuse => {}
+    }
+
+    // save the client token to disk so that it can be reused
+    debug!("persisting the token to disk at {}", token_path);
+    store_token(token_path, &client_token, options.in_secrets_file)?;
+
+    Ok(client_token)
+}
+
+// a token file that doesn't exist yet, or a secrets file without a token, holds no token
+fn load_token(token_path: &str, in_secrets_file: bool) -> Result<Option<ClientToken>> {
     if !Path::new(token_path).exists() {
-        client_token =
-            initialize_token(client_id, client_secret, auth_url, token_url, redirect_url).await?;
+        return Ok(None);
+    }
+    if in_secrets_file {
+        return Ok(SecretsFile::load(token_path)?.token);
+    }
+    let reader = BufReader::new(File::open(token_path)?);
+    Ok(Some(serde_json::from_reader(reader)?))
+}
+
+// the rest of a secrets file is kept as it is, only the token is replaced
+fn store_token(token_path: &str, client_token: &ClientToken, in_secrets_file: bool) -> Result<()> {
+    if !in_secrets_file {
+        return save_token(token_path, client_token);
+    }
+    let mut secrets = match Path::new(token_path).exists() {
+        true => SecretsFile::load(token_path)?,
+        false => SecretsFile::default(),
+    };
+    secrets.token = Some(client_token.clone());
+    write_json(token_path, &secrets)
+}
+
+fn save_token(token_path: &str, client_token: &ClientToken) -> Result<()> {
+    write_json(token_path, client_token)
+}
+
+// writes to a temporary file first and renames it over the old one, so that a failed write
+// never leaves a truncated token behind
+fn write_json<T: Serialize>(path: &str, value: &T) -> Result<()> {
+    let temp_path = format!("{}.tmp", path);
+    let file = File::create(&temp_path)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, value)?;
+    writer.into_inner()?.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::matchers::{body_string_contains, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    fn expired_token() -> ClientToken {
+        ClientToken {
+            refresh_token: "refresh".to_string(),
+            access_token: "access".to_string(),
+            expiration_date: 0,
+            last_refreshed: 0,
+        }
     }
-    // reuse the existing token stored in the token file
-    else {
-        let file = File::open(token_path)?;
-        let reader = BufReader::new(file);
-        client_token = serde_json::from_reader(reader)?;
+
+    #[test]
+    fn test_client_token_debug_is_redacted() {
+        let token = ClientToken {
+            refresh_token: "refresh-secret".to_string(),
+            access_token: "access-secret".to_string(),
+            ..expired_token()
+        };
+        let debug = format!("{:?}", token);
+        assert!(!debug.contains("refresh-secret") && !debug.contains("access-secret"));
+        assert!(debug.contains("\"refr...\"") && debug.contains("\"acce...\""));
     }
 
-    // the client token has expired! generate a new one from scratch
-    let current_time_millis = Utc::now().timestamp_millis();
-    let five_days_millis = 1000 * 60 * 60 * 24 * 5;
-    if client_token.expiration_date <= current_time_millis {
-        debug!("generating a new token from scratch");
-        client_token =
-            initialize_token(client_id, client_secret, auth_url, token_url, redirect_url).await?;
+    #[test]
+    fn test_token_action() {
+        let day = 1000 * 60 * 60 * 24;
+        let now = 100 * day;
+        let token = |expires_in_days: i64, refreshed_days_ago: i64| ClientToken {
+            expiration_date: now + expires_in_days * day,
+            last_refreshed: now - refreshed_days_ago * day,
+            ..expired_token()
+        };
+        let options = TokenOptions::default();
+        assert_eq!(
+            token_action(&token(-1, 30), now, &options),
+            TokenAction::Initialize
+        );
+        assert_eq!(
+            token_action(&token(3, 28), now, &options),
+            TokenAction::Refresh
+        );
+        assert_eq!(
+            token_action(&token(20, 10), now, &options),
+            TokenAction::Reuse
+        );
+
+        // refreshed on age alone, however far the token is from expiring
+        let options = TokenOptions {
+            always_refresh_after: Some(Duration::from_secs(60 * 60 * 24 * 7)),
+            ..Default::default()
+        };
+        assert_eq!(
+            token_action(&token(20, 10), now, &options),
+            TokenAction::Refresh
+        );
+        assert_eq!(
+            token_action(&token(20, 2), now, &options),
+            TokenAction::Reuse
+        );
     }
-    // the client token is close to expiration. refresh it
-    else if current_time_millis - client_token.expiration_date >= five_days_millis {
-        debug!("refreshing the token");
-        client_token =
-            refresh_token(client_id, client_secret, auth_url, token_url, client_token).await?;
+
+    #[test]
+    fn test_read_redirect_url_closed_stdin() -> anyhow::Result<()> {
+        assert!(read_redirect_url(io::empty()).is_err());
+        assert_eq!(
+            read_redirect_url("http://localhost/?code=abc\n".as_bytes())?,
+            "http://localhost/?code=abc\n"
+        );
+        Ok(())
     }
 
-    // save the client token to disk so that it can be reused
-    debug!("persisting the token to disk at {}", token_path);
-    let file = File::create(token_path)?;
-    let writer = BufWriter::new(file);
-    serde_json::to_writer_pretty(writer, &client_token)?;
+    #[test]
+    fn test_save_token_replaces_old_token() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join("jellymal-test-token.json");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "old token")?;
 
-    Ok(client_token)
+        save_token(path, &expired_token())?;
+        let saved: ClientToken = serde_json::from_reader(File::open(path)?)?;
+        assert_eq!(saved.access_token, "access");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_secrets_file_round_trip() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join("jellymal-test-secrets.json");
+        let path = path.to_str().unwrap();
+        let token = ClientToken {
+            expiration_date: Utc::now().timestamp_millis() + 1000 * 60 * 60 * 24 * 30,
+            last_refreshed: Utc::now().timestamp_millis(),
+            ..expired_token()
+        };
+        write_json(
+            path,
+            &SecretsFile {
+                client_id: Some("client".to_string()),
+                client_secret: Some("secret".to_string()),
+                redirect_url: None,
+                token: Some(token),
+            },
+        )?;
+
+        // a fresh token is reused as is, no request has to be made
+        let options = TokenOptions {
+            in_secrets_file: true,
+            ..Default::default()
+        };
+        let client_token = load_or_refresh_token(
+            "client",
+            "secret",
+            "http://localhost/authorize",
+            "http://localhost/token",
+            "http://localhost/callback",
+            path,
+            &options,
+        )
+        .await?;
+        assert_eq!(client_token.access_token, "access");
+
+        // saving the token keeps the rest of the file
+        let secrets = SecretsFile::load(path)?;
+        assert_eq!(secrets.client_id.as_deref(), Some("client"));
+        assert_eq!(secrets.client_secret.as_deref(), Some("secret"));
+        assert_eq!(secrets.redirect_url, None);
+        assert_eq!(
+            secrets.token.map(|token| token.refresh_token).as_deref(),
+            Some("refresh")
+        );
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_complete_auth_in_a_second_run() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        let token_path = std::env::temp_dir().join("jellymal-test-complete-auth.json");
+        let token_path = token_path.to_str().unwrap();
+        let client = oauth_client(
+            "client",
+            "secret",
+            &format!("{}/authorize", server.uri()),
+            &format!("{}/token", server.uri()),
+            "http://localhost/callback",
+        )?;
+
+        // the first run only prints the url, leaving the verifier behind
+        let auth_url = start_auth(&client, &pending_auth_path(token_path))?;
+        let pending_auth: PendingAuth =
+            serde_json::from_reader(File::open(pending_auth_path(token_path))?)?;
+        let state = get_query_param("state", auth_url.query_pairs())?;
+        assert_eq!(state, pending_auth.csrf_token);
+
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("code=abc"))
+            .and(body_string_contains(format!(
+                "code_verifier={}",
+                pending_auth.pkce_verifier
+            )))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "access",
+                "refresh_token": "refresh",
+                "token_type": "bearer",
+                "expires_in": 3600
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        // a redirect from some other authorization is turned away
+        let uri = server.uri();
+        let complete = |redirect: String| {
+            let uri = uri.clone();
+            async move {
+                complete_auth(
+                    "client",
+                    "secret",
+                    &format!("{}/authorize", uri),
+                    &format!("{}/token", uri),
+                    "http://localhost/callback",
+                    token_path,
+                    false,
+                    &redirect,
+                    &Backoff::new(TOKEN_RETRY_BACKOFF),
+                )
+                .await
+            }
+        };
+        assert!(
+            complete("http://localhost/callback?code=abc&state=other".to_string())
+                .await
+                .is_err()
+        );
+
+        let client_token = complete(format!(
+            "http://localhost/callback?code=abc&state={}",
+            state
+        ))
+        .await?;
+        assert_eq!(client_token.access_token, "access");
+        let saved: ClientToken = serde_json::from_reader(File::open(token_path)?)?;
+        assert_eq!(saved.refresh_token, "refresh");
+        assert!(!Path::new(&pending_auth_path(token_path)).exists());
+        std::fs::remove_file(token_path)?;
+        Ok(())
+    }
+
+    async fn refresh(server: &MockServer) -> Result<ClientToken> {
+        refresh_token(
+            "client",
+            "secret",
+            &format!("{}/authorize", server.uri()),
+            &format!("{}/token", server.uri()),
+            expired_token(),
+            &Backoff::new(TOKEN_RETRY_BACKOFF),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_retries_server_errors() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "new-access",
+                "token_type": "bearer",
+                "expires_in": 3600,
+                "refresh_token": "new-refresh"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let token = refresh(&server).await?;
+        assert_eq!(token.access_token, "new-access");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refresh_token_invalid_grant() -> anyhow::Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(
+                ResponseTemplate::new(400).set_body_json(json!({ "error": "invalid_grant" })),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        assert!(refresh(&server).await.is_err());
+        Ok(())
+    }
 }
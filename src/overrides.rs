@@ -0,0 +1,218 @@
+use std::{fs::File, io::BufReader};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::mal::ListStatus;
+use crate::mapping::ResolvedEpisode;
+
+/// Part of a season that mal lists as its own entry, e.g. the second cour of a split season.
+#[derive(Serialize, Deserialize)]
+pub struct Cour {
+    // the first and last jellyfin episode in the cour
+    pub episode_range: (i32, i32),
+    pub mal_id: i32,
+    // subtracted from the jellyfin episode number, e.g. 12 when mal starts the cour at 1
+    pub offset: i32,
+}
+
+/// User provided corrections for a single series, or a single season of it.
+#[derive(Serialize, Deserialize)]
+pub struct SeriesOverride {
+    pub tvdb_id: i32,
+    // applies to every season when unset
+    pub season: Option<i32>,
+    // for releases where one jellyfin file holds several mal episodes
+    pub episodes_per_file: Option<i32>,
+    pub episode_offset: Option<i32>,
+    // routes parts of the season to their own mal entries, instead of the mapping's
+    #[serde(default)]
+    pub cours: Vec<Cour>,
+    // always sent as the series' status, e.g. to keep a rewatch as watching
+    pub status: Option<ListStatus>,
+    // keeps the series watching even once every episode has been watched
+    #[serde(default)]
+    pub no_auto_complete: bool,
+}
+
+impl SeriesOverride {
+    /// Converts a watched episode count into the count reported to mal.
+    pub fn adjust_episode_number(&self, episode_number: i32) -> i32 {
+        episode_number * self.episodes_per_file.unwrap_or(1) + self.episode_offset.unwrap_or(0)
+    }
+
+    /// Picks the status sent to mal, in place of the one worked out from jellyfin.
+    pub fn adjust_status(&self, status: ListStatus) -> ListStatus {
+        match (self.status, status) {
+            (Some(pinned), _) => pinned,
+            (None, ListStatus::Completed) if self.no_auto_complete => ListStatus::Watching,
+            (None, status) => status,
+        }
+    }
+
+    /// Resolves an episode by the cour it's in, if any of them holds it.
+    pub fn resolve_cour(&self, episode_number: i32) -> Option<ResolvedEpisode> {
+        self.cours
+            .iter()
+            .find(|cour| (cour.episode_range.0..=cour.episode_range.1).contains(&episode_number))
+            .map(|cour| ResolvedEpisode {
+                mal_id: cour.mal_id,
+                episode_number: episode_number - cour.offset,
+            })
+    }
+}
+
+#[derive(Default)]
+pub struct Overrides {
+    entries: Vec<SeriesOverride>,
+}
+
+impl Overrides {
+    pub fn load(overrides_path: &str) -> Result<Overrides> {
+        let f = File::open(overrides_path)?;
+        let reader = BufReader::new(f);
+        let entries: Vec<SeriesOverride> = serde_json::from_reader(reader)?;
+        Overrides::from_entries(entries)
+    }
+
+    pub fn from_entries(entries: Vec<SeriesOverride>) -> Result<Overrides> {
+        for entry in entries.iter() {
+            if let Some(episodes_per_file) = entry.episodes_per_file {
+                if episodes_per_file <= 0 {
+                    return Err(anyhow!(
+                        "episodes_per_file for tvdb id {} must be positive, got {}",
+                        entry.tvdb_id,
+                        episodes_per_file
+                    ));
+                }
+            }
+            for cour in entry.cours.iter() {
+                let (first, last) = cour.episode_range;
+                if first > last {
+                    return Err(anyhow!(
+                        "episode_range of a cour for tvdb id {} ends before it starts, got {}-{}",
+                        entry.tvdb_id,
+                        first,
+                        last
+                    ));
+                }
+            }
+        }
+        Ok(Overrides { entries })
+    }
+
+    /// Gets the override for a season, preferring one for that specific season over one for the
+    /// whole series.
+    pub fn get(&self, tvdb_id: i32, season: i32) -> Option<&SeriesOverride> {
+        let mut series_override = None;
+        for entry in self.entries.iter().filter(|entry| entry.tvdb_id == tvdb_id) {
+            match entry.season {
+                Some(entry_season) if entry_season == season => return Some(entry),
+                None if series_override.is_none() => series_override = Some(entry),
+                _ => {}
+            }
+        }
+        series_override
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_episodes_per_file() -> Result<(), anyhow::Error> {
+        let overrides = Overrides::load("tests/fixtures/overrides.json")?;
+        let series_override = overrides.get(80644, 2).ok_or(anyhow!("missing override"))?;
+        assert_eq!(series_override.adjust_episode_number(6), 12);
+        assert!(overrides.get(80644, 1).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_episodes_per_file_must_be_positive() {
+        let result = Overrides::from_entries(vec![SeriesOverride {
+            tvdb_id: 1,
+            season: None,
+            episodes_per_file: Some(0),
+            episode_offset: None,
+            cours: vec![],
+            status: None,
+            no_auto_complete: false,
+        }]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_resolve_cour() -> Result<(), anyhow::Error> {
+        let overrides = Overrides::load("tests/fixtures/overrides.json")?;
+        let series_override = overrides.get(79481, 1).ok_or(anyhow!("missing override"))?;
+        assert_eq!(
+            series_override.resolve_cour(5),
+            Some(ResolvedEpisode {
+                mal_id: 1535,
+                episode_number: 5
+            })
+        );
+        // mal starts the second cour over at 1
+        assert_eq!(
+            series_override.resolve_cour(15),
+            Some(ResolvedEpisode {
+                mal_id: 2994,
+                episode_number: 2
+            })
+        );
+        assert_eq!(series_override.resolve_cour(40), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cour_range_must_be_ordered() {
+        let result = Overrides::from_entries(vec![SeriesOverride {
+            tvdb_id: 1,
+            season: Some(1),
+            episodes_per_file: None,
+            episode_offset: None,
+            cours: vec![Cour {
+                episode_range: (13, 1),
+                mal_id: 2,
+                offset: 12,
+            }],
+            status: None,
+            no_auto_complete: false,
+        }]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_adjust_status() {
+        let series_override = |status, no_auto_complete| SeriesOverride {
+            tvdb_id: 1,
+            season: Some(1),
+            episodes_per_file: None,
+            episode_offset: None,
+            cours: vec![],
+            status,
+            no_auto_complete,
+        };
+        let none = series_override(None, false);
+        assert_eq!(
+            none.adjust_status(ListStatus::Completed),
+            ListStatus::Completed
+        );
+        let no_auto_complete = series_override(None, true);
+        assert_eq!(
+            no_auto_complete.adjust_status(ListStatus::Completed),
+            ListStatus::Watching
+        );
+        let pinned = series_override(Some(ListStatus::OnHold), true);
+        assert_eq!(
+            pinned.adjust_status(ListStatus::Completed),
+            ListStatus::OnHold
+        );
+        assert_eq!(
+            pinned.adjust_status(ListStatus::Watching),
+            ListStatus::OnHold
+        );
+    }
+}
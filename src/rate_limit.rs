@@ -0,0 +1,36 @@
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tokio::time::{sleep_until, Instant};
+
+/// Spaces out requests so that no more than a fixed number are started per second, no matter
+/// how many tasks share the limiter.
+pub struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: f64) -> RateLimiter {
+        RateLimiter::with_interval(Duration::from_secs_f64(1.0 / requests_per_second))
+    }
+
+    /// Spaces requests out by a fixed interval instead of a rate.
+    pub fn with_interval(interval: Duration) -> RateLimiter {
+        RateLimiter {
+            interval,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until the next request is allowed to start.
+    pub async fn acquire(&self) {
+        // waiters queue on the lock, so they're let through in order
+        let mut next = self.next.lock().await;
+        let now = Instant::now();
+        if *next > now {
+            sleep_until(*next).await;
+        }
+        *next = (*next).max(now) + self.interval;
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::jellyfin::{Episode, SeasonKey};
+use crate::mapping::MappingTable;
+use crate::SyncReport;
+
+const CSV_HEADER: &str = "series_name,tvdb_id,mal_id,season,old_episode,new_episode,status";
+
+/// How the report of a sync is rendered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// a line per series, for reading
+    Text,
+    /// the whole report as a json object
+    Json,
+    /// a row per updated series, for spreadsheets
+    Csv,
+}
+
+/// The latest watched episode of a season, as it's dumped. Kept apart from `Episode` so the
+/// output doesn't change with jellyfin's model.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct EpisodeSummary {
+    pub tvdb_id: i32,
+    pub season: i32,
+    // the last episode in the file
+    pub number: i32,
+    pub name: String,
+    pub series_name: String,
+    pub watched: bool,
+    // only known when the season could be mapped
+    pub mal_id: Option<i32>,
+}
+
+impl From<&Episode> for EpisodeSummary {
+    fn from(episode: &Episode) -> Self {
+        EpisodeSummary {
+            tvdb_id: episode.tvdb_id,
+            season: episode.season_number,
+            number: episode.last_number(),
+            name: episode.name.clone(),
+            series_name: episode.series_name.clone(),
+            watched: episode.watched,
+            mal_id: None,
+        }
+    }
+}
+
+/// Summarizes the latest episode of each season, sorted by season, with its mal id when the
+/// mapping resolves it.
+pub fn summarize_latest_episodes(
+    latest_episodes: &HashMap<SeasonKey, Episode>,
+    mapping_table: Option<&MappingTable>,
+) -> Vec<EpisodeSummary> {
+    let mut latest_episodes: Vec<(&SeasonKey, &Episode)> = latest_episodes.iter().collect();
+    latest_episodes.sort_by_key(|(key, _)| **key);
+    latest_episodes
+        .into_iter()
+        .map(|((tvdb_id, _), episode)| EpisodeSummary {
+            mal_id: mapping_table
+                .and_then(|table| table.resolve_episode(*tvdb_id, episode).ok())
+                .map(|resolved| resolved.mal_id),
+            ..EpisodeSummary::from(episode)
+        })
+        .collect()
+}
+
+/// Renders the report in the given format.
+pub fn render(report: &SyncReport, format: OutputFormat) -> Result<String> {
+    Ok(match format {
+        OutputFormat::Text => render_text(report),
+        OutputFormat::Json => serde_json::to_string_pretty(report)? + "\n",
+        OutputFormat::Csv => render_csv(report),
+    })
+}
+
+fn render_text(report: &SyncReport) -> String {
+    let mut text = String::new();
+    for change in &report.updated {
+        text.push_str(&format!(
+            "updated {} season {} (mal-id: {}): episode {} -> {}\n",
+            change.series_name,
+            change.season,
+            change.mal_id,
+            change.old_episode,
+            change.new_episode
+        ));
+    }
+    for skipped in &report.skipped {
+        text.push_str(&format!(
+            "skipped {} season {}: {}\n",
+            skipped.series_name, skipped.season, skipped.reason
+        ));
+    }
+    for failed in &report.failed {
+        text.push_str(&format!(
+            "failed {} season {}: {}\n",
+            failed.series_name, failed.season, failed.error
+        ));
+    }
+    text
+}
+
+// only the updated series have all of the columns, so they're the only rows
+fn render_csv(report: &SyncReport) -> String {
+    let mut csv = format!("{}\n", CSV_HEADER);
+    for change in &report.updated {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{}\n",
+            csv_field(&change.series_name),
+            change.tvdb_id,
+            change.mal_id,
+            change.season,
+            change.old_episode,
+            change.new_episode,
+            change.status.as_str()
+        ));
+    }
+    csv
+}
+
+// quotes fields holding a separator, doubling any quotes inside them
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mal::ListStatus;
+    use crate::mapping::ConsolidatedEntry;
+    use crate::{FailedSeries, SeriesChange, SkippedSeries};
+
+    fn sample_report() -> SyncReport {
+        SyncReport {
+            updated: vec![SeriesChange {
+                series_name: "Clannad, After Story".to_string(),
+                tvdb_id: 80644,
+                season: 2,
+                mal_id: 4181,
+                old_episode: 3,
+                new_episode: 5,
+                status: ListStatus::Watching,
+            }],
+            skipped: vec![SkippedSeries {
+                series_name: "Cowboy Bebop".to_string(),
+                tvdb_id: 76885,
+                season: 1,
+                mal_id: Some(1),
+                reason: "already up to date".to_string(),
+            }],
+            failed: vec![FailedSeries {
+                series_name: "Death Note".to_string(),
+                tvdb_id: 79481,
+                season: 1,
+                error: "unable to map tvdb to anidb".to_string(),
+            }],
+            interrupted: false,
+        }
+    }
+
+    #[test]
+    fn test_render_text() -> Result<(), anyhow::Error> {
+        assert_eq!(
+            render(&sample_report(), OutputFormat::Text)?,
+            "updated Clannad, After Story season 2 (mal-id: 4181): episode 3 -> 5\n\
+             skipped Cowboy Bebop season 1: already up to date\n\
+             failed Death Note season 1: unable to map tvdb to anidb\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_json() -> Result<(), anyhow::Error> {
+        let json: serde_json::Value =
+            serde_json::from_str(&render(&sample_report(), OutputFormat::Json)?)?;
+        assert_eq!(json["updated"][0]["mal_id"], 4181);
+        assert_eq!(json["skipped"][0]["reason"], "already up to date");
+        assert_eq!(json["failed"][0]["tvdb_id"], 79481);
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_csv() -> Result<(), anyhow::Error> {
+        assert_eq!(
+            render(&sample_report(), OutputFormat::Csv)?,
+            "series_name,tvdb_id,mal_id,season,old_episode,new_episode,status\n\
+             \"Clannad, After Story\",80644,4181,2,3,5,watching\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_episode_summary_round_trip() -> Result<(), anyhow::Error> {
+        let episode = Episode {
+            id: "15".to_string(),
+            number: 7,
+            number_end: Some(8),
+            absolute_number: None,
+            name: "Fireworks".to_string(),
+            season_number: 2,
+            series_name: "Clannad, After Story".to_string(),
+            series_id: "42".to_string(),
+            tvdb_id: 80644,
+            watched: true,
+            played_percentage: None,
+            series_favorite: false,
+            season_finished: false,
+        };
+        let mapping_table = MappingTable::from_entries(vec![ConsolidatedEntry {
+            tvdb_id: 80644,
+            tvdb_season: 2,
+            anidb_id: 6351,
+            mal_id: Some(4181),
+            episode_offset: 0,
+            season_ranges: vec![],
+        }]);
+        let summaries = summarize_latest_episodes(
+            &HashMap::from([((80644, 2), episode)]),
+            Some(&mapping_table),
+        );
+        assert_eq!(
+            summaries,
+            vec![EpisodeSummary {
+                tvdb_id: 80644,
+                season: 2,
+                number: 8,
+                name: "Fireworks".to_string(),
+                series_name: "Clannad, After Story".to_string(),
+                watched: true,
+                mal_id: Some(4181),
+            }]
+        );
+        let json = serde_json::to_string(&summaries)?;
+        assert_eq!(
+            serde_json::from_str::<Vec<EpisodeSummary>>(&json)?,
+            summaries
+        );
+        Ok(())
+    }
+}
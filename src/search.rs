@@ -0,0 +1,160 @@
+use log::info;
+
+// how similar a title has to be to count as a match, unless configured otherwise. a typo or a
+// missing "the" gets through, a different season or sequel doesn't
+pub const DEFAULT_MIN_SCORE: f64 = 0.9;
+// how many results of a title search are scored
+pub const SEARCH_LIMIT: usize = 10;
+
+/// A series found by searching the tracker for a title.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Candidate {
+    pub mal_id: i32,
+    pub title: String,
+    // the english title and synonyms, which the library is often named after instead
+    pub alternative_titles: Vec<String>,
+}
+
+/// A candidate picked for a series, and how close its best title came.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchMatch {
+    pub mal_id: i32,
+    pub title: String,
+    pub score: f64,
+}
+
+/// How alike two titles are, from 0 to 1, ignoring case and punctuation.
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let (a, b) = (normalize(a), normalize(b));
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 0.0;
+    }
+    1.0 - levenshtein(&a, &b) as f64 / longest as f64
+}
+
+/// Picks the candidate whose title is most like `name`. Every pick is logged with its score and
+/// the runner-up, so that the guess can be checked. Nothing is picked when the best score is
+/// below `min_score`.
+pub fn best_match(name: &str, candidates: &[Candidate], min_score: f64) -> Option<SearchMatch> {
+    let mut scored: Vec<SearchMatch> = candidates
+        .iter()
+        .map(|candidate| SearchMatch {
+            mal_id: candidate.mal_id,
+            title: candidate.title.clone(),
+            score: std::iter::once(&candidate.title)
+                .chain(&candidate.alternative_titles)
+                .map(|title| similarity(name, title))
+                .fold(0.0, f64::max),
+        })
+        .collect();
+    // stable, so that equal scores keep the tracker's order
+    scored.sort_by(|a, b| b.score.total_cmp(&a.score));
+    let mut scored = scored.into_iter();
+    let best = scored.next()?;
+    let runner_up = match scored.next() {
+        Some(runner_up) => format!("{} at {:.2}", runner_up.title, runner_up.score),
+        None => "none".to_string(),
+    };
+    if best.score < min_score {
+        info!(
+            "not matching series {} by title, the closest was {} (mal-id: {}) at {:.2}, below \
+             the minimum of {:.2}. runner-up: {}",
+            name, best.title, best.mal_id, best.score, min_score, runner_up
+        );
+        return None;
+    }
+    info!(
+        "matched series {} by title to {} (mal-id: {}) at {:.2}. runner-up: {}",
+        name, best.title, best.mal_id, best.score, runner_up
+    );
+    Some(best)
+}
+
+// lowercase words, without the punctuation that differs between jellyfin and mal
+fn normalize(title: &str) -> Vec<char> {
+    let title: String = title
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+    title
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .collect()
+}
+
+// the number of single character edits turning a into b
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.iter().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(mal_id: i32, title: &str, alternative_titles: &[&str]) -> Candidate {
+        Candidate {
+            mal_id,
+            title: title.to_string(),
+            alternative_titles: alternative_titles.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_similarity() {
+        assert_eq!(similarity("Cowboy Bebop", "Cowboy Bebop"), 1.0);
+        assert_eq!(similarity("Steins;Gate", "steins gate"), 1.0);
+        assert_eq!(similarity("abcdefghij", "abcdefghik"), 0.9);
+        assert!(similarity("Cowboy Bebop", "Cowboy Bebop: Tengoku no Tobira") < 0.5);
+        assert_eq!(similarity("", "!!"), 0.0);
+    }
+
+    #[test]
+    fn test_best_match_threshold() {
+        let candidates = vec![
+            candidate(1, "abcdefghik", &[]),
+            candidate(2, "abcdefgxyz", &[]),
+        ];
+        // the best scores exactly 0.9
+        assert_eq!(
+            best_match("abcdefghij", &candidates, 0.9),
+            Some(SearchMatch {
+                mal_id: 1,
+                title: "abcdefghik".to_string(),
+                score: 0.9
+            })
+        );
+        assert_eq!(
+            best_match("abcdefghij", &candidates, 0.8).map(|m| m.mal_id),
+            Some(1)
+        );
+        assert_eq!(best_match("abcdefghij", &candidates, 0.95), None);
+        assert_eq!(best_match("abcdefghij", &[], 0.0), None);
+    }
+
+    #[test]
+    fn test_best_match_alternative_titles() {
+        let candidates = vec![
+            candidate(16498, "Shingeki no Kyojin", &["Attack on Titan"]),
+            candidate(
+                25777,
+                "Shingeki no Kyojin Season 2",
+                &["Attack on Titan Season 2"],
+            ),
+        ];
+        let best = best_match("Attack on Titan", &candidates, DEFAULT_MIN_SCORE).unwrap();
+        assert_eq!((best.mal_id, best.score), (16498, 1.0));
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::jellyfin::SeasonKey;
+use crate::SyncReport;
+
+// checkpoints older than this belong to a run that's long over, so they're started over
+const RUN_WINDOW_MILLIS: i64 = 24 * 60 * 60 * 1000;
+// how many series are synced between writes of the state file
+const CHECKPOINT_INTERVAL: usize = 10;
+
+#[derive(Serialize, Deserialize, Default)]
+struct State {
+    // when the interrupted run started, in milliseconds since the epoch
+    started_at: i64,
+    synced: BTreeSet<SeasonKey>,
+}
+
+// a season jellyfin reports as finished, waiting for more runs to agree before it's completed
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+struct PendingCompletion {
+    episode: i32,
+    runs: u32,
+}
+
+/// The mal entries waiting to be completed, kept across runs rather than for a single one.
+/// Nothing is written without a path.
+#[derive(Default)]
+pub struct Completions {
+    path: Option<String>,
+    pending: BTreeMap<i32, PendingCompletion>,
+}
+
+impl Completions {
+    pub fn load(path: Option<&str>) -> Result<Completions> {
+        let pending = match path.filter(|path| Path::new(path).exists()) {
+            Some(path) => serde_json::from_reader(BufReader::new(File::open(path)?))?,
+            None => BTreeMap::new(),
+        };
+        Ok(Completions {
+            path: path.map(|path| path.to_string()),
+            pending,
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            let writer = BufWriter::new(File::create(path)?);
+            serde_json::to_writer(writer, &self.pending)?;
+        }
+        Ok(())
+    }
+}
+
+/// The series already synced by the current run, kept in the state file so a restarted run can
+/// skip them. Nothing is written without a path.
+#[derive(Default)]
+pub struct Checkpoint {
+    path: Option<String>,
+    state: State,
+    unsaved: usize,
+    completions: Completions,
+}
+
+impl Checkpoint {
+    /// Resumes the run recorded in the state file, or starts a new one if there's none or it's
+    /// outside of the run window.
+    pub fn load(state_path: &str) -> Result<Checkpoint> {
+        Checkpoint::load_from(Some(state_path), Some(state_path))
+    }
+
+    /// Like `load`, but reads the state from one file and writes it to another, leaving the
+    /// input untouched. Either can be left out, to start a new run or to write nothing.
+    pub fn load_from(input_path: Option<&str>, output_path: Option<&str>) -> Result<Checkpoint> {
+        let now = Utc::now().timestamp_millis();
+        let state = if let Some(input_path) = input_path.filter(|path| Path::new(path).exists()) {
+            let f = File::open(input_path)?;
+            let reader = BufReader::new(f);
+            let state: State = serde_json::from_reader(reader)?;
+            if now - state.started_at < RUN_WINDOW_MILLIS {
+                info!(
+                    "resuming an interrupted run, skipping {} already synced series",
+                    state.synced.len()
+                );
+                state
+            } else {
+                debug!("discarding a checkpoint from outside of the run window");
+                State {
+                    started_at: now,
+                    ..Default::default()
+                }
+            }
+        } else {
+            State {
+                started_at: now,
+                ..Default::default()
+            }
+        };
+        Ok(Checkpoint {
+            path: output_path.map(|path| path.to_string()),
+            state,
+            unsaved: 0,
+            completions: Completions::default(),
+        })
+    }
+
+    /// Keeps the entries waiting to be completed along with the checkpoint. Unlike the
+    /// checkpoint, they're kept once the run is over.
+    pub fn with_completions(mut self, completions: Completions) -> Checkpoint {
+        self.completions = completions;
+        self
+    }
+
+    /// Counts another run finding the entry finished at `episode`, returning how many runs in a
+    /// row have. A different episode starts the count over.
+    pub fn confirm_completion(&mut self, mal_id: i32, episode: i32) -> u32 {
+        let pending = self
+            .completions
+            .pending
+            .entry(mal_id)
+            .or_insert(PendingCompletion { episode, runs: 0 });
+        if pending.episode != episode {
+            *pending = PendingCompletion { episode, runs: 0 };
+        }
+        pending.runs += 1;
+        pending.runs
+    }
+
+    /// Whether the entry is waiting to be completed at `episode`.
+    pub fn is_completion_pending(&self, mal_id: i32, episode: i32) -> bool {
+        self.completions
+            .pending
+            .get(&mal_id)
+            .is_some_and(|pending| pending.episode == episode)
+    }
+
+    pub fn forget_completion(&mut self, mal_id: i32) {
+        self.completions.pending.remove(&mal_id);
+    }
+
+    pub fn is_synced(&self, tvdb_id: i32, season: i32) -> bool {
+        self.state.synced.contains(&(tvdb_id, season))
+    }
+
+    /// Records a season as synced, writing the state file every few seasons.
+    pub fn mark_synced(&mut self, tvdb_id: i32, season: i32) -> Result<()> {
+        self.state.synced.insert((tvdb_id, season));
+        self.unsaved += 1;
+        if self.unsaved >= CHECKPOINT_INTERVAL {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        if let Some(path) = &self.path {
+            let f = File::create(path)?;
+            let writer = BufWriter::new(f);
+            serde_json::to_writer(writer, &self.state)?;
+        }
+        self.completions.save()?;
+        self.unsaved = 0;
+        Ok(())
+    }
+
+    /// Removes the checkpoint once the run has completed.
+    pub fn clear(self) -> Result<()> {
+        self.completions.save()?;
+        if let Some(path) = &self.path {
+            if Path::new(path).exists() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LastRun {
+    started_at: DateTime<Utc>,
+}
+
+/// Reads when the last fully successful run started, if there's been one.
+pub fn load_last_run(last_run_path: &str) -> Result<Option<DateTime<Utc>>> {
+    if !Path::new(last_run_path).exists() {
+        return Ok(None);
+    }
+    let f = File::open(last_run_path)?;
+    let last_run: LastRun = serde_json::from_reader(BufReader::new(f))?;
+    Ok(Some(last_run.started_at))
+}
+
+/// Records the start of a run, but only once it synced everything. The next run only looks at
+/// what was played since, so a series that failed would otherwise never be retried.
+pub fn save_last_run(
+    last_run_path: &str,
+    started_at: DateTime<Utc>,
+    report: &SyncReport,
+) -> Result<bool> {
+    if report.interrupted || !report.failed.is_empty() {
+        debug!("not saving the last run, since it didn't sync every series");
+        return Ok(false);
+    }
+    let f = File::create(last_run_path)?;
+    serde_json::to_writer(BufWriter::new(f), &LastRun { started_at })?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FailedSeries;
+
+    #[test]
+    fn test_checkpoint_resume() -> Result<(), anyhow::Error> {
+        let path = std::env::temp_dir().join("jellymal-test-state.json");
+        let path = path.to_str().unwrap();
+        let mut checkpoint = Checkpoint::load(path)?;
+        checkpoint.mark_synced(1, 1)?;
+        checkpoint.save()?;
+
+        // a restart picks the synced series back up
+        let checkpoint = Checkpoint::load(path)?;
+        assert!(checkpoint.is_synced(1, 1));
+        assert!(!checkpoint.is_synced(1, 2));
+        checkpoint.clear()?;
+        assert!(!Path::new(path).exists());
+        assert!(!Checkpoint::load(path)?.is_synced(1, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_separate_input_and_output() -> Result<(), anyhow::Error> {
+        let input = std::env::temp_dir().join("jellymal-test-state-input.json");
+        let input = input.to_str().unwrap();
+        let output = std::env::temp_dir().join("jellymal-test-state-output.json");
+        let output = output.to_str().unwrap();
+        let mut baseline = Checkpoint::load(input)?;
+        baseline.mark_synced(1, 1)?;
+        baseline.save()?;
+        let baseline = std::fs::read_to_string(input)?;
+
+        let mut checkpoint = Checkpoint::load_from(Some(input), Some(output))?;
+        assert!(checkpoint.is_synced(1, 1));
+        checkpoint.mark_synced(2, 1)?;
+        checkpoint.save()?;
+
+        // the input is left as it was, the output has both
+        assert_eq!(std::fs::read_to_string(input)?, baseline);
+        let resumed = Checkpoint::load(output)?;
+        assert!(resumed.is_synced(1, 1) && resumed.is_synced(2, 1));
+        checkpoint.clear()?;
+        assert!(Path::new(input).exists() && !Path::new(output).exists());
+        std::fs::remove_file(input)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_completions_kept_across_runs() -> Result<(), anyhow::Error> {
+        let path = std::env::temp_dir().join("jellymal-test-completions.json");
+        let path = path.to_str().unwrap();
+        let mut checkpoint = Checkpoint::default().with_completions(Completions::load(Some(path))?);
+        assert_eq!(checkpoint.confirm_completion(10, 12), 1);
+        checkpoint.clear()?;
+
+        let mut checkpoint = Checkpoint::default().with_completions(Completions::load(Some(path))?);
+        assert!(checkpoint.is_completion_pending(10, 12));
+        assert_eq!(checkpoint.confirm_completion(10, 12), 2);
+        // the last episode moved, e.g. after jellyfin's metadata changed
+        assert_eq!(checkpoint.confirm_completion(10, 13), 1);
+        assert!(!checkpoint.is_completion_pending(10, 12));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_outside_run_window() -> Result<(), anyhow::Error> {
+        let path = std::env::temp_dir().join("jellymal-test-state-expired.json");
+        let path = path.to_str().unwrap();
+        let state = State {
+            started_at: Utc::now().timestamp_millis() - RUN_WINDOW_MILLIS - 1,
+            synced: BTreeSet::from([(1, 1)]),
+        };
+        serde_json::to_writer(File::create(path)?, &state)?;
+
+        assert!(!Checkpoint::load(path)?.is_synced(1, 1));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_last_run() -> Result<(), anyhow::Error> {
+        let path = std::env::temp_dir().join("jellymal-test-last-run.json");
+        let path = path.to_str().unwrap();
+        if Path::new(path).exists() {
+            std::fs::remove_file(path)?;
+        }
+        assert_eq!(load_last_run(path)?, None);
+
+        let first = Utc::now() - chrono::Duration::hours(1);
+        assert!(save_last_run(path, first, &SyncReport::default())?);
+        assert_eq!(load_last_run(path)?, Some(first));
+
+        // neither a failed nor an interrupted run moves it forward
+        let failed = SyncReport {
+            failed: vec![FailedSeries {
+                series_name: "Death Note".to_string(),
+                tvdb_id: 79481,
+                season: 1,
+                error: "unable to map tvdb to anidb".to_string(),
+            }],
+            ..Default::default()
+        };
+        assert!(!save_last_run(path, Utc::now(), &failed)?);
+        let interrupted = SyncReport {
+            interrupted: true,
+            ..Default::default()
+        };
+        assert!(!save_last_run(path, Utc::now(), &interrupted)?);
+        assert_eq!(load_last_run(path)?, Some(first));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1502 @@
+use std::collections::{HashMap, HashSet};
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use tokio_util::sync::CancellationToken;
+
+use crate::jellyfin::{Episode, SeasonKey};
+use crate::mal::{is_completed, ListStatus, MyAnimeListApi, UserAnimeListDatum};
+use crate::mapping::{MappingError, MappingTable, ResolvedEpisode};
+use crate::overrides::Overrides;
+use crate::search::{best_match, Candidate, SEARCH_LIMIT};
+use crate::state::Checkpoint;
+use crate::{FailedSeries, SeriesChange, SkippedSeries, SyncReport};
+
+/// Somewhere watch progress is tracked, e.g. a user's mal list.
+#[allow(async_fn_in_trait)]
+pub trait Tracker {
+    async fn get_latest_episode_number(&self, series_id: i32) -> Result<i32>;
+    async fn set_latest_episode_number(
+        &self,
+        series_id: i32,
+        episode_number: i32,
+        status: ListStatus,
+    ) -> Result<()>;
+    async fn get_list_status(&self, series_id: i32) -> Result<Option<ListStatus>>;
+    // none when the tracker doesn't know how many episodes there are
+    async fn get_num_episodes(&self, series_id: i32) -> Result<Option<i32>>;
+    // series matching a title, none when the tracker can't be searched
+    async fn search(&self, _title: &str) -> Result<Vec<Candidate>> {
+        Ok(vec![])
+    }
+}
+
+impl Tracker for MyAnimeListApi {
+    async fn get_latest_episode_number(&self, series_id: i32) -> Result<i32> {
+        MyAnimeListApi::get_latest_episode_number(self, series_id).await
+    }
+
+    async fn set_latest_episode_number(
+        &self,
+        series_id: i32,
+        episode_number: i32,
+        status: ListStatus,
+    ) -> Result<()> {
+        MyAnimeListApi::set_progress(self, series_id, episode_number, status).await
+    }
+
+    async fn get_list_status(&self, series_id: i32) -> Result<Option<ListStatus>> {
+        MyAnimeListApi::get_list_status(self, series_id).await
+    }
+
+    async fn get_num_episodes(&self, series_id: i32) -> Result<Option<i32>> {
+        MyAnimeListApi::get_num_episodes(self, series_id).await
+    }
+
+    async fn search(&self, title: &str) -> Result<Vec<Candidate>> {
+        MyAnimeListApi::search_anime(self, title, SEARCH_LIMIT).await
+    }
+}
+
+/// Settings deciding which series get synced at all.
+pub struct SyncOptions {
+    // series with fewer episodes watched on jellyfin aren't synced, 0 syncs everything
+    pub min_episodes_watched: i32,
+    // only series marked as a favorite on jellyfin are synced
+    pub favorites_only: bool,
+    // how long a single series may take before it's given up on and counted as failed
+    pub series_timeout: Option<Duration>,
+    // no new series are started after this, like after a shutdown
+    pub deadline: Option<Instant>,
+    // mal's episode count may go down and completed series may go back to watching
+    pub allow_decrease: bool,
+    // how many runs in a row must find a season finished before it's completed, 0 and 1 both
+    // complete it right away
+    pub complete_after_runs: u32,
+    // share of mal's episodes that has to be watched for a finished season to be completed,
+    // clamped to 0 to 1
+    pub completed_ratio: f64,
+    // series the mappings can't place are searched for by title, and matched when the best
+    // title scores at least this. none leaves them unmapped
+    pub search_min_score: Option<f64>,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            min_episodes_watched: 0,
+            favorites_only: false,
+            series_timeout: None,
+            deadline: None,
+            allow_decrease: false,
+            complete_after_runs: 0,
+            completed_ratio: 1.0,
+            search_min_score: None,
+        }
+    }
+}
+
+enum SeriesOutcome {
+    Updated(SeriesChange),
+    Skipped(SkippedSeries),
+}
+
+/// Pushes the latest watched episode of each series to the tracker, when it's ahead of what the
+/// tracker has. A series failing doesn't stop the others from being synced. Series recorded in
+/// the checkpoint are skipped, and newly synced ones are added to it. Once `shutdown` is
+/// cancelled, the series being synced is finished and the rest are left for the next run.
+pub async fn sync<T: Tracker>(
+    tracker: &T,
+    latest_episodes: HashMap<SeasonKey, Episode>,
+    mapping_table: &MappingTable,
+    overrides: &Overrides,
+    options: &SyncOptions,
+    checkpoint: &mut Checkpoint,
+    shutdown: &CancellationToken,
+) -> SyncReport {
+    run(
+        tracker,
+        latest_episodes,
+        mapping_table,
+        overrides,
+        options,
+        checkpoint,
+        shutdown,
+        true,
+    )
+    .await
+}
+
+/// Compares jellyfin with the tracker without writing anything. `updated` holds the series where
+/// jellyfin is ahead, `skipped` those where the tracker is ahead or both match, and `failed` the
+/// ones that couldn't be mapped.
+pub async fn verify<T: Tracker>(
+    tracker: &T,
+    latest_episodes: HashMap<SeasonKey, Episode>,
+    mapping_table: &MappingTable,
+    overrides: &Overrides,
+    options: &SyncOptions,
+) -> SyncReport {
+    run(
+        tracker,
+        latest_episodes,
+        mapping_table,
+        overrides,
+        options,
+        &mut Checkpoint::default(),
+        &CancellationToken::new(),
+        false,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run<T: Tracker>(
+    tracker: &T,
+    latest_episodes: HashMap<SeasonKey, Episode>,
+    mapping_table: &MappingTable,
+    overrides: &Overrides,
+    options: &SyncOptions,
+    checkpoint: &mut Checkpoint,
+    shutdown: &CancellationToken,
+    write: bool,
+) -> SyncReport {
+    let mut report = SyncReport::default();
+    // seasons that fall back to the same mal entry mustn't undo each other's progress, and the
+    // tracker may not see changes made during the run
+    let mut pushed: HashMap<i32, i32> = HashMap::new();
+    // go through the series in a stable order, so that runs over the same library log the same
+    let mut latest_episodes: Vec<(SeasonKey, Episode)> = latest_episodes.into_iter().collect();
+    latest_episodes
+        .sort_by(|(a_key, a), (b_key, b)| (&a.series_name, a_key).cmp(&(&b.series_name, b_key)));
+    for ((tvdb_id, season), episode) in latest_episodes {
+        if shutdown.is_cancelled() {
+            info!("stopping the sync early, the remaining series are synced on the next run");
+            report.interrupted = true;
+            break;
+        }
+        if options
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            info!("reached the max runtime, the remaining series are synced on the next run");
+            report.interrupted = true;
+            break;
+        }
+        if checkpoint.is_synced(tvdb_id, season) {
+            report.skipped.push(SkippedSeries {
+                series_name: episode.series_name,
+                tvdb_id,
+                season: episode.season_number,
+                mal_id: None,
+                reason: "already synced before a restart".to_string(),
+            });
+            continue;
+        }
+        if options.favorites_only && !episode.series_favorite {
+            report.skipped.push(SkippedSeries {
+                series_name: episode.series_name,
+                tvdb_id,
+                season: episode.season_number,
+                mal_id: None,
+                reason: "not a favorite".to_string(),
+            });
+            continue;
+        }
+        if episode.last_number() < options.min_episodes_watched {
+            let reason = format!(
+                "only {} episodes watched, fewer than the minimum of {}",
+                episode.last_number(),
+                options.min_episodes_watched
+            );
+            report.skipped.push(SkippedSeries {
+                series_name: episode.series_name,
+                tvdb_id,
+                season: episode.season_number,
+                mal_id: None,
+                reason,
+            });
+            continue;
+        }
+        let series = sync_series(
+            tracker,
+            tvdb_id,
+            &episode,
+            mapping_table,
+            overrides,
+            &mut pushed,
+            checkpoint,
+            options,
+            write,
+        );
+        let outcome = match options.series_timeout {
+            Some(series_timeout) => tokio::time::timeout(series_timeout, series)
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("timed out after {:?}", series_timeout))),
+            None => series.await,
+        };
+        // failed series aren't checkpointed, so they're retried after a restart
+        if outcome.is_ok() {
+            if let Err(e) = checkpoint.mark_synced(tvdb_id, season) {
+                warn!("unable to save the checkpoint: {}", e);
+            }
+        }
+        match outcome {
+            Ok(SeriesOutcome::Updated(change)) => report.updated.push(change),
+            Ok(SeriesOutcome::Skipped(skipped)) => report.skipped.push(skipped),
+            Err(e) => {
+                warn!("unable to sync series {}: {}", episode.series_name, e);
+                report.failed.push(FailedSeries {
+                    series_name: episode.series_name,
+                    tvdb_id,
+                    season: episode.season_number,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+    report
+}
+
+/// Waits for the jellyfin scan and the tracker's fetch, which don't depend on each other, at the
+/// same time. Either failing fails both, with each error kept apart.
+pub async fn fetch_concurrently<J, T>(
+    jellyfin: impl Future<Output = Result<J>>,
+    tracker: impl Future<Output = Result<T>>,
+) -> Result<(J, T)> {
+    match tokio::join!(jellyfin, tracker) {
+        (Ok(jellyfin), Ok(tracker)) => Ok((jellyfin, tracker)),
+        (Err(e), Ok(_)) => Err(e.context("unable to fetch from jellyfin")),
+        (Ok(_), Err(e)) => Err(e.context("unable to fetch from mal")),
+        (Err(jellyfin_error), Err(tracker_error)) => Err(anyhow!(
+            "unable to fetch from jellyfin: {:#}, and unable to fetch from mal: {:#}",
+            jellyfin_error,
+            tracker_error
+        )),
+    }
+}
+
+/// The mal ids that the episodes in the library map to, whether they've been watched or not.
+pub fn library_mal_ids(
+    episodes: &[Episode],
+    mapping_table: &MappingTable,
+    overrides: &Overrides,
+) -> HashSet<i32> {
+    let mut mal_ids = HashSet::new();
+    for episode in episodes {
+        let series_override = overrides.get(episode.tvdb_id, episode.season_number);
+        // a file spanning several episodes may cross into the next entry
+        for number in [episode.number, episode.last_number()] {
+            let resolved = match series_override.and_then(|o| o.resolve_cour(number)) {
+                Some(resolved) => Ok(resolved),
+                None => mapping_table.resolve(episode.tvdb_id, episode.season_number, number),
+            };
+            if let Ok(resolved) = resolved {
+                mal_ids.insert(resolved.mal_id);
+            }
+        }
+    }
+    mal_ids
+}
+
+/// Finds the entries on the mal list that none of the library's series map to.
+pub fn find_missing<'a>(
+    anime_list: &'a [UserAnimeListDatum],
+    library_mal_ids: &HashSet<i32>,
+) -> Vec<&'a UserAnimeListDatum> {
+    anime_list
+        .iter()
+        .filter(|datum| !library_mal_ids.contains(&datum.node.id))
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn sync_series<T: Tracker>(
+    tracker: &T,
+    tvdb_id: i32,
+    episode: &Episode,
+    mapping_table: &MappingTable,
+    overrides: &Overrides,
+    pushed: &mut HashMap<i32, i32>,
+    checkpoint: &mut Checkpoint,
+    options: &SyncOptions,
+    write: bool,
+) -> Result<SeriesOutcome> {
+    debug!(
+        "latest watched episode of series {} is {} (jellyfin-id: {}, name: {})",
+        episode.series_name,
+        episode.last_number(),
+        episode.id,
+        episode.name
+    );
+    // find the mal id. if the user's latest watched on jellyfin is greater than the latest
+    // watch on mal, update the user's list
+    let series_override = overrides.get(tvdb_id, episode.season_number);
+    // a cour in the overrides takes the place of the mapping
+    let resolved = match series_override.and_then(|o| o.resolve_cour(episode.last_number())) {
+        Some(resolved) => resolved,
+        None => match mapping_table.resolve_episode(tvdb_id, episode) {
+            Ok(resolved) => resolved,
+            Err(e) if e.is::<MappingError>() => {
+                search_title(tracker, episode, options).await?.ok_or(e)?
+            }
+            Err(e) => return Err(e),
+        },
+    };
+    let mal_id = resolved.mal_id;
+    let episode_number = match series_override {
+        Some(series_override) => series_override.adjust_episode_number(resolved.episode_number),
+        None => resolved.episode_number,
+    };
+    let mal_latest_episode_number = match pushed.get(&mal_id) {
+        Some(pushed_episode_number) => *pushed_episode_number,
+        None => tracker.get_latest_episode_number(mal_id).await?,
+    };
+    // only lower what's on the tracker, never what another season of the same entry pushed
+    let decrease = options.allow_decrease
+        && !pushed.contains_key(&mal_id)
+        && episode_number < mal_latest_episode_number;
+    // an entry waiting to be completed is looked at again, even though its count is up to date
+    let pending = episode_number == mal_latest_episode_number
+        && checkpoint.is_completion_pending(mal_id, episode_number);
+    if episode_number <= mal_latest_episode_number && !decrease && !pending {
+        let reason = if episode_number < mal_latest_episode_number {
+            debug!(
+                "not lowering series {} (mal-id: {}) from episode {} to {}",
+                episode.series_name, mal_id, mal_latest_episode_number, episode_number
+            );
+            format!("mal is ahead at episode {}", mal_latest_episode_number)
+        } else {
+            "already up to date".to_string()
+        };
+        return Ok(SeriesOutcome::Skipped(SkippedSeries {
+            series_name: episode.series_name.clone(),
+            tvdb_id,
+            season: episode.season_number,
+            mal_id: Some(mal_id),
+            reason,
+        }));
+    }
+
+    // a season without anything left to watch is done, as far as jellyfin can tell. jellyfin
+    // may not have every episode though, so enough of mal's episodes have to be watched
+    let reached_end = tracker
+        .get_num_episodes(mal_id)
+        .await?
+        .is_some_and(|num_episodes| {
+            is_completed(
+                episode_number,
+                num_episodes,
+                options.completed_ratio.clamp(0.0, 1.0),
+            )
+        });
+    let status = if episode.season_finished && reached_end {
+        ListStatus::Completed
+    } else {
+        ListStatus::Watching
+    };
+    let status = match series_override {
+        Some(series_override) => series_override.adjust_status(status),
+        None => status,
+    };
+    let status = if status == ListStatus::Completed {
+        let runs = checkpoint.confirm_completion(mal_id, episode_number);
+        if runs < options.complete_after_runs {
+            debug!(
+                "not completing series {} (mal-id: {}) yet, it's been finished for {} of {} runs",
+                episode.series_name, mal_id, runs, options.complete_after_runs
+            );
+            ListStatus::Watching
+        } else {
+            checkpoint.forget_completion(mal_id);
+            status
+        }
+    } else {
+        checkpoint.forget_completion(mal_id);
+        status
+    };
+    if pending && status != ListStatus::Completed {
+        return Ok(SeriesOutcome::Skipped(SkippedSeries {
+            series_name: episode.series_name.clone(),
+            tvdb_id,
+            season: episode.season_number,
+            mal_id: Some(mal_id),
+            reason: "waiting to be completed".to_string(),
+        }));
+    }
+    // a completed series stays completed, even when the count moves on or an override asks
+    // for watching
+    let status = if status != ListStatus::Completed
+        && !options.allow_decrease
+        && tracker.get_list_status(mal_id).await? == Some(ListStatus::Completed)
+    {
+        debug!(
+            "not moving series {} (mal-id: {}) from completed to {}",
+            episode.series_name,
+            mal_id,
+            status.as_str()
+        );
+        ListStatus::Completed
+    } else {
+        status
+    };
+    if write {
+        info!(
+            series_name = episode.series_name.as_str(), mal_id = mal_id;
+            "setting latest episode of series {} (mal-id: {}) to {} ({})",
+            episode.series_name, mal_id, episode_number, status.as_str()
+        );
+        tracker
+            .set_latest_episode_number(mal_id, episode_number, status)
+            .await?;
+    }
+    pushed.insert(mal_id, episode_number);
+    Ok(SeriesOutcome::Updated(SeriesChange {
+        series_name: episode.series_name.clone(),
+        tvdb_id,
+        season: episode.season_number,
+        mal_id,
+        old_episode: mal_latest_episode_number,
+        new_episode: episode_number,
+        status,
+    }))
+}
+
+// only a first season is searched for, since mal titles later ones in too many ways to guess
+async fn search_title<T: Tracker>(
+    tracker: &T,
+    episode: &Episode,
+    options: &SyncOptions,
+) -> Result<Option<ResolvedEpisode>> {
+    let Some(min_score) = options.search_min_score else {
+        return Ok(None);
+    };
+    if episode.season_number != 1 {
+        return Ok(None);
+    }
+    let candidates = tracker.search(&episode.series_name).await?;
+    Ok(
+        best_match(&episode.series_name, &candidates, min_score).map(|found| ResolvedEpisode {
+            mal_id: found.mal_id,
+            episode_number: episode.last_number(),
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::mapping::ConsolidatedEntry;
+    use crate::overrides::{Cour, SeriesOverride};
+    use crate::state::Completions;
+
+    /// A tracker backed by an in-memory list of mal id to episodes watched.
+    #[derive(Default)]
+    struct FakeTracker {
+        list: Mutex<HashMap<i32, i32>>,
+        statuses: Mutex<HashMap<i32, ListStatus>>,
+        num_episodes: Mutex<HashMap<i32, i32>>,
+        // what every search finds, and the titles searched for
+        candidates: Vec<Candidate>,
+        searches: Mutex<Vec<String>>,
+    }
+
+    impl Tracker for FakeTracker {
+        async fn get_latest_episode_number(&self, series_id: i32) -> Result<i32> {
+            Ok(*self.list.lock().unwrap().get(&series_id).unwrap_or(&0))
+        }
+
+        async fn set_latest_episode_number(
+            &self,
+            series_id: i32,
+            episode_number: i32,
+            status: ListStatus,
+        ) -> Result<()> {
+            self.list.lock().unwrap().insert(series_id, episode_number);
+            self.statuses.lock().unwrap().insert(series_id, status);
+            Ok(())
+        }
+
+        async fn get_list_status(&self, series_id: i32) -> Result<Option<ListStatus>> {
+            Ok(self.statuses.lock().unwrap().get(&series_id).copied())
+        }
+
+        async fn get_num_episodes(&self, series_id: i32) -> Result<Option<i32>> {
+            Ok(self.num_episodes.lock().unwrap().get(&series_id).copied())
+        }
+
+        async fn search(&self, title: &str) -> Result<Vec<Candidate>> {
+            self.searches.lock().unwrap().push(title.to_string());
+            Ok(self.candidates.clone())
+        }
+    }
+
+    fn entry(tvdb_id: i32, mal_id: i32) -> ConsolidatedEntry {
+        season_entry(tvdb_id, 1, mal_id)
+    }
+
+    fn season_entry(tvdb_id: i32, tvdb_season: i32, mal_id: i32) -> ConsolidatedEntry {
+        ConsolidatedEntry {
+            tvdb_id,
+            tvdb_season,
+            anidb_id: tvdb_id,
+            mal_id: Some(mal_id),
+            episode_offset: 0,
+            season_ranges: vec![],
+        }
+    }
+
+    fn watched(tvdb_id: i32, number: i32) -> Episode {
+        watched_in_season(tvdb_id, 1, number)
+    }
+
+    fn watched_in_season(tvdb_id: i32, season_number: i32, number: i32) -> Episode {
+        Episode {
+            id: tvdb_id.to_string(),
+            number,
+            number_end: None,
+            absolute_number: None,
+            name: "test_episode".to_string(),
+            season_number,
+            series_name: format!("series_{}", tvdb_id),
+            series_id: tvdb_id.to_string(),
+            tvdb_id,
+            watched: true,
+            played_percentage: None,
+            series_favorite: false,
+            season_finished: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_report() {
+        let tracker = FakeTracker {
+            list: Mutex::new(HashMap::from([(10, 2), (20, 7)])),
+            ..Default::default()
+        };
+        let mapping_table = MappingTable::from_entries(vec![entry(1, 10), entry(2, 20)]);
+        let latest_episodes = HashMap::from([
+            // jellyfin is ahead of mal
+            ((1, 1), watched(1, 5)),
+            // mal is ahead of jellyfin
+            ((2, 1), watched(2, 3)),
+            // not in the mapping
+            ((3, 1), watched(3, 1)),
+        ]);
+
+        let report = sync(
+            &tracker,
+            latest_episodes,
+            &mapping_table,
+            &Overrides::default(),
+            &SyncOptions::default(),
+            &mut Checkpoint::default(),
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert_eq!(
+            report.updated,
+            vec![SeriesChange {
+                series_name: "series_1".to_string(),
+                tvdb_id: 1,
+                season: 1,
+                mal_id: 10,
+                old_episode: 2,
+                new_episode: 5,
+                status: ListStatus::Watching,
+            }]
+        );
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].mal_id, Some(20));
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].tvdb_id, 3);
+        assert_eq!(tracker.list.lock().unwrap()[&10], 5);
+        assert_eq!(tracker.list.lock().unwrap()[&20], 7);
+    }
+
+    #[tokio::test]
+    async fn test_sync_skips_checkpointed_series() -> Result<()> {
+        let tracker = FakeTracker::default();
+        let mapping_table = MappingTable::from_entries(vec![entry(1, 10), entry(2, 20)]);
+        let latest_episodes = HashMap::from([((1, 1), watched(1, 5)), ((2, 1), watched(2, 3))]);
+
+        // simulate a run that was interrupted after syncing the first series
+        let path = std::env::temp_dir().join("jellymal-test-sync-state.json");
+        let path = path.to_str().unwrap();
+        let mut checkpoint = Checkpoint::load(path)?;
+        checkpoint.mark_synced(1, 1)?;
+        checkpoint.save()?;
+
+        let mut checkpoint = Checkpoint::load(path)?;
+        let report = sync(
+            &tracker,
+            latest_episodes,
+            &mapping_table,
+            &Overrides::default(),
+            &SyncOptions::default(),
+            &mut checkpoint,
+            &CancellationToken::new(),
+        )
+        .await;
+        checkpoint.clear()?;
+
+        assert_eq!(report.updated.len(), 1);
+        assert_eq!(report.updated[0].mal_id, 20);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].tvdb_id, 1);
+        assert!(!tracker.list.lock().unwrap().contains_key(&10));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sync_seasons_separately() {
+        let tracker = FakeTracker::default();
+        let mapping_table =
+            MappingTable::from_entries(vec![season_entry(1, 1, 10), season_entry(1, 2, 11)]);
+        let latest_episodes = HashMap::from([
+            ((1, 1), watched_in_season(1, 1, 12)),
+            ((1, 2), watched_in_season(1, 2, 4)),
+        ]);
+
+        let report = sync(
+            &tracker,
+            latest_episodes,
+            &mapping_table,
+            &Overrides::default(),
+            &SyncOptions::default(),
+            &mut Checkpoint::default(),
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert_eq!(report.updated.len(), 2);
+        assert_eq!(tracker.list.lock().unwrap()[&10], 12);
+        assert_eq!(tracker.list.lock().unwrap()[&11], 4);
+    }
+
+    #[tokio::test]
+    async fn test_sync_seasons_sharing_an_entry() {
+        let tracker = FakeTracker::default();
+        // season 2 has no entry of its own, so both seasons resolve to the season 1 entry
+        let mapping_table = MappingTable::from_entries(vec![entry(1, 10)]);
+        let latest_episodes = HashMap::from([
+            ((1, 1), watched_in_season(1, 1, 12)),
+            ((1, 2), watched_in_season(1, 2, 4)),
+        ]);
+
+        sync(
+            &tracker,
+            latest_episodes,
+            &mapping_table,
+            &Overrides::default(),
+            &SyncOptions::default(),
+            &mut Checkpoint::default(),
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert_eq!(tracker.list.lock().unwrap()[&10], 12);
+    }
+
+    #[tokio::test]
+    async fn test_sync_in_series_order() {
+        let tracker = FakeTracker::default();
+        let mapping_table = MappingTable::from_entries(
+            (1..=20)
+                .map(|tvdb_id| entry(tvdb_id, tvdb_id * 10))
+                .collect(),
+        );
+        let latest_episodes: HashMap<SeasonKey, Episode> = (1..=20)
+            .map(|tvdb_id| ((tvdb_id, 1), watched(tvdb_id, 1)))
+            .collect();
+
+        let report = sync(
+            &tracker,
+            latest_episodes,
+            &mapping_table,
+            &Overrides::default(),
+            &SyncOptions::default(),
+            &mut Checkpoint::default(),
+            &CancellationToken::new(),
+        )
+        .await;
+
+        let names: Vec<&str> = report
+            .updated
+            .iter()
+            .map(|change| change.series_name.as_str())
+            .collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+    }
+
+    #[tokio::test]
+    async fn test_verify_doesnt_write() {
+        let tracker = FakeTracker {
+            list: Mutex::new(HashMap::from([(10, 2), (20, 7), (30, 1)])),
+            ..Default::default()
+        };
+        let mapping_table =
+            MappingTable::from_entries(vec![entry(1, 10), entry(2, 20), entry(3, 30)]);
+        let latest_episodes = HashMap::from([
+            ((1, 1), watched(1, 5)),
+            ((2, 1), watched(2, 3)),
+            ((3, 1), watched(3, 1)),
+            ((4, 1), watched(4, 1)),
+        ]);
+
+        let report = verify(
+            &tracker,
+            latest_episodes,
+            &mapping_table,
+            &Overrides::default(),
+            &SyncOptions::default(),
+        )
+        .await;
+
+        assert_eq!(report.updated.len(), 1);
+        assert_eq!(report.updated[0].new_episode, 5);
+        let reasons: Vec<&str> = report
+            .skipped
+            .iter()
+            .map(|skipped| skipped.reason.as_str())
+            .collect();
+        assert_eq!(
+            reasons,
+            vec!["mal is ahead at episode 7", "already up to date"]
+        );
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].tvdb_id, 4);
+        assert_eq!(tracker.list.lock().unwrap()[&10], 2);
+    }
+
+    #[tokio::test]
+    async fn test_sync_favorites_only() {
+        let tracker = FakeTracker::default();
+        let mapping_table = MappingTable::from_entries(vec![entry(1, 10), entry(2, 20)]);
+        let favorite = Episode {
+            series_favorite: true,
+            ..watched(1, 3)
+        };
+        let options = SyncOptions {
+            favorites_only: true,
+            ..Default::default()
+        };
+
+        let report = sync(
+            &tracker,
+            HashMap::from([((1, 1), favorite), ((2, 1), watched(2, 4))]),
+            &mapping_table,
+            &Overrides::default(),
+            &options,
+            &mut Checkpoint::default(),
+            &CancellationToken::new(),
+        )
+        .await;
+        assert_eq!(report.updated.len(), 1);
+        assert_eq!(report.updated[0].mal_id, 10);
+        assert_eq!(report.skipped[0].tvdb_id, 2);
+        assert_eq!(report.skipped[0].reason, "not a favorite");
+        assert!(!tracker.list.lock().unwrap().contains_key(&20));
+    }
+
+    #[test]
+    fn test_find_missing() {
+        let mapping_table = MappingTable::from_entries(vec![entry(1, 10), entry(2, 20)]);
+        let unwatched = Episode {
+            watched: false,
+            ..watched(2, 1)
+        };
+        // unmapped series don't map to anything, so they can't keep an entry either
+        let library_mal_ids = library_mal_ids(
+            &[watched(1, 3), unwatched, watched(3, 1)],
+            &mapping_table,
+            &Overrides::default(),
+        );
+        assert_eq!(library_mal_ids, HashSet::from([10, 20]));
+
+        let datum = |id: i32| {
+            serde_json::from_value::<UserAnimeListDatum>(serde_json::json!({
+                "node": { "id": id, "title": format!("series {}", id) },
+                "list_status": { "num_episodes_watched": 1, "status": "watching" }
+            }))
+            .unwrap()
+        };
+        let anime_list = vec![datum(10), datum(20), datum(30)];
+        let missing: Vec<i32> = find_missing(&anime_list, &library_mal_ids)
+            .iter()
+            .map(|datum| datum.node.id)
+            .collect();
+        assert_eq!(missing, vec![30]);
+    }
+
+    #[tokio::test]
+    async fn test_sync_ranged_episode() {
+        let tracker = FakeTracker::default();
+        let mapping_table = MappingTable::from_entries(vec![entry(1, 10)]);
+        // a single file holding episodes 1 and 2
+        let episode = Episode {
+            number_end: Some(2),
+            ..watched(1, 1)
+        };
+
+        let report = sync(
+            &tracker,
+            HashMap::from([((1, 1), episode)]),
+            &mapping_table,
+            &Overrides::default(),
+            &SyncOptions::default(),
+            &mut Checkpoint::default(),
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert_eq!(report.updated[0].new_episode, 2);
+        assert_eq!(tracker.list.lock().unwrap()[&10], 2);
+    }
+
+    #[tokio::test]
+    async fn test_sync_finished_season_completes() {
+        let tracker = FakeTracker {
+            num_episodes: Mutex::new(HashMap::from([(10, 12), (20, 12), (30, 24)])),
+            ..Default::default()
+        };
+        let mapping_table =
+            MappingTable::from_entries(vec![entry(1, 10), entry(2, 20), entry(3, 30)]);
+        let finished = |tvdb_id: i32| Episode {
+            season_finished: true,
+            ..watched(tvdb_id, 12)
+        };
+        // jellyfin only has the first half of series 3
+        let latest_episodes = HashMap::from([
+            ((1, 1), finished(1)),
+            ((2, 1), watched(2, 4)),
+            ((3, 1), finished(3)),
+        ]);
+
+        let report = sync(
+            &tracker,
+            latest_episodes,
+            &mapping_table,
+            &Overrides::default(),
+            &SyncOptions::default(),
+            &mut Checkpoint::default(),
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert_eq!(report.updated[0].status, ListStatus::Completed);
+        assert_eq!(
+            *tracker.statuses.lock().unwrap(),
+            HashMap::from([
+                (10, ListStatus::Completed),
+                (20, ListStatus::Watching),
+                (30, ListStatus::Watching)
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_completed_ratio() {
+        let tracker = FakeTracker {
+            num_episodes: Mutex::new(HashMap::from([(10, 20), (20, 20), (30, 20)])),
+            ..Default::default()
+        };
+        let mapping_table =
+            MappingTable::from_entries(vec![entry(1, 10), entry(2, 20), entry(3, 30)]);
+        let finished = |tvdb_id: i32, episode_number: i32| Episode {
+            season_finished: true,
+            ..watched(tvdb_id, episode_number)
+        };
+        // 19 of 20 is exactly 0.95, 18 falls short of it
+        let latest_episodes = HashMap::from([
+            ((1, 1), finished(1, 20)),
+            ((2, 1), finished(2, 19)),
+            ((3, 1), finished(3, 18)),
+        ]);
+        let options = SyncOptions {
+            completed_ratio: 0.95,
+            ..Default::default()
+        };
+
+        sync(
+            &tracker,
+            latest_episodes,
+            &mapping_table,
+            &Overrides::default(),
+            &options,
+            &mut Checkpoint::default(),
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert_eq!(
+            *tracker.statuses.lock().unwrap(),
+            HashMap::from([
+                (10, ListStatus::Completed),
+                (20, ListStatus::Completed),
+                (30, ListStatus::Watching)
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_search_fallback() {
+        // "series 6" is one edit from "series 5" in 8 characters, a score of 0.875
+        let candidate = |mal_id: i32, title: &str| Candidate {
+            mal_id,
+            title: title.to_string(),
+            alternative_titles: vec![],
+        };
+        let candidates = vec![candidate(60, "Series 6"), candidate(70, "Other Series")];
+        let mapping_table = MappingTable::from_entries(vec![entry(1, 10)]);
+        for (min_score, synced) in [(0.8, true), (0.875, true), (0.9, false)] {
+            let tracker = FakeTracker {
+                candidates: candidates.clone(),
+                ..Default::default()
+            };
+            let options = SyncOptions {
+                search_min_score: Some(min_score),
+                ..Default::default()
+            };
+            let report = sync(
+                &tracker,
+                HashMap::from([((1, 1), watched(1, 3)), ((5, 1), watched(5, 4))]),
+                &mapping_table,
+                &Overrides::default(),
+                &options,
+                &mut Checkpoint::default(),
+                &CancellationToken::new(),
+            )
+            .await;
+
+            // only the series the mappings don't know is searched for
+            assert_eq!(*tracker.searches.lock().unwrap(), vec!["series_5"]);
+            if synced {
+                assert_eq!(
+                    *tracker.list.lock().unwrap(),
+                    HashMap::from([(10, 3), (60, 4)])
+                );
+            } else {
+                // below the minimum it stays unmapped
+                assert_eq!(*tracker.list.lock().unwrap(), HashMap::from([(10, 3)]));
+                assert_eq!(report.failed[0].tvdb_id, 5);
+                assert_eq!(report.failed[0].error, "unable to map tvdb to anidb");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_search_fallback_off() {
+        let tracker = FakeTracker {
+            candidates: vec![Candidate {
+                mal_id: 50,
+                title: "series_5".to_string(),
+                alternative_titles: vec![],
+            }],
+            ..Default::default()
+        };
+        let options = SyncOptions {
+            search_min_score: Some(0.9),
+            ..Default::default()
+        };
+        // later seasons aren't searched for, and nothing is without a minimum score
+        for (season, options) in [(2, options), (1, SyncOptions::default())] {
+            let report = sync(
+                &tracker,
+                HashMap::from([((5, season), watched_in_season(5, season, 4))]),
+                &MappingTable::from_entries(vec![]),
+                &Overrides::default(),
+                &options,
+                &mut Checkpoint::default(),
+                &CancellationToken::new(),
+            )
+            .await;
+            assert_eq!(report.failed.len(), 1);
+        }
+        assert!(tracker.searches.lock().unwrap().is_empty());
+        assert!(tracker.list.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sync_status_override() -> Result<()> {
+        let tracker = FakeTracker::default();
+        let mapping_table = MappingTable::from_entries(vec![entry(1, 10)]);
+        // a rewatch of a finished season stays watching
+        let overrides = Overrides::from_entries(vec![SeriesOverride {
+            tvdb_id: 1,
+            season: Some(1),
+            episodes_per_file: None,
+            episode_offset: None,
+            cours: vec![],
+            status: Some(ListStatus::Watching),
+            no_auto_complete: false,
+        }])?;
+        let finished = Episode {
+            season_finished: true,
+            ..watched(1, 12)
+        };
+
+        let report = sync(
+            &tracker,
+            HashMap::from([((1, 1), finished)]),
+            &mapping_table,
+            &overrides,
+            &SyncOptions::default(),
+            &mut Checkpoint::default(),
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert_eq!(report.updated[0].status, ListStatus::Watching);
+        assert_eq!(
+            *tracker.statuses.lock().unwrap(),
+            HashMap::from([(10, ListStatus::Watching)])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sync_never_decreases() -> Result<()> {
+        let mapping_table =
+            MappingTable::from_entries(vec![entry(1, 10), entry(2, 20), entry(3, 30)]);
+        // series 3 wants to stay watching on a rewatch
+        let overrides = Overrides::from_entries(vec![SeriesOverride {
+            tvdb_id: 3,
+            season: Some(1),
+            episodes_per_file: None,
+            episode_offset: None,
+            cours: vec![],
+            status: Some(ListStatus::Watching),
+            no_auto_complete: false,
+        }])?;
+        let latest_episodes = || {
+            let finished = Episode {
+                season_finished: true,
+                ..watched(3, 12)
+            };
+            HashMap::from([
+                ((1, 1), watched(1, 5)),
+                ((2, 1), watched(2, 13)),
+                ((3, 1), finished),
+            ])
+        };
+        let tracker = || FakeTracker {
+            list: Mutex::new(HashMap::from([(10, 12), (20, 12), (30, 11)])),
+            statuses: Mutex::new(HashMap::from([
+                (10, ListStatus::Completed),
+                (20, ListStatus::Completed),
+                (30, ListStatus::Completed),
+            ])),
+            ..Default::default()
+        };
+
+        let kept = tracker();
+        let report = sync(
+            &kept,
+            latest_episodes(),
+            &mapping_table,
+            &overrides,
+            &SyncOptions::default(),
+            &mut Checkpoint::default(),
+            &CancellationToken::new(),
+        )
+        .await;
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(
+            *kept.list.lock().unwrap(),
+            HashMap::from([(10, 12), (20, 13), (30, 12)])
+        );
+        assert!(kept
+            .statuses
+            .lock()
+            .unwrap()
+            .values()
+            .all(|status| *status == ListStatus::Completed));
+
+        let mirrored = tracker();
+        let options = SyncOptions {
+            allow_decrease: true,
+            ..Default::default()
+        };
+        sync(
+            &mirrored,
+            latest_episodes(),
+            &mapping_table,
+            &overrides,
+            &options,
+            &mut Checkpoint::default(),
+            &CancellationToken::new(),
+        )
+        .await;
+        assert_eq!(
+            *mirrored.list.lock().unwrap(),
+            HashMap::from([(10, 5), (20, 13), (30, 12)])
+        );
+        assert_eq!(
+            *mirrored.statuses.lock().unwrap(),
+            HashMap::from([
+                (10, ListStatus::Watching),
+                (20, ListStatus::Watching),
+                (30, ListStatus::Watching),
+            ])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sync_complete_after_runs() -> Result<()> {
+        let tracker = FakeTracker {
+            num_episodes: Mutex::new(HashMap::from([(10, 12)])),
+            ..Default::default()
+        };
+        let mapping_table = MappingTable::from_entries(vec![entry(1, 10)]);
+        let options = SyncOptions {
+            complete_after_runs: 3,
+            ..Default::default()
+        };
+        let path = std::env::temp_dir().join("jellymal-test-sync-completions.json");
+        let path = path.to_str().unwrap();
+        let mut statuses = vec![];
+        for _ in 0..4 {
+            let mut checkpoint =
+                Checkpoint::default().with_completions(Completions::load(Some(path))?);
+            let finished = Episode {
+                season_finished: true,
+                ..watched(1, 12)
+            };
+            sync(
+                &tracker,
+                HashMap::from([((1, 1), finished)]),
+                &mapping_table,
+                &Overrides::default(),
+                &options,
+                &mut checkpoint,
+                &CancellationToken::new(),
+            )
+            .await;
+            checkpoint.clear()?;
+            statuses.push(tracker.statuses.lock().unwrap()[&10]);
+        }
+
+        // the third run in a row to find it finished completes it
+        assert_eq!(
+            statuses,
+            vec![
+                ListStatus::Watching,
+                ListStatus::Watching,
+                ListStatus::Completed,
+                ListStatus::Completed
+            ]
+        );
+        assert_eq!(tracker.list.lock().unwrap()[&10], 12);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sync_allow_decrease_shared_entry() {
+        // both series fall back to the same entry, in either order
+        let mapping_table = MappingTable::from_entries(vec![entry(1, 10), entry(2, 10)]);
+        let options = SyncOptions {
+            allow_decrease: true,
+            ..Default::default()
+        };
+        for (first, second) in [(5, 3), (3, 5)] {
+            let tracker = FakeTracker {
+                list: Mutex::new(HashMap::from([(10, 12)])),
+                ..Default::default()
+            };
+            sync(
+                &tracker,
+                HashMap::from([((1, 1), watched(1, first)), ((2, 1), watched(2, second))]),
+                &mapping_table,
+                &Overrides::default(),
+                &options,
+                &mut Checkpoint::default(),
+                &CancellationToken::new(),
+            )
+            .await;
+            assert_eq!(tracker.list.lock().unwrap()[&10], 5);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_split_cour() -> Result<()> {
+        let tracker = FakeTracker::default();
+        // series 1 is midway through the first cour and series 2 into the second
+        let split = |tvdb_id: i32| SeriesOverride {
+            tvdb_id,
+            season: Some(1),
+            episodes_per_file: None,
+            episode_offset: None,
+            cours: vec![
+                Cour {
+                    episode_range: (1, 12),
+                    mal_id: tvdb_id * 10,
+                    offset: 0,
+                },
+                Cour {
+                    episode_range: (13, 24),
+                    mal_id: tvdb_id * 10 + 1,
+                    offset: 12,
+                },
+            ],
+            status: None,
+            no_auto_complete: false,
+        };
+        let overrides = Overrides::from_entries(vec![split(1), split(2)])?;
+        let latest_episodes = HashMap::from([((1, 1), watched(1, 7)), ((2, 1), watched(2, 15))]);
+
+        // the cours don't need a mapping at all
+        let report = sync(
+            &tracker,
+            latest_episodes,
+            &MappingTable::from_entries(vec![]),
+            &overrides,
+            &SyncOptions::default(),
+            &mut Checkpoint::default(),
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert_eq!(report.updated.len(), 2);
+        assert_eq!(
+            *tracker.list.lock().unwrap(),
+            HashMap::from([(10, 7), (21, 3)])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_concurrently() -> Result<()> {
+        let after = |millis: u64, value: i32| async move {
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+            Ok::<i32, anyhow::Error>(value)
+        };
+        // whichever side finishes first, both results come back in place
+        assert_eq!(fetch_concurrently(after(20, 1), after(0, 2)).await?, (1, 2));
+        assert_eq!(fetch_concurrently(after(0, 1), after(20, 2)).await?, (1, 2));
+
+        let failing = |message: &'static str| async move { Err::<i32, _>(anyhow!(message)) };
+        let error = fetch_concurrently(failing("no user"), after(0, 2))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "unable to fetch from jellyfin: no user"
+        );
+        let error = fetch_concurrently(after(0, 1), failing("token expired"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "unable to fetch from mal: token expired"
+        );
+        let error = fetch_concurrently(failing("no user"), failing("token expired"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unable to fetch from jellyfin: no user, and unable to fetch from mal: token expired"
+        );
+        Ok(())
+    }
+
+    /// Cancels the sync as soon as the first series is written.
+    struct CancellingTracker {
+        tracker: FakeTracker,
+        shutdown: CancellationToken,
+    }
+
+    impl Tracker for CancellingTracker {
+        async fn get_latest_episode_number(&self, series_id: i32) -> Result<i32> {
+            self.tracker.get_latest_episode_number(series_id).await
+        }
+
+        async fn get_list_status(&self, series_id: i32) -> Result<Option<ListStatus>> {
+            self.tracker.get_list_status(series_id).await
+        }
+
+        async fn get_num_episodes(&self, series_id: i32) -> Result<Option<i32>> {
+            self.tracker.get_num_episodes(series_id).await
+        }
+
+        async fn set_latest_episode_number(
+            &self,
+            series_id: i32,
+            episode_number: i32,
+            status: ListStatus,
+        ) -> Result<()> {
+            self.shutdown.cancel();
+            self.tracker
+                .set_latest_episode_number(series_id, episode_number, status)
+                .await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_drains_on_shutdown() {
+        let tracker = CancellingTracker {
+            tracker: FakeTracker::default(),
+            shutdown: CancellationToken::new(),
+        };
+        let mapping_table = MappingTable::from_entries(vec![entry(1, 10), entry(2, 20)]);
+        let latest_episodes = HashMap::from([((1, 1), watched(1, 5)), ((2, 1), watched(2, 3))]);
+
+        let report = sync(
+            &tracker,
+            latest_episodes,
+            &mapping_table,
+            &Overrides::default(),
+            &SyncOptions::default(),
+            &mut Checkpoint::default(),
+            &tracker.shutdown,
+        )
+        .await;
+
+        // the series being written when the shutdown came in is finished, the other isn't started
+        assert!(report.interrupted);
+        assert_eq!(report.updated.len(), 1);
+        assert_eq!(report.updated[0].mal_id, 10);
+        assert!(!tracker.tracker.list.lock().unwrap().contains_key(&20));
+    }
+
+    /// Takes far too long to look one of the series up.
+    struct SlowTracker {
+        tracker: FakeTracker,
+        slow_series_id: i32,
+    }
+
+    impl Tracker for SlowTracker {
+        async fn get_latest_episode_number(&self, series_id: i32) -> Result<i32> {
+            if series_id == self.slow_series_id {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+            self.tracker.get_latest_episode_number(series_id).await
+        }
+
+        async fn set_latest_episode_number(
+            &self,
+            series_id: i32,
+            episode_number: i32,
+            status: ListStatus,
+        ) -> Result<()> {
+            self.tracker
+                .set_latest_episode_number(series_id, episode_number, status)
+                .await
+        }
+
+        async fn get_list_status(&self, series_id: i32) -> Result<Option<ListStatus>> {
+            self.tracker.get_list_status(series_id).await
+        }
+
+        async fn get_num_episodes(&self, series_id: i32) -> Result<Option<i32>> {
+            self.tracker.get_num_episodes(series_id).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_deadline() {
+        let tracker = SlowTracker {
+            tracker: FakeTracker::default(),
+            slow_series_id: 10,
+        };
+        let mapping_table =
+            MappingTable::from_entries(vec![entry(1, 10), entry(2, 20), entry(3, 30)]);
+        let latest_episodes = HashMap::from([
+            ((1, 1), watched(1, 5)),
+            ((2, 1), watched(2, 3)),
+            ((3, 1), watched(3, 1)),
+        ]);
+        // the first series outlasts the deadline, but is still seen through
+        let options = SyncOptions {
+            series_timeout: Some(Duration::from_millis(100)),
+            deadline: Some(Instant::now() + Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        let mut checkpoint = Checkpoint::default();
+        let report = sync(
+            &tracker,
+            latest_episodes,
+            &mapping_table,
+            &Overrides::default(),
+            &options,
+            &mut checkpoint,
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert!(report.interrupted);
+        assert_eq!(report.failed.len(), 1);
+        assert!(report.updated.is_empty());
+        assert!(tracker.tracker.list.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sync_series_timeout() {
+        let tracker = SlowTracker {
+            tracker: FakeTracker::default(),
+            slow_series_id: 10,
+        };
+        let mapping_table = MappingTable::from_entries(vec![entry(1, 10), entry(2, 20)]);
+        let latest_episodes = HashMap::from([((1, 1), watched(1, 5)), ((2, 1), watched(2, 3))]);
+        let options = SyncOptions {
+            series_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        let report = sync(
+            &tracker,
+            latest_episodes,
+            &mapping_table,
+            &Overrides::default(),
+            &options,
+            &mut Checkpoint::default(),
+            &CancellationToken::new(),
+        )
+        .await;
+
+        // the slow series is given up on, and the next one still gets synced
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].tvdb_id, 1);
+        assert!(report.failed[0].error.starts_with("timed out"));
+        assert_eq!(report.updated.len(), 1);
+        assert_eq!(report.updated[0].mal_id, 20);
+    }
+
+    #[tokio::test]
+    async fn test_sync_min_episodes_watched() {
+        let tracker = FakeTracker::default();
+        let mapping_table = MappingTable::from_entries(vec![entry(1, 10), entry(2, 20)]);
+        let latest_episodes = HashMap::from([((1, 1), watched(1, 1)), ((2, 1), watched(2, 2))]);
+
+        let report = sync(
+            &tracker,
+            latest_episodes,
+            &mapping_table,
+            &Overrides::default(),
+            &SyncOptions {
+                min_episodes_watched: 2,
+                ..Default::default()
+            },
+            &mut Checkpoint::default(),
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert_eq!(report.updated.len(), 1);
+        assert_eq!(report.updated[0].mal_id, 20);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].tvdb_id, 1);
+        assert!(!tracker.list.lock().unwrap().contains_key(&10));
+    }
+}